@@ -0,0 +1,26 @@
+// Fixtures shared by the benches (`mod common;` in each).
+
+// Deterministic, valid 32-char base32 store hash for `seed`.
+pub fn base32_hash(seed: u32) -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdfghijklmnpqrsvwxyz";
+    let mut x = seed.wrapping_mul(2654435761) | 1;
+    (0..32)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            ALPHABET[(x % 32) as usize] as char
+        })
+        .collect()
+}
+
+// PATH of `entries` bin dirs, every fourth one a stdenv requisite.
+pub fn large_path(entries: u32) -> String {
+    (0..entries)
+        .map(|i| match i % 4 {
+            0 => format!("/nix/store/{}-coreutils-9.4/bin", base32_hash(i)),
+            _ => format!("/nix/store/{}-tool{i}-1.{i}.0/bin", base32_hash(i + 100_000)),
+        })
+        .collect::<Vec<_>>()
+        .join(":")
+}
@@ -0,0 +1,110 @@
+// Throughput of the two steps every prompt pays for: `parse_hashes` on a
+// large nix eval result (whole, and streamed through `HashScanner` in pipe-
+// sized chunks as `refresh` does) and `select_packages` on a 200-entry PATH.
+// Run with `cargo bench --bench hot_path`; compare runs before and after a change.
+// Timed with a small harness of its own rather than criterion so the crate
+// stays dependency-free: a warm-up, then 30 samples reported as the median
+// and interquartile range, which outlier runs don't move.
+
+use std::{
+    collections::HashSet,
+    hint::black_box,
+    time::{Duration, Instant},
+};
+
+use nix_path_pkgs::{HashScanner, SKIP, parse_hashes, select_packages};
+
+mod common;
+use common::{base32_hash, large_path};
+
+// Names and versions shaped like a real stdenv closure.
+const NAMES: &[(&str, &str)] = &[
+    ("glibc", "2.38-44"),
+    ("gcc-wrapper", "13.2.0"),
+    ("coreutils", "9.4"),
+    ("bash", "5.2p26"),
+    ("openssl", "3.0.13-dev"),
+    ("zlib", "1.3.1"),
+    ("binutils", "2.41"),
+    ("gnumake", "4.4.1"),
+    ("perl", "5.38.2"),
+    ("python3", "3.11.8"),
+];
+
+// `entries` store paths as `nix eval --json` prints them.
+fn eval_json(entries: u32) -> String {
+    let paths: Vec<String> = (0..entries)
+        .map(|i| {
+            let (name, version) = NAMES[i as usize % NAMES.len()];
+            format!("\"/nix/store/{}-{name}-{version}\"", base32_hash(i))
+        })
+        .collect();
+    format!("[{}]", paths.join(","))
+}
+
+// Time per call: the median and interquartile range of 30 samples.
+struct Timing {
+    median: Duration,
+    p25: Duration,
+    p75: Duration,
+}
+
+impl std::fmt::Display for Timing {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:?} (IQR {:?}..{:?})", self.median, self.p25, self.p75)
+    }
+}
+
+// Warm up for ~100ms, then take 30 samples of a batch sized to ~20ms each.
+fn time_per_run(mut f: impl FnMut()) -> Timing {
+    let warmup = Instant::now();
+    let mut calls = 0u32;
+    while warmup.elapsed() < Duration::from_millis(100) {
+        f();
+        calls += 1;
+    }
+    let batch = (calls / 5).max(1);
+    let mut samples: Vec<Duration> = (0..30)
+        .map(|_| {
+            let start = Instant::now();
+            for _ in 0..batch {
+                f();
+            }
+            start.elapsed() / batch
+        })
+        .collect();
+    samples.sort_unstable();
+    Timing { median: samples[15], p25: samples[7], p75: samples[22] }
+}
+
+fn main() {
+    let json = eval_json(2000);
+    let per_parse = time_per_run(|| {
+        black_box(parse_hashes(black_box(json.as_bytes())));
+    });
+    let mb_per_sec = json.len() as f64 / per_parse.median.as_secs_f64() / 1e6;
+    println!("parse_hashes, {} KiB / 2000 paths: {per_parse} per parse, {mb_per_sec:.0} MB/s", json.len() / 1024);
+
+    // Chunks like the 64 KiB reads off nix's stdout; 4 KiB is a small pipe buffer.
+    for chunk in [64 * 1024, 4 * 1024] {
+        let per_scan = time_per_run(|| {
+            let mut scanner = HashScanner::default();
+            for part in black_box(json.as_bytes()).chunks(chunk) {
+                scanner.feed(part);
+            }
+            black_box(scanner.finish());
+        });
+        let mb_per_sec = json.len() as f64 / per_scan.median.as_secs_f64() / 1e6;
+        println!("HashScanner, {} KiB chunks: {per_scan} per parse, {mb_per_sec:.0} MB/s", chunk / 1024);
+    }
+
+    let path = large_path(200);
+    let ignore = parse_hashes(eval_json(2000).as_bytes());
+    let skip: HashSet<&str> = SKIP.iter().copied().collect();
+    assert_eq!(select_packages(&path, &ignore, &skip).len(), 150);
+    let per_walk = time_per_run(|| {
+        black_box(select_packages(black_box(&path), &ignore, &skip));
+    });
+    let entries_per_sec = 200.0 / per_walk.median.as_secs_f64() / 1e6;
+    println!("select_packages, 200-entry PATH: {per_walk} per walk, {entries_per_sec:.1}M entries/s");
+}
@@ -0,0 +1,115 @@
+// Allocation and timing check for the PATH walk on a large (200-entry) PATH.
+// Run with `cargo bench --bench path_walk`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    collections::HashSet,
+    hint::black_box,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Instant,
+};
+
+use nix_path_pkgs::{Filter, IgnoreSet, SKIP};
+
+mod common;
+use common::{base32_hash, large_path};
+
+struct Counting;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+fn count_allocs(f: impl FnOnce()) -> usize {
+    let before = ALLOCS.load(Ordering::Relaxed);
+    f();
+    ALLOCS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let path = large_path(200);
+    let ignore = IgnoreSet::default();
+    let skip: HashSet<&str> = SKIP.iter().copied().collect();
+    let filter = Filter::new(&ignore, &skip);
+
+    // What the walk used to do: fixed capacity-32 collections, grown on demand
+    let fixed = count_allocs(|| {
+        let mut seen: HashSet<&str> = HashSet::with_capacity(32);
+        let mut kept: Vec<&str> = Vec::with_capacity(32);
+        for dir in path.split(':') {
+            if seen.insert(dir) {
+                kept.push(dir);
+            }
+        }
+        black_box((seen, kept));
+    });
+
+    let sized = count_allocs(|| {
+        black_box(filter.packages(&path));
+    });
+
+    const RUNS: u32 = 10_000;
+    let start = Instant::now();
+    for _ in 0..RUNS {
+        black_box(filter.packages(black_box(&path)));
+    }
+    let per_walk = start.elapsed() / RUNS;
+
+    println!("200-entry PATH: fixed-capacity collections: {fixed} allocations");
+    println!("200-entry PATH: walk sized from PATH:        {sized} allocations");
+    println!("200-entry PATH: {per_walk:?} per walk");
+
+    // Ignore set: 1000 requisite hashes built then probed by a 200-entry PATH
+    // (half hits), as 32-char strings vs decoded 20-byte arrays.
+    let requisites: Vec<String> = (0..1000u32).map(base32_hash).collect();
+    let probes: Vec<String> = (500..700u32).map(base32_hash).collect();
+
+    let as_strings = || {
+        let set: HashSet<String> = requisites.iter().map(|h| h.to_string()).collect();
+        probes.iter().filter(|h| set.contains(h.as_str())).count()
+    };
+    let as_decoded = || {
+        let set: IgnoreSet = requisites.iter().map(String::as_str).collect();
+        probes.iter().filter(|h| set.contains(h)).count()
+    };
+    assert_eq!(as_strings(), as_decoded());
+
+    let string_allocs = count_allocs(|| {
+        black_box(as_strings());
+    });
+    let decoded_allocs = count_allocs(|| {
+        black_box(as_decoded());
+    });
+
+    const SET_RUNS: u32 = 1_000;
+    let start = Instant::now();
+    for _ in 0..SET_RUNS {
+        black_box(as_strings());
+    }
+    let string_time = start.elapsed() / SET_RUNS;
+    let start = Instant::now();
+    for _ in 0..SET_RUNS {
+        black_box(as_decoded());
+    }
+    let decoded_time = start.elapsed() / SET_RUNS;
+
+    println!("1000-hash ignore set: HashSet<String>: {string_allocs} allocations, {string_time:?}");
+    println!("1000-hash ignore set: decoded:         {decoded_allocs} allocations, {decoded_time:?}");
+}
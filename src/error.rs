@@ -0,0 +1,67 @@
+// Failure kinds for evaluating and caching the ignore set.
+
+use std::{fmt, io, path::PathBuf, time::Duration};
+
+#[derive(Debug)]
+pub enum Error {
+    NixNotFound(io::Error),                        // `nix` couldn't be spawned
+    NixEvalFailed(String),                         // nix's stderr
+    CacheIo { path: PathBuf, source: io::Error },  // reading/writing a cache file
+    Timeout(Duration),                             // nix ran past NIX_PATH_PKGS_NIX_TIMEOUT
+    BadExpr { flake: String, stderr: String },     // the flake/expression can't be evaluated
+    NotStorePaths { flake: String },               // eval output isn't a JSON array of strings
+    OutputTooLarge(u64),                           // nix printed more than this many MiB
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NixNotFound(e) if e.kind() == io::ErrorKind::NotFound => write!(
+                f,
+                "`nix` not found on PATH; is nix installed? (https://nixos.org/download) \
+                 Or pass --ignore-file to run without it"
+            ),
+            Error::NixNotFound(e) => write!(f, "cannot run `nix` ({e}); is nix installed and on PATH?"),
+            Error::NixEvalFailed(stderr) => write!(f, "nix eval failed:\n{}", stderr.trim_end()),
+            Error::CacheIo { path, source } => write!(
+                f,
+                "cache {}: {source}; check permissions or set NIX_PATH_PKGS_CACHE_TTL=0",
+                path.display()
+            ),
+            Error::Timeout(limit) => write!(
+                f,
+                "nix eval took longer than {}s; raise NIX_PATH_PKGS_NIX_TIMEOUT or warm the cache",
+                limit.as_secs()
+            ),
+            Error::BadExpr { stderr, .. } if crate::is_flakes_disabled(stderr) => write!(
+                f,
+                "this nix has flakes disabled; add `experimental-features = nix-command flakes` to nix.conf, \
+                 or pass --ignore-file to skip nix entirely"
+            ),
+            Error::BadExpr { flake, stderr } if crate::is_flake_not_found(stderr) => write!(
+                f,
+                "`{flake}` flake not in registry; run `nix registry add {flake} <flake-ref>` or pass --flake"
+            ),
+            Error::BadExpr { flake, stderr } => {
+                write!(f, "cannot evaluate stdenv from `{flake}`; check --flake:\n{}", stderr.trim_end())
+            }
+            Error::NotStorePaths { flake } => write!(
+                f,
+                "the stdenv expression for `{flake}` didn't evaluate to a list of store paths; check --flake"
+            ),
+            Error::OutputTooLarge(mib) => write!(
+                f,
+                "nix printed more than {mib} MiB; check --flake or raise NIX_PATH_PKGS_NIX_MAX_OUTPUT_MB"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NixNotFound(e) | Error::CacheIo { source: e, .. } => Some(e),
+            _ => None,
+        }
+    }
+}
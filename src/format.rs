@@ -0,0 +1,566 @@
+// Output formats for the kept package list.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Write as _},
+    io::{self, Write},
+    str::FromStr,
+};
+
+use crate::{IgnoreSet, Package, Stats, hash_and_item, store_hash};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Plain,    // "git, ripgrep"
+    Ndjson,   // one JSON object per package per line
+    Json,     // one JSON array of package objects
+    Table,    // aligned columns with a header, for humans
+    Paths,    // "name\t/nix/store/..." per line, for fzf and friends
+    RawPaths, // the kept PATH entries themselves, one per line
+    Env,      // "NIX_PKG_GIT=2.40.1" per line, for `eval`
+    Names,    // bare names, one per line, for completion
+    Toml,     // a `[[package]]` table per package
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "plain" => Ok(Format::Plain),
+            "ndjson" => Ok(Format::Ndjson),
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Table),
+            "paths" => Ok(Format::Paths),
+            "raw-paths" => Ok(Format::RawPaths),
+            "env" => Ok(Format::Env),
+            "names" => Ok(Format::Names),
+            "toml" => Ok(Format::Toml),
+            _ => Err(format!("unknown format `{s}` (expected plain, ndjson, json, table, paths, raw-paths, env, names or toml)")),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    // The --format value that selects it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Plain => "plain",
+            Format::Ndjson => "ndjson",
+            Format::Json => "json",
+            Format::Table => "table",
+            Format::Paths => "paths",
+            Format::RawPaths => "raw-paths",
+            Format::Env => "env",
+            Format::Names => "names",
+            Format::Toml => "toml",
+        })
+    }
+}
+
+// `s` as a quoted JSON string.
+pub fn json_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// The keys of every JSON package object, in output order, with what each
+// holds. Both the objects and `json_schema` are built from this list.
+pub const JSON_FIELDS: [(&str, &str); 4] = [
+    ("name", "package name, e.g. \"git\""),
+    ("version", "version, e.g. \"2.40.1\"; empty if the store path has none"),
+    ("hash", "32-char nix base32 store hash"),
+    ("path", "store path, e.g. \"/nix/store/<hash>-git-2.40.1\""),
+];
+
+// Added after JSON_FIELDS under --with-counts; optional in the schema.
+pub const OCCURRENCES_FIELD: (&str, &str) = ("occurrences", "PATH entries with this name before dedup (--with-counts)");
+
+// Added last under --show-rev when the revision is known; optional too.
+pub const NIXPKGS_REV_FIELD: (&str, &str) = ("nixpkgs_rev", "nixpkgs revision the ignore set came from (--show-rev)");
+
+// `p`'s values in JSON_FIELDS order.
+fn json_values<'a>(p: &Package<'a>) -> [&'a str; 4] {
+    [p.name, p.version, p.hash, p.path]
+}
+
+// Key and JSON-encoded value pairs for `p`, plus `occurrences` if counted
+// and `nixpkgs_rev` if given.
+fn json_pairs(p: &Package, count: Option<usize>, rev: Option<&str>) -> Vec<(&'static str, String)> {
+    let mut pairs: Vec<(&str, String)> = JSON_FIELDS.iter().zip(json_values(p)).map(|((k, _), v)| (*k, json_str(v))).collect();
+    if let Some(n) = count {
+        pairs.push((OCCURRENCES_FIELD.0, n.to_string()));
+    }
+    if let Some(rev) = rev {
+        pairs.push((NIXPKGS_REV_FIELD.0, json_str(rev)));
+    }
+    pairs
+}
+
+// {"name":..,"version":..,"hash":..,"path":..}
+pub fn json_object(p: &Package) -> String {
+    json_object_counted(p, None, None)
+}
+
+// `json_object` with `"occurrences":n` and then `"nixpkgs_rev":..` at the
+// end when `count` / `rev` are set.
+pub fn json_object_counted(p: &Package, count: Option<usize>, rev: Option<&str>) -> String {
+    let body: Vec<String> = json_pairs(p, count, rev).iter().map(|(k, v)| format!("\"{k}\":{v}")).collect();
+    format!("{{{}}}", body.join(","))
+}
+
+// `json_object_counted` with one key per line, nested `indent` deep.
+pub fn json_object_pretty(p: &Package, count: Option<usize>, rev: Option<&str>, indent: &str) -> String {
+    let body: Vec<String> = json_pairs(p, count, rev).iter().map(|(k, v)| format!("{indent}  \"{k}\": {v}")).collect();
+    format!("{{\n{}\n{indent}}}", body.join(",\n"))
+}
+
+// JSON Schema (draft 2020-12) for one package object: what each ndjson line
+// and each json array element is.
+pub fn json_schema() -> String {
+    let mut props: Vec<String> = JSON_FIELDS
+        .iter()
+        .map(|(k, desc)| format!("    \"{k}\": {{ \"type\": \"string\", \"description\": {} }}", json_str(desc)))
+        .collect();
+    let (key, desc) = OCCURRENCES_FIELD;
+    props.push(format!("    \"{key}\": {{ \"type\": \"integer\", \"minimum\": 1, \"description\": {} }}", json_str(desc)));
+    let (key, desc) = NIXPKGS_REV_FIELD;
+    props.push(format!("    \"{key}\": {{ \"type\": \"string\", \"description\": {} }}", json_str(desc)));
+    let required: Vec<String> = JSON_FIELDS.iter().map(|(k, _)| json_str(k)).collect();
+    format!(
+        "{{\n  \"$schema\": \"https://json-schema.org/draft/2020-12/schema\",\n  \"title\": \"nix-path-pkgs package\",\n  \"type\": \"object\",\n  \"properties\": {{\n{}\n  }},\n  \"required\": [{}],\n  \"additionalProperties\": false\n}}\n",
+        props.join(",\n"),
+        required.join(", ")
+    )
+}
+
+// One complete object per line, flushed so consumers see it right away.
+// `pretty` spreads each object over several lines (a stream jq still reads).
+pub fn write_ndjson_line(
+    out: &mut impl Write,
+    p: &Package,
+    count: Option<usize>,
+    rev: Option<&str>,
+    pretty: bool,
+) -> io::Result<()> {
+    let obj = if pretty { json_object_pretty(p, count, rev, "") } else { json_object_counted(p, count, rev) };
+    writeln!(out, "{obj}")?;
+    out.flush()
+}
+
+// The whole list as one array, always ending in exactly one newline; `[]`
+// when there's nothing, so the output still parses. `count` is --with-counts,
+// `rev` the --show-rev revision.
+pub fn write_json_array(
+    out: &mut impl Write,
+    pkgs: &[Package],
+    count: impl Fn(&Package) -> Option<usize>,
+    rev: Option<&str>,
+    pretty: bool,
+) -> io::Result<()> {
+    if pkgs.is_empty() {
+        return writeln!(out, "[]");
+    }
+    if pretty {
+        let items: Vec<String> = pkgs.iter().map(|p| format!("  {}", json_object_pretty(p, count(p), rev, "  "))).collect();
+        writeln!(out, "[\n{}\n]", items.join(",\n"))
+    } else {
+        let items: Vec<String> = pkgs.iter().map(|p| json_object_counted(p, count(p), rev)).collect();
+        writeln!(out, "[{}]", items.join(","))
+    }
+}
+
+// `s` as a TOML basic string: like JSON's, but DEL is a control char too and
+// \b / \f have short forms.
+pub fn toml_str(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '\u{8}' => out.push_str("\\b"),
+            '\u{c}' => out.push_str("\\f"),
+            c if (c as u32) < 0x20 || c == '\u{7f}' => {
+                let _ = write!(out, "\\u{:04X}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+// An array of tables, one `[[package]]` per package with the JSON_FIELDS keys
+// (plus `occurrences` under --with-counts), blank line between. Nothing at all
+// for an empty list, which is still a valid document.
+pub fn write_toml(out: &mut impl Write, pkgs: &[Package], count: impl Fn(&Package) -> Option<usize>) -> io::Result<()> {
+    for (i, p) in pkgs.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        writeln!(out, "[[package]]")?;
+        for ((key, _), value) in JSON_FIELDS.iter().zip(json_values(p)) {
+            writeln!(out, "{key} = {}", toml_str(value))?;
+        }
+        if let Some(n) = count(p) {
+            writeln!(out, "{} = {n}", OCCURRENCES_FIELD.0)?;
+        }
+    }
+    Ok(())
+}
+
+// `s` as one POSIX shell word: as is if it's plain, else single-quoted.
+pub fn shell_quote(s: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !s.is_empty() && s.chars().all(plain) {
+        return s.to_string();
+    }
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// "cargo-watch" => "NIX_PKG_CARGO_WATCH": uppercase, anything that can't be
+// in a shell identifier becomes `_`.
+pub fn env_var_name(name: &str) -> String {
+    let sanitized: String =
+        name.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' }).collect();
+    format!("NIX_PKG_{sanitized}")
+}
+
+// One `NIX_PKG_<NAME>=<version>` line per package. Names that sanitize to the
+// same variable ("cargo-watch", "cargo.watch") get `_2`, `_3`, ... in PATH
+// order. Store names only hold `[A-Za-z0-9+-._?=]`, none of which an
+// assignment expands, so versions need no quoting.
+pub fn write_env(out: &mut impl Write, pkgs: &[Package]) -> io::Result<()> {
+    let mut used = HashSet::new();
+    for p in pkgs {
+        let base = env_var_name(p.name);
+        let var = (1..)
+            .map(|n| if n == 1 { base.clone() } else { format!("{base}_{n}") })
+            .find(|v| !used.contains(v))
+            .unwrap_or(base);
+        writeln!(out, "{var}={}", p.version)?;
+        used.insert(var);
+    }
+    Ok(())
+}
+
+// NAME / VERSION / HASH (first 8 chars) / PATH, columns sized from the data.
+// With `max_width`, the path column is cut to fit and ends in "…". `bold`
+// highlights the header row.
+pub fn write_table(out: &mut impl Write, pkgs: &[Package], max_width: Option<usize>, bold: bool) -> io::Result<()> {
+    const HEADER: [&str; 4] = ["NAME", "VERSION", "HASH", "PATH"];
+    let rows: Vec<[&str; 4]> = pkgs
+        .iter()
+        .map(|p| [p.name, p.version, p.hash.get(..8).unwrap_or(p.hash), p.path])
+        .collect();
+
+    let mut widths = HEADER.map(|h| h.chars().count());
+    for row in &rows {
+        for (w, cell) in widths.iter_mut().zip(row) {
+            *w = (*w).max(cell.chars().count());
+        }
+    }
+    if let Some(max) = max_width {
+        let before_path: usize = widths[..3].iter().map(|w| w + 2).sum();
+        widths[3] = widths[3].min(max.saturating_sub(before_path)).max(HEADER[3].len());
+    }
+
+    let line = |row: &[&str; 4]| {
+        let path = ellipsize(row[3], widths[3]);
+        format!("{:w0$}  {:w1$}  {:w2$}  {path}", row[0], row[1], row[2], w0 = widths[0], w1 = widths[1], w2 = widths[2])
+    };
+    if bold {
+        writeln!(out, "\x1b[1m{}\x1b[0m", line(&HEADER))?;
+    } else {
+        writeln!(out, "{}", line(&HEADER))?;
+    }
+    for row in &rows {
+        writeln!(out, "{}", line(row))?;
+    }
+    Ok(())
+}
+
+// Whether to style output. `flag` is --color always/never (None for auto),
+// `var` reads the environment, `tty` is whether output is an interactive
+// terminal. Precedence: the flag, then NO_COLOR (any non-empty value),
+// CLICOLOR_FORCE (non-zero forces color even when piped), CLICOLOR=0, and
+// finally the terminal check.
+pub fn should_color(flag: Option<bool>, var: impl Fn(&str) -> Option<String>, tty: bool) -> bool {
+    let set = |name| var(name).filter(|v| !v.is_empty());
+    if let Some(on) = flag {
+        on
+    } else if set("NO_COLOR").is_some() {
+        false
+    } else if set("CLICOLOR_FORCE").is_some_and(|v| v != "0") {
+        true
+    } else if set("CLICOLOR").is_some_and(|v| v == "0") {
+        false
+    } else {
+        tty
+    }
+}
+
+// Prometheus text exposition of one walk, for node_exporter's textfile collector.
+pub fn write_metrics(out: &mut impl Write, stats: &Stats, cache_hit: bool) -> io::Result<()> {
+    let metrics = [
+        ("nix_path_pkgs_total", "Packages listed after filtering.", stats.kept),
+        ("nix_path_pkgs_scanned", "PATH entries scanned.", stats.scanned),
+        ("nix_path_pkgs_skipped", "Packages dropped by name (skip list, allowlist, versions).", stats.skipped),
+        ("nix_path_pkgs_ignored", "Packages dropped as stdenv requisites or by hash.", stats.ignored),
+        ("nix_path_pkgs_duplicates", "Repeat occurrences of an already listed name.", stats.duplicates),
+        ("nix_path_pkgs_cache_hit", "1 if the ignore set came from the cache.", cache_hit as usize),
+    ];
+    for (name, help, value) in metrics {
+        writeln!(out, "# HELP {name} {help}")?;
+        writeln!(out, "# TYPE {name} gauge")?;
+        writeln!(out, "{name} {value}")?;
+    }
+    Ok(())
+}
+
+// "git-1a2b3c4d": the name plus the first `len` chars of its store hash.
+pub fn name_with_hash(p: &Package, len: usize) -> String {
+    format!("{}-{}", p.name, &p.hash[..len.min(p.hash.len())])
+}
+
+// --tree: each package, then its direct references (`refs`, by store hash)
+// one level down, "(ignored)" on those in `ignore`. Self-references are left
+// out; a package whose references are unknown gets "?" instead.
+pub fn write_tree(out: &mut impl Write, pkgs: &[Package], refs: &HashMap<String, Vec<String>>, ignore: &IgnoreSet) -> io::Result<()> {
+    for p in pkgs {
+        if p.version.is_empty() {
+            writeln!(out, "{}", p.name)?;
+        } else {
+            writeln!(out, "{} {}", p.name, p.version)?;
+        }
+        let Some(deps) = refs.get(p.hash) else {
+            writeln!(out, "└── ?")?;
+            continue;
+        };
+        let deps: Vec<&String> = deps.iter().filter(|r| store_hash(r) != Some(p.hash)).collect();
+        for (i, r) in deps.iter().enumerate() {
+            let branch = if i + 1 == deps.len() { "└──" } else { "├──" };
+            let item = hash_and_item(r).map_or(r.as_str(), |(_, item)| item);
+            let mark = if store_hash(r).is_some_and(|h| ignore.contains(h)) { " (ignored)" } else { "" };
+            writeln!(out, "{branch} {item}{mark}")?;
+        }
+    }
+    Ok(())
+}
+
+// --collapse-prefix: names sharing a prefix before `sep` ("cargo-watch",
+// "cargo-edit") become one "cargo (+2 subcommands)" entry where the first of
+// them (or "cargo" itself) was. A prefix needs two or more such names to
+// collapse. With `keep_base` the base tool stays its own entry and the rest
+// read "cargo-* (2)".
+pub fn collapse_prefixes(names: &[&str], sep: &str, keep_base: bool) -> Vec<String> {
+    let prefix = |name: &str| name.split_once(sep).map_or(name, |(p, _)| p).to_string();
+    let mut subs: HashMap<String, usize> = HashMap::new();
+    for name in names.iter().filter(|n| n.contains(sep)) {
+        *subs.entry(prefix(name)).or_insert(0) += 1;
+    }
+    let collapsed = |p: &str| subs.get(p).is_some_and(|&n| n >= 2);
+
+    let mut out = Vec::new();
+    let mut placed = HashSet::new();
+    for &name in names {
+        let p = prefix(name);
+        let is_base = !name.contains(sep);
+        if !collapsed(&p) || (keep_base && is_base) {
+            out.push(name.to_string());
+        } else if placed.insert(p.clone()) {
+            let n = subs[&p];
+            out.push(if keep_base { format!("{p}{sep}* ({n})") } else { format!("{p} (+{n} subcommands)") });
+        }
+    }
+    out
+}
+
+// "foo [out, dev, man]"; just "foo" when only the default output is there.
+pub fn output_group(name: &str, outputs: &[&str]) -> String {
+    match outputs {
+        ["out"] => name.to_string(),
+        _ => format!("{name} [{}]", outputs.join(", ")),
+    }
+}
+
+// `s` cut to `width` chars, the last one replaced by "…" if anything was cut.
+pub fn ellipsize(s: &str, width: usize) -> String {
+    if s.chars().count() <= width {
+        return s.to_string();
+    }
+    let mut out: String = s.chars().take(width.saturating_sub(1)).collect();
+    out.push('…');
+    out
+}
+
+// (name, version) of each object in a saved package list: a JSON array of
+// package objects or NDJSON, as written by `json_object`. Other keys and
+// non-string values are skipped. None if the text isn't well-formed.
+pub fn parse_package_list(text: &str) -> Option<Vec<(String, String)>> {
+    let mut out = Vec::new();
+    let mut chars = text.chars();
+    let mut depth = 0usize;
+    let (mut key, mut after_colon) = (None::<String>, false);
+    let mut current = (None, None);
+    while let Some(c) = chars.next() {
+        match c {
+            '{' => {
+                depth += 1;
+                if depth == 1 {
+                    current = (None, None);
+                }
+                (key, after_colon) = (None, false);
+            }
+            '}' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    out.push((current.0.take()?, current.1.take().unwrap_or_default()));
+                }
+                (key, after_colon) = (None, false);
+            }
+            '"' => {
+                let s = json_unescape(&mut chars)?;
+                match key.take() {
+                    Some(k) if after_colon && depth == 1 => match k.as_str() {
+                        "name" => current.0 = Some(s),
+                        "version" => current.1 = Some(s),
+                        _ => {}
+                    },
+                    _ => key = Some(s),
+                }
+                after_colon = false;
+            }
+            ':' => after_colon = key.is_some(),
+            ',' => (key, after_colon) = (None, false),
+            _ => {}
+        }
+    }
+    (depth == 0).then_some(out)
+}
+
+// Rest of a JSON string after its opening quote, unescaped.
+fn json_unescape(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => match chars.next()? {
+                'n' => s.push('\n'),
+                'r' => s.push('\r'),
+                't' => s.push('\t'),
+                'b' => s.push('\u{8}'),
+                'f' => s.push('\u{c}'),
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    s.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+                }
+                c => s.push(c), // \" \\ \/
+            },
+            c => s.push(c),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    Name,
+    Version,
+    Hash,
+    Path,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Lit(String),
+    Field(Field),
+}
+
+// "{name}@{version}" style per-package template; "{{" and "}}" are literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template(Vec<Segment>);
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut segs = Vec::new();
+        let mut lit = String::new();
+        let mut chars = s.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    lit.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    lit.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed `{{{name}` in template")),
+                        }
+                    }
+                    let field = match name.as_str() {
+                        "name" => Field::Name,
+                        "version" => Field::Version,
+                        "hash" => Field::Hash,
+                        "path" => Field::Path,
+                        _ => return Err(format!("unknown template placeholder `{{{name}}}`")),
+                    };
+                    if !lit.is_empty() {
+                        segs.push(Segment::Lit(std::mem::take(&mut lit)));
+                    }
+                    segs.push(Segment::Field(field));
+                }
+                '}' => return Err("unmatched `}` in template (use `}}`)".into()),
+                c => lit.push(c),
+            }
+        }
+        if !lit.is_empty() {
+            segs.push(Segment::Lit(lit));
+        }
+        Ok(Template(segs))
+    }
+}
+
+impl Template {
+    pub fn render(&self, p: &Package) -> String {
+        let mut out = String::new();
+        for seg in &self.0 {
+            out.push_str(match seg {
+                Segment::Lit(s) => s,
+                Segment::Field(Field::Name) => p.name,
+                Segment::Field(Field::Version) => p.version,
+                Segment::Field(Field::Hash) => p.hash,
+                Segment::Field(Field::Path) => p.path,
+            });
+        }
+        out
+    }
+}
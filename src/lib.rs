@@ -0,0 +1,926 @@
+// Core PATH and store-path parsing, kept free of IO so it can be tested directly.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::str::FromStr;
+
+pub mod error;
+pub mod format;
+pub mod pattern;
+
+pub const SKIP: &[&str] = &["bash-interactive", "ghostty", "ghostty-bin"];
+
+// Common noise nobody wants in a prompt (locales, terminfo, certs).
+pub const DEFAULT_IGNORE: &[&str] = &[
+    "glibc-locales",
+    "locales",
+    "terminfo",
+    "ncurses",
+    "cacert",
+    "nss-cacert",
+    "tzdata",
+];
+
+// nix's base32 alphabet (no e, o, u, t).
+const BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+
+// Reverse lookup for BASE32; 0xff marks bytes outside the alphabet.
+const BASE32_DIGITS: [u8; 256] = {
+    let mut t = [0xff; 256];
+    let mut i = 0;
+    while i < 32 {
+        t[BASE32[i] as usize] = i as u8;
+        i += 1;
+    }
+    t
+};
+
+// Every byte in nix's base32 alphabet (no e, o, t or u).
+pub fn is_base32(hash: &str) -> bool {
+    hash.bytes().all(|c| BASE32_DIGITS[c as usize] != 0xff)
+}
+
+// 32-char store hash => the 20 bytes it encodes (nix's bit order: the last
+// char holds the lowest 5 bits). Decodes four independent 8-char groups of
+// 40 bits each, so there's no per-digit branching or long carry chain.
+pub fn decode_base32(s: &str) -> Option<[u8; 20]> {
+    let s: &[u8; 32] = s.as_bytes().try_into().ok()?;
+    let mut groups = [0u64; 4];
+    let mut seen = 0u8; // OR of all digits; 0xff if any char was invalid
+    for (group, chars) in groups.iter_mut().zip(s.chunks_exact(8)) {
+        for &c in chars {
+            let d = BASE32_DIGITS[c as usize];
+            seen |= d;
+            *group = *group << 5 | d as u64;
+        }
+    }
+    if seen & 0xe0 != 0 {
+        return None;
+    }
+    let [g0, g1, g2, g3] = groups.map(u128::from);
+    let low = g3 | g2 << 40 | g1 << 80 | g0 << 120;
+    let high = (g0 >> 8) as u32;
+    let mut out = [0u8; 20];
+    out[..16].copy_from_slice(&low.to_le_bytes());
+    out[16..].copy_from_slice(&high.to_le_bytes());
+    Some(out)
+}
+
+// A store hash as the 20 bytes its 32 chars encode. The ignore set holds
+// these and PATH hashes are decoded once per lookup, so membership is a
+// fixed-size compare. Displays as the 32-char form again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StoreHash(pub [u8; 20]);
+
+impl FromStr for StoreHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        decode_base32(s).map(StoreHash).ok_or_else(|| format!("`{s}` isn't a 32-char nix base32 hash"))
+    }
+}
+
+// The inverse of decode_base32: char n (from the end) is bits 5n..5n+5.
+impl fmt::Display for StoreHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        let mut out = [0u8; 32];
+        for (n, c) in out.iter_mut().rev().enumerate() {
+            let (i, j) = (n * 5 / 8, n * 5 % 8);
+            let hi = b.get(i + 1).map_or(0, |&h| (h as u16) << 8);
+            *c = BASE32[(((b[i] as u16 | hi) >> j) & 0x1f) as usize];
+        }
+        f.write_str(std::str::from_utf8(&out).unwrap_or_default())
+    }
+}
+
+// "/nix/store/<hash>-..." => "<hash>": exactly 32 base32 chars, then a dash.
+pub fn store_hash(path: &str) -> Option<&str> {
+    let hash = path.strip_prefix("/nix/store/")?.get(..32)?;
+    (path.as_bytes().get(43) == Some(&b'-') && is_base32(hash)).then_some(hash)
+}
+
+// Decoded store hashes are already uniformly distributed, so folding the
+// bytes together is enough; SipHash would cost more than the lookup itself.
+#[derive(Default)]
+struct FoldHasher(u64);
+
+impl Hasher for FoldHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for chunk in bytes.chunks(8) {
+            let mut word = [0u8; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.0 = self.0.rotate_left(5) ^ u64::from_le_bytes(word);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// Requisite hashes, stored decoded so lookups compare fixed-size arrays
+// instead of hashing strings. A hash outside the base32 alphabet can't be on
+// PATH (`hash_and_item` rejects it), so it's dropped rather than stored.
+#[derive(Debug, Clone, Default)]
+pub struct IgnoreSet {
+    hashes: HashSet<StoreHash, BuildHasherDefault<FoldHasher>>,
+}
+
+impl IgnoreSet {
+    pub fn insert(&mut self, hash: &str) {
+        if let Ok(h) = hash.parse() {
+            self.hashes.insert(h);
+        }
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        hash.parse().is_ok_and(|h| self.contains_hash(&h))
+    }
+
+    pub fn contains_hash(&self, hash: &StoreHash) -> bool {
+        self.hashes.contains(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.hashes.len()
+    }
+
+    // Everything in `other` too.
+    pub fn merge(&mut self, other: IgnoreSet) {
+        self.hashes.extend(other.hashes);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a> FromIterator<&'a str> for IgnoreSet {
+    fn from_iter<I: IntoIterator<Item = &'a str>>(iter: I) -> Self {
+        let mut set = IgnoreSet::default();
+        for h in iter {
+            set.insert(h);
+        }
+        set
+    }
+}
+
+// `out` from the first line that starts (after indentation) with `[` or `{`:
+// a noisy nix setup can print warnings or `builtins.trace` output to stdout
+// ahead of the JSON. All of `out` if no line does.
+pub fn json_payload(out: &[u8]) -> &[u8] {
+    let mut start = 0;
+    for line in out.split_inclusive(|&b| b == b'\n') {
+        if matches!(line.iter().find(|b| !b.is_ascii_whitespace()), Some(b'[' | b'{')) {
+            return &out[start..];
+        }
+        start += line.len();
+    }
+    out
+}
+
+// Whether `json` is a top-level array of strings, the only shape
+// `parse_hashes` can trust: its byte scan would happily pull hashes out of
+// objects or nested lists too.
+pub fn is_string_array(json: &[u8]) -> bool {
+    let ws = |i: &mut usize| {
+        while json.get(*i).is_some_and(u8::is_ascii_whitespace) {
+            *i += 1;
+        }
+    };
+    let mut i = 0;
+    ws(&mut i);
+    if json.get(i) != Some(&b'[') {
+        return false;
+    }
+    i += 1;
+    ws(&mut i);
+    if json.get(i) == Some(&b']') {
+        i += 1;
+        ws(&mut i);
+        return i == json.len();
+    }
+    loop {
+        if json.get(i) != Some(&b'"') {
+            return false;
+        }
+        i += 1;
+        // Skip the string, escapes included
+        loop {
+            match json.get(i) {
+                None => return false,
+                Some(b'"') => break,
+                Some(b'\\') => i += 2,
+                Some(_) => i += 1,
+            }
+        }
+        i += 1;
+        ws(&mut i);
+        match json.get(i) {
+            Some(b',') => i += 1,
+            Some(b']') => break,
+            _ => return false,
+        }
+        ws(&mut i);
+    }
+    i += 1;
+    ws(&mut i);
+    i == json.len()
+}
+
+pub fn parse_hashes(json: &[u8]) -> IgnoreSet {
+    let mut scanner = HashScanner::default();
+    scanner.feed(json);
+    scanner.finish()
+}
+
+// `parse_hashes` for output that arrives in chunks, so hashes are picked out
+// while nix is still writing. Only the last 43 bytes (a possible split
+// "/nix/store/<hash>-") are kept between chunks.
+#[derive(Default)]
+pub struct HashScanner {
+    hashes: IgnoreSet,
+    rest: Vec<u8>,  // unscanned tail of the previous chunks
+    carry: Vec<u8>, // a UTF-8 sequence split across chunks
+    invalid: bool,  // not UTF-8; `finish` then returns nothing, like parse_hashes
+}
+
+impl HashScanner {
+    pub fn feed(&mut self, chunk: &[u8]) {
+        self.check_utf8(chunk);
+        // Starts in `rest` need up to 43 bytes of `chunk` to be decided.
+        let old = self.rest.len();
+        let head = chunk.len().min(43);
+        self.rest.extend_from_slice(&chunk[..head]);
+        let n = scan_hashes(&self.rest, &mut self.hashes);
+        if head < 43 {
+            self.rest.drain(..n);
+            return;
+        }
+        // Scanning stopped at or past the end of `rest`, within 43 bytes.
+        let skip = n - old;
+        let m = skip + scan_hashes(&chunk[skip..], &mut self.hashes);
+        self.rest.clear();
+        self.rest.extend_from_slice(&chunk[m..]);
+    }
+
+    pub fn finish(self) -> IgnoreSet {
+        if self.invalid || !self.carry.is_empty() { IgnoreSet::default() } else { self.hashes }
+    }
+
+    fn check_utf8(&mut self, chunk: &[u8]) {
+        if self.invalid {
+            return;
+        }
+        let joined;
+        let bytes = if self.carry.is_empty() {
+            chunk
+        } else {
+            joined = [self.carry.as_slice(), chunk].concat();
+            &joined
+        };
+        match std::str::from_utf8(bytes) {
+            Ok(_) => self.carry.clear(),
+            Err(e) if e.error_len().is_none() => self.carry = bytes[e.valid_up_to()..].to_vec(),
+            Err(_) => self.invalid = true,
+        }
+    }
+}
+
+// Hashes at every start in `bytes` that still has a full "/nix/store/<hash>-"
+// window; returns the first start not looked at.
+fn scan_hashes(bytes: &[u8], hashes: &mut IgnoreSet) -> usize {
+    let mut i = 0;
+    while i + 44 <= bytes.len() {
+        if bytes[i..].starts_with(b"/nix/store/")
+            && let Some(hash) = std::str::from_utf8(&bytes[i..i + 44]).ok().and_then(store_hash)
+        {
+            hashes.insert(hash);
+            i += 43;
+        } else {
+            i += 1;
+        }
+    }
+    i
+}
+
+// "/nix/store/<hash>-bash-5.3/bin" => ("<hash>", "bash")
+pub fn hash_and_name(dir: &str) -> Option<(&str, &str)> {
+    let (hash, item) = hash_and_item(dir)?;
+    Some((hash, split_version(item).0))
+}
+
+// "/nix/store/<hash>-bash-5.3/bin" => ("<hash>", "bash-5.3")
+// Derivations and source files are never packages, so they yield None.
+// Extra dashes after the hash ("<hash>--foo") belong to the separator, not
+// the name.
+pub fn hash_and_item(dir: &str) -> Option<(&str, &str)> {
+    let hash = store_hash(dir)?;
+    let rest = dir.get(44..)?.trim_start_matches('-'); // after "<hash>-"
+    let item = rest.split('/').next().unwrap_or(""); // "bash-5.3p3"
+    if is_non_package(item) {
+        return None;
+    }
+    Some((hash, item))
+}
+
+// "/mnt/nix/store/<hash>-git/bin" with root "/mnt" => "/nix/store/<hash>-git/bin":
+// a chroot store's paths as the store itself names them. Anything else
+// (including plain /nix/store paths) is returned as is.
+pub fn in_store_root<'p>(dir: &'p str, root: &str) -> &'p str {
+    match dir.strip_prefix(root) {
+        Some(rest) if !root.is_empty() && rest.starts_with("/nix/store/") => rest,
+        _ => dir,
+    }
+}
+
+// Store items that are build inputs rather than outputs.
+const NON_PACKAGE_SUFFIXES: &[&str] = &[
+    ".drv", ".tar", ".tar.gz", ".tgz", ".tar.xz", ".tar.bz2", ".tar.zst", ".zip", ".patch", ".diff",
+];
+
+// "foo-1.0.drv", "foo-1.0.tar.gz" => true
+pub fn is_non_package(item: &str) -> bool {
+    NON_PACKAGE_SUFFIXES.iter().any(|s| item.ends_with(s))
+}
+
+// Substring search ignoring ASCII case; no allocation. "" is in everything.
+pub fn contains_ignore_case(haystack: &str, needle: &str) -> bool {
+    needle.is_empty() || haystack.as_bytes().windows(needle.len()).any(|w| w.eq_ignore_ascii_case(needle.as_bytes()))
+}
+
+// "foo-wrapped" / "foo.wrapped" => "foo": wrapper derivations named after
+// what they wrap. Anything else is returned as is.
+pub fn unwrapped_name(name: &str) -> &str {
+    match name.strip_suffix("-wrapped").or_else(|| name.strip_suffix(".wrapped")) {
+        Some(base) if !base.is_empty() => base,
+        _ => name,
+    }
+}
+
+// Output names nixpkgs appends to a multi-output store name ("foo-1.0-dev",
+// "foo-man"); the default output "out" gets no suffix.
+pub const OUTPUT_NAMES: &[&str] = &["bin", "dev", "lib", "man", "doc", "info", "devdoc", "debug", "static", "terminfo"];
+
+// git 2.40.1-doc => ("git", "doc"), foo-dev => ("foo", "dev"), git 2.40.1 => ("git", "out")
+pub fn split_output<'a>(pkg: &Package<'a>) -> (&'a str, &'a str) {
+    let suffix = |s: &'a str| s.rsplit_once('-').filter(|(_, out)| OUTPUT_NAMES.contains(out));
+    match pkg.version {
+        "" => suffix(pkg.name).unwrap_or((pkg.name, "out")),
+        v => (pkg.name, suffix(v).map_or("out", |(_, out)| out)),
+    }
+}
+
+// "bash-5.3p3" => ("bash", "5.3p3"), "rustup" => ("rustup", "")
+// Cuts at the first "-<digit>" that starts a version-like segment, so names
+// such as "foo-2fa-1.0" keep their digit-led components.
+pub fn split_version(item: &str) -> (&str, &str) {
+    let b = item.as_bytes();
+    for i in 0..b.len() {
+        if b[i] == b'-' && b.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
+            let seg = item[i + 1..].split('-').next().unwrap_or("");
+            if seg.contains('.') || is_undotted_version(seg) {
+                return (&item[..i], &item[i + 1..]);
+            }
+        }
+    }
+    (item, "")
+}
+
+// Digits, optionally with letter runs between digit runs and one trailing
+// letter: "2024", "8u402", "1p2", "2024a", "59c". Two or more trailing
+// letters ("2fa"), or a lone digit and letter ("3d", "4k"), read as part of a
+// name instead.
+fn is_undotted_version(seg: &str) -> bool {
+    if seg.len() == 2 {
+        return seg.bytes().all(|c| c.is_ascii_digit());
+    }
+    let mut rest = seg;
+    loop {
+        let after_digits = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+        if after_digits.len() == rest.len() {
+            return false;
+        }
+        if after_digits.is_empty() {
+            return true;
+        }
+        let after_letters = after_digits.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+        if after_letters.len() == after_digits.len() {
+            return false;
+        }
+        if after_letters.is_empty() {
+            return after_digits.len() == 1;
+        }
+        rest = after_letters;
+    }
+}
+
+// Next version component, as nix splits them: a run of digits or a run of
+// other non-separator chars; "." and "-" only separate. "" once exhausted.
+fn next_component<'a>(v: &mut &'a str) -> &'a str {
+    *v = v.trim_start_matches(['.', '-']);
+    let digits = v.starts_with(|c: char| c.is_ascii_digit());
+    let end = v
+        .find(|c: char| c == '.' || c == '-' || c.is_ascii_digit() != digits)
+        .unwrap_or(v.len());
+    let (component, rest) = v.split_at(end);
+    *v = rest;
+    component
+}
+
+// nix's componentsLT: numbers compare numerically and beat words, except
+// "pre" which sorts below everything; a missing component sorts below a number.
+fn component_lt(a: &str, b: &str) -> bool {
+    match (a.parse::<u64>(), b.parse::<u64>()) {
+        (Ok(x), Ok(y)) => x < y,
+        (_, Ok(_)) if a.is_empty() => true,
+        _ if a == "pre" && b != "pre" => true,
+        _ if b == "pre" => false,
+        (_, Ok(_)) => true, // 2.3a < 2.3.1
+        (Ok(_), _) => false,
+        _ => a < b,
+    }
+}
+
+// builtins.compareVersions: "2.40.1" vs "2.40" => Greater.
+pub fn compare_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    let (mut a, mut b) = (a, b);
+    while !a.is_empty() || !b.is_empty() {
+        let (x, y) = (next_component(&mut a), next_component(&mut b));
+        if component_lt(x, y) {
+            return std::cmp::Ordering::Less;
+        }
+        if component_lt(y, x) {
+            return std::cmp::Ordering::Greater;
+        }
+    }
+    std::cmp::Ordering::Equal
+}
+
+// Store packages on a PATH string as (name, version), first occurrence only.
+pub fn path_packages(path: &str, delimiter: char) -> Vec<(&str, &str)> {
+    let mut out = Vec::new();
+    let mut seen = HashSet::new();
+    for dir in path.split(delimiter).filter(|s| !s.is_empty()) {
+        if let Some((_, item)) = hash_and_item(dir) {
+            let (name, version) = split_version(item);
+            if !name.is_empty() && seen.insert(name) {
+                out.push((name, version));
+            }
+        }
+    }
+    out
+}
+
+// --sort-by name: byte order of the name ("Zed" < "_x" < "apple"), then of
+// the version and path. Plain `str` comparison, never the locale, so the
+// order is the same on every machine whatever LC_COLLATE says.
+pub fn sort_by_name(pkgs: &mut [Package]) {
+    pkgs.sort_by(|a, b| (a.name, a.version, a.path).cmp(&(b.name, b.version, b.path)));
+}
+
+// "+name" added, "-name" removed, "~name (old -> new)" version changed.
+pub fn diff_packages(old: &[(&str, &str)], new: &[(&str, &str)]) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (name, v_old) in old {
+        match new.iter().find(|(n, _)| n == name) {
+            None => lines.push(format!("-{name}")),
+            Some((_, v_new)) if v_new != v_old => {
+                lines.push(format!("~{name} ({v_old} -> {v_new})"))
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, _) in new {
+        if !old.iter().any(|(n, _)| n == name) {
+            lines.push(format!("+{name}"));
+        }
+    }
+    lines
+}
+
+// nix stderr phrasings for failures worth retrying (locked db, network hiccups).
+// Eval errors such as a missing flake are deterministic and never match.
+const TRANSIENT_NIX_ERRORS: &[&str] = &[
+    "database is locked",
+    "SQLite database",
+    "unable to download",
+    "Couldn't resolve host",
+    "Could not resolve host",
+    "Connection reset",
+    "Connection refused",
+    "Timeout was reached",
+    "timed out",
+    "HTTP error 5",
+];
+
+pub fn is_transient_nix_error(stderr: &str) -> bool {
+    TRANSIENT_NIX_ERRORS.iter().any(|p| stderr.contains(p))
+}
+
+// One kept PATH entry; all fields borrow from the PATH string.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Package<'a> {
+    pub name: &'a str,    // "git"
+    pub version: &'a str, // "2.40.1" (empty if none)
+    pub hash: &'a str,    // 32-char store hash
+    pub path: &'a str,    // "/nix/store/<hash>-git-2.40.1"
+}
+
+impl<'a> Package<'a> {
+    // "/nix/store/<hash>-git-2.40.1/bin" => git 2.40.1
+    pub fn from_dir(dir: &'a str) -> Option<Self> {
+        let (hash, item) = hash_and_item(dir)?;
+        let (name, version) = split_version(item);
+        let end = dir[44..].find('/').map_or(dir.len(), |i| 44 + i);
+        Some(Package { name, version, hash, path: &dir[..end] })
+    }
+}
+
+// `nix path-info --closure-size` lines ("<store path>  <bytes>") as (hash, bytes).
+pub fn parse_path_info_sizes(text: &str) -> Vec<(&str, u64)> {
+    text.lines()
+        .filter_map(|line| {
+            let mut it = line.split_whitespace();
+            let (hash, _) = hash_and_item(it.next()?)?;
+            Some((hash, it.next()?.parse().ok()?))
+        })
+        .collect()
+}
+
+// "90" / "90s", "30m", "2h", "1d" => seconds. Bare integers are seconds.
+pub fn parse_duration_secs(s: &str) -> Option<u64> {
+    let (num, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let mult = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => return None,
+    };
+    num.parse::<u64>().ok()?.checked_mul(mult)
+}
+
+// Nix system double: "x86_64-linux", "aarch64-darwin". Lowercase arch and
+// os, nothing a nix string literal would need escaped.
+pub fn is_valid_system(s: &str) -> bool {
+    let part = |p: &str| !p.is_empty() && p.bytes().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == b'_');
+    s.split_once('-').is_some_and(|(arch, os)| part(arch) && part(os))
+}
+
+// nix can't resolve the flake reference (e.g. "nixpkgs" missing from the registry).
+pub fn is_flake_not_found(stderr: &str) -> bool {
+    stderr.contains("cannot find flake")
+}
+
+// nix without `nix-command` / `flakes` enabled: "experimental Nix feature
+// 'flakes' is disabled; add '--extra-experimental-features flakes' ...".
+pub fn is_flakes_disabled(stderr: &str) -> bool {
+    stderr.contains("experimental Nix feature") && stderr.contains("is disabled")
+}
+
+// nix errors about the flake/expression itself; no retry or wait will fix them.
+const BAD_EXPR_NIX_ERRORS: &[&str] = &[
+    "cannot find flake",
+    "experimental Nix feature",
+    "does not provide attribute",
+    "undefined variable",
+    "syntax error",
+];
+
+pub fn is_bad_expr(stderr: &str) -> bool {
+    BAD_EXPR_NIX_ERRORS.iter().any(|p| stderr.contains(p))
+        || stderr.contains("attribute '") && stderr.contains("' missing")
+}
+
+// Upper bound on kept packages: one per PATH entry (or `first`). Sizing walk
+// collections from this avoids regrowth on 100+ entry dev-shell PATHs.
+pub fn entries_hint(path: &str, delimiter: char, first: Option<usize>) -> usize {
+    let n = path.matches(delimiter).count() + 1;
+    first.map_or(n, |f| f.min(n))
+}
+
+// Counters from one PATH walk. `scanned` counts every non-empty entry,
+// including ones that aren't store paths.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub scanned: usize,
+    pub kept: usize,
+    pub skipped: usize,    // skip lists
+    pub ignored: usize,    // requisite hashes / hash prefixes
+    pub duplicates: usize, // name already kept earlier on PATH
+}
+
+// What to drop from a PATH walk. Only `ignore` and `skip` are required.
+#[derive(Clone, Copy)]
+pub struct Filter<'a> {
+    pub ignore: &'a IgnoreSet,            // requisite hashes (exact)
+    pub skip: &'a HashSet<&'a str>,       // package names
+    pub ignore_prefixes: &'a [String],    // user-supplied hash prefixes
+    pub first: Option<usize>,             // stop after this many packages
+    pub requisites_only: bool,            // keep only hashes IN `ignore`
+    pub only: Option<&'a HashSet<String>>, // allowlisted package names
+    pub delimiter: char,                  // between PATH entries
+    pub min_versions: &'a [(String, String)], // (name, version) minimums
+    pub skip_patterns: &'a [pattern::Regex], // package names, searched
+    pub dedup_wrappers: bool,             // "foo-wrapped" counts as "foo"
+    pub case_insensitive: bool,           // "Git" == "git" for dedup and `skip`
+    pub search: Option<&'a str>,          // keep only names containing this (any case)
+    pub search_exact: bool,               // ...or equal to it, with `search`
+    pub store_root: &'a str,              // "/mnt": entries under /mnt/nix/store count too
+    pub skip_versioned: &'a [(String, String)], // (name, version prefix) pairs
+    pub keep_duplicates: bool,            // every qualifying entry, repeats included
+    pub include_globs: &'a [pattern::Glob], // if any, keep only names matching one
+    pub exclude_globs: &'a [pattern::Glob], // package names, whole-name match
+}
+
+impl<'a> Filter<'a> {
+    pub fn new(ignore: &'a IgnoreSet, skip: &'a HashSet<&'a str>) -> Self {
+        Filter { ignore, skip, ignore_prefixes: &[], first: None, requisites_only: false, only: None, delimiter: ':', min_versions: &[], skip_patterns: &[], dedup_wrappers: false, case_insensitive: false, search: None, search_exact: false, store_root: "", skip_versioned: &[], keep_duplicates: false, include_globs: &[], exclude_globs: &[] }
+    }
+
+    // The package for a PATH entry, as this filter names it.
+    fn package<'p>(&self, dir: &'p str) -> Option<Package<'p>> {
+        let mut pkg = Package::from_dir(in_store_root(dir, self.store_root))?;
+        if self.dedup_wrappers {
+            pkg.name = unwrapped_name(pkg.name);
+        }
+        Some(pkg)
+    }
+
+    // What `name` is deduplicated as; only allocates for a name that folds.
+    pub fn dedup_key<'p>(&self, name: &'p str) -> Cow<'p, str> {
+        if self.case_insensitive && name.bytes().any(|b| b.is_ascii_uppercase()) {
+            Cow::Owned(name.to_ascii_lowercase())
+        } else {
+            Cow::Borrowed(name)
+        }
+    }
+
+    fn is_ignored(&self, pkg: &Package) -> bool {
+        self.ignore.contains(pkg.hash) != self.requisites_only
+            || self.ignore_prefixes.iter().any(|p| pkg.hash.starts_with(p.as_str()))
+    }
+
+    fn too_old(&self, pkg: &Package) -> bool {
+        self.min_versions
+            .iter()
+            .any(|(name, min)| name == pkg.name && compare_versions(pkg.version, min).is_lt())
+    }
+
+    fn misses_search(&self, pkg: &Package) -> bool {
+        match self.search {
+            Some(q) if self.search_exact => !pkg.name.eq_ignore_ascii_case(q),
+            Some(q) => !contains_ignore_case(pkg.name, q),
+            None => false,
+        }
+    }
+
+    // ("python3", "3.11") covers python3 3.11 and 3.11.8, not 3.12 or 3.110.
+    fn skipped_version(&self, pkg: &Package) -> bool {
+        self.skip_versioned.iter().any(|(name, version)| {
+            name == pkg.name
+                && pkg.version.strip_prefix(version.as_str()).is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+
+    fn is_skipped(&self, pkg: &Package) -> bool {
+        pkg.name.is_empty()
+            || self.skip.contains(pkg.name)
+            || (self.case_insensitive && self.skip.iter().any(|s| s.eq_ignore_ascii_case(pkg.name)))
+            || self.skip_patterns.iter().any(|r| r.is_match(pkg.name))
+            || self.exclude_globs.iter().any(|g| g.matches(pkg.name))
+            || (!self.include_globs.is_empty() && !self.include_globs.iter().any(|g| g.matches(pkg.name)))
+            || self.skipped_version(pkg)
+            || self.only.is_some_and(|only| !only.contains(pkg.name))
+            || self.too_old(pkg)
+            || self.misses_search(pkg)
+    }
+
+    // Lazy walk of `path`: kept packages in PATH order, first occurrence only
+    // (and only `first` of them). PATH entries past the point where the caller
+    // stops pulling are never looked at.
+    pub fn iter<'p>(&self, path: &'p str) -> Packages<'a, 'p> {
+        Packages {
+            filter: *self,
+            entries: path.split(self.delimiter),
+            seen: HashSet::with_capacity(entries_hint(path, self.delimiter, self.first)),
+            stats: Stats::default(),
+        }
+    }
+
+    // Walk PATH in order, calling `f` for each kept package as it's found.
+    pub fn for_each<'p>(&self, path: &'p str, f: impl FnMut(Package<'p>)) -> Stats {
+        let mut walk = self.iter(path);
+        walk.by_ref().for_each(f);
+        walk.stats()
+    }
+
+    // Kept names found on PATH with more than one distinct version, versions in
+    // PATH order (the first one wins). Walks every entry, ignoring `first`.
+    pub fn shadowed<'p>(&self, path: &'p str) -> Vec<(&'p str, Vec<&'p str>)> {
+        let mut versions: Vec<(&str, Vec<&str>)> = Vec::new();
+        for dir in path.split(self.delimiter).filter(|s| !s.is_empty()) {
+            let Some(pkg) = self.package(dir) else {
+                continue;
+            };
+            if self.is_ignored(&pkg) || self.is_skipped(&pkg) {
+                continue;
+            }
+            match versions.iter_mut().find(|(name, _)| *name == pkg.name) {
+                Some((_, seen)) if !seen.contains(&pkg.version) => seen.push(pkg.version),
+                Some(_) => {}
+                None => versions.push((pkg.name, vec![pkg.version])),
+            }
+        }
+        versions.retain(|(_, seen)| seen.len() > 1);
+        versions
+    }
+
+    // How many PATH entries each name had before dedup, keyed by `dedup_key`.
+    // Ignored and skipped entries don't count. Walks every entry, ignoring `first`.
+    pub fn occurrences<'p>(&self, path: &'p str) -> HashMap<Cow<'p, str>, usize> {
+        let mut counts = HashMap::new();
+        for dir in path.split(self.delimiter).filter(|s| !s.is_empty()) {
+            let Some(pkg) = self.package(dir) else {
+                continue;
+            };
+            if !self.is_ignored(&pkg) && !self.is_skipped(&pkg) {
+                *counts.entry(self.dedup_key(pkg.name)).or_insert(0) += 1;
+            }
+        }
+        counts
+    }
+
+    // Every PATH entry that names `name`, in order, with what the walk made of
+    // it. At most one is Kept: the one the list shows. Ignores `first`.
+    pub fn explain<'p>(&self, path: &'p str, name: &str) -> Vec<(Package<'p>, Verdict)> {
+        let want = self.dedup_key(name);
+        let mut kept = false;
+        let mut found = Vec::new();
+        for dir in path.split(self.delimiter).filter(|s| !s.is_empty()) {
+            let Some(pkg) = self.package(dir) else {
+                continue;
+            };
+            if self.dedup_key(pkg.name) != want {
+                continue;
+            }
+            let verdict = if self.is_ignored(&pkg) {
+                Verdict::Ignored
+            } else if self.is_skipped(&pkg) {
+                Verdict::Skipped
+            } else if kept {
+                Verdict::Duplicate
+            } else {
+                kept = true;
+                Verdict::Kept
+            };
+            found.push((pkg, verdict));
+        }
+        found
+    }
+
+    // Kept packages grouped by base name (see `split_output`), each with its
+    // outputs in PATH order. `first` counts groups.
+    pub fn outputs<'p>(&self, path: &'p str) -> Vec<(&'p str, Vec<&'p str>)> {
+        let mut groups: Vec<(&str, Vec<&str>)> = Vec::new();
+        for dir in path.split(self.delimiter).filter(|s| !s.is_empty()) {
+            let Some(pkg) = self.package(dir) else {
+                continue;
+            };
+            if self.is_ignored(&pkg) || self.is_skipped(&pkg) {
+                continue;
+            }
+            let (name, output) = split_output(&pkg);
+            let full = self.first == Some(groups.len());
+            match groups.iter_mut().find(|(n, _)| *n == name) {
+                Some((_, seen)) if !seen.contains(&output) => seen.push(output),
+                Some(_) => {}
+                None if full => {}
+                None => groups.push((name, vec![output])),
+            }
+        }
+        groups
+    }
+
+    // Kept PATH entries themselves ("/nix/store/<hash>-git-2.40.1/bin") in
+    // order. Same ignore/skip checks as `for_each`, but deduplicated by entry
+    // rather than by name, so each distinct store dir is listed once.
+    pub fn for_each_dir<'p>(&self, path: &'p str, mut f: impl FnMut(&'p str)) -> Stats {
+        let mut stats = Stats::default();
+        let mut seen: HashSet<&str> = HashSet::with_capacity(entries_hint(path, self.delimiter, self.first));
+        for dir in path.split(self.delimiter).filter(|s| !s.is_empty()) {
+            if self.first == Some(stats.kept) {
+                break;
+            }
+            stats.scanned += 1;
+            let Some(pkg) = self.package(dir) else {
+                continue;
+            };
+            if self.is_ignored(&pkg) {
+                stats.ignored += 1;
+            } else if self.is_skipped(&pkg) {
+                stats.skipped += 1;
+            } else if !self.keep_duplicates && !seen.insert(dir) {
+                stats.duplicates += 1;
+            } else {
+                stats.kept += 1;
+                f(dir);
+            }
+        }
+        stats
+    }
+
+    pub fn packages<'p>(&self, path: &'p str) -> Vec<Package<'p>> {
+        let mut out = Vec::with_capacity(entries_hint(path, self.delimiter, self.first));
+        self.for_each(path, |p| out.push(p));
+        out
+    }
+
+    pub fn select(&self, path: &str) -> Vec<String> {
+        let mut ordered = Vec::with_capacity(entries_hint(path, self.delimiter, self.first));
+        self.for_each(path, |p| ordered.push(p.name.to_string()));
+        ordered
+    }
+}
+
+// What the walk did with one PATH entry (see `Filter::explain`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Kept,      // listed
+    Ignored,   // stdenv requisite or --ignore-hash
+    Skipped,   // skip list, --skip-regex, --only-from, --min-version, ...
+    Duplicate, // an earlier entry with the same name was kept
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Verdict::Kept => "kept",
+            Verdict::Ignored => "ignored",
+            Verdict::Skipped => "skipped",
+            Verdict::Duplicate => "duplicate",
+        })
+    }
+}
+
+// Iterator from `Filter::iter`. `'f` is the filter's borrow of its sets (ignore,
+// skip, ...), `'p` the PATH string; yielded packages borrow only from `'p`, so
+// they can outlive both the iterator and the filter.
+pub struct Packages<'f, 'p> {
+    filter: Filter<'f>,
+    entries: std::str::Split<'p, char>,
+    seen: HashSet<Cow<'p, str>>, // folded names under `case_insensitive`
+    stats: Stats,
+}
+
+impl<'p> Packages<'_, 'p> {
+    // Counters for the entries walked so far.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+impl<'p> Iterator for Packages<'_, 'p> {
+    type Item = Package<'p>;
+
+    fn next(&mut self) -> Option<Package<'p>> {
+        if self.filter.first == Some(self.stats.kept) {
+            return None;
+        }
+        for dir in self.entries.by_ref().filter(|s| !s.is_empty()) {
+            self.stats.scanned += 1;
+            let Some(pkg) = self.filter.package(dir) else {
+                continue;
+            };
+            if self.filter.is_ignored(&pkg) {
+                self.stats.ignored += 1;
+            } else if self.filter.is_skipped(&pkg) {
+                self.stats.skipped += 1;
+            } else if !self.filter.keep_duplicates && !self.seen.insert(self.filter.dedup_key(pkg.name)) {
+                self.stats.duplicates += 1;
+            } else {
+                self.stats.kept += 1;
+                return Some(pkg);
+            }
+        }
+        None
+    }
+}
+
+// Lazy counterpart of `select_packages`: borrowed packages, nothing allocated
+// per entry.
+pub fn packages_iter<'a>(path: &'a str, ignore: &'a IgnoreSet, skip: &'a HashSet<&'a str>) -> Packages<'a, 'a> {
+    Filter::new(ignore, skip).iter(path)
+}
+
+// Packages on `path` that survive the requisite hashes and skip list, in PATH order.
+pub fn select_packages(path: &str, ignore: &IgnoreSet, skip: &HashSet<&str>) -> Vec<String> {
+    Filter::new(ignore, skip).select(path)
+}
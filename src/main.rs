@@ -1,236 +1,2477 @@
 use std::{
-    collections::HashSet, env, fs, io,
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    env, fmt, fs,
+    io::{self, BufRead, BufReader, BufWriter, IsTerminal, Read, Write},
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{DirBuilderExt, FileTypeExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
     path::{Path, PathBuf},
-    process::{Command, ExitCode},
-    time::{Duration, SystemTime},
+    process::{Command, ExitCode, Output, Stdio},
+    sync::{
+        OnceLock,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
+use nix_path_pkgs::error::Error;
+use nix_path_pkgs::format::{self, Format, Template};
+use nix_path_pkgs::pattern::{Glob, Regex};
+use nix_path_pkgs::{DEFAULT_IGNORE, Filter, HashScanner, IgnoreSet, Package, SKIP, Stats, entries_hint, diff_packages, in_store_root, is_bad_expr, is_base32, is_string_array, is_transient_nix_error, is_valid_system, json_payload, parse_duration_secs, parse_hashes, parse_path_info_sizes, path_packages, sort_by_name, split_version, store_hash};
+
+// `@flake@` is substituted with the quoted flake reference (default "nixpkgs"),
+// `@system@` with --system or the current system.
 const NIX_EXPR: &str = r#"
-with builtins.getFlake "nixpkgs";
-with legacyPackages.${builtins.currentSystem};
+with builtins.getFlake @flake@;
+with legacyPackages.${@system@};
 lib.filter lib.isDerivation stdenv.allowedRequisites
 "#;
 
-const SKIP: &[&str] = &["bash-interactive", "ghostty", "ghostty-bin"];
+// --filter-set bootstrap: just the toolchain every build gets (compiler,
+// libc, binutils, coreutils), so tools that happen to be in the wider
+// allowedRequisites closure stay listed.
+const BOOTSTRAP_EXPR: &str = r#"
+with builtins.getFlake @flake@;
+with legacyPackages.${@system@};
+lib.filter lib.isDerivation [ stdenv.cc stdenv.cc.cc stdenv.cc.bintools stdenv.cc.bintools.bintools stdenv.cc.libc coreutils ]
+"#;
+
+// Leading line of every cache file. Bump when the format changes so files
+// written by older versions are ignored instead of misread.
+const CACHE_MAGIC: &[u8] = b"nix-path-pkgs cache v1\n";
+
+// Cache dirs with fewer entries than this aren't worth a cleanup walk.
+const CLEANUP_MIN_ENTRIES: usize = 4;
+
+// --quiet: nothing of ours on stderr; the exit code still tells what happened.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Flag/env/default settings for this run; see `config()`.
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+// eprintln! unless --quiet.
+macro_rules! note {
+    ($($arg:tt)*) => {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+struct Opts {
+    flake: String,
+    diff: Option<(PathBuf, PathBuf)>,
+    ignore_hash: Vec<String>,
+    skip_regex: Vec<Regex>,
+    include: Vec<Glob>,
+    exclude: Vec<Glob>,
+    serve: Option<PathBuf>,
+    first: Option<usize>,
+    no_default_ignore: bool,
+    format: Format,
+    template: Option<Template>,
+    json_pretty: bool,
+    sort_by: Option<SortBy>,
+    group_by: Option<GroupBy>,
+    short_hash: Option<usize>,
+    ttl: Option<String>,
+    refresh: bool,
+    verbose: bool,
+    subcommand: Option<Subcommand>,
+    force: bool,
+    include_requisites: bool,
+    output: Option<PathBuf>,
+    write_empty: bool,
+    strip_version: bool,
+    only_from: Option<PathBuf>,
+    path_delimiter: char,
+    color: Color,
+    profile: Option<PathBuf>,
+    generation: Option<u32>,
+    baseline: Option<PathBuf>,
+    update_baseline: bool,
+    min_versions: Vec<(String, String)>,
+    fail_on_shadow: bool,
+    ignore_file: Option<PathBuf>,
+    metrics: bool,
+    explain_cache: bool,
+    dedup_wrappers: bool,
+    all_profiles: bool,
+    case_insensitive: bool,
+    since_last: bool,
+    json_schema: bool,
+    no_newline: bool,
+    system: Option<String>,
+    search: Option<String>,
+    exact: bool,
+    dump_config: bool,
+    delta_from_login: bool,
+    store: Option<String>,
+    show_rev: bool,
+    skip_versioned: Vec<(String, String)>,
+    ignore_self: bool,
+    explain_package: Option<String>,
+    with_counts: bool,
+    tree: bool,
+    no_dedup: bool,
+    output_on_empty: Option<String>,
+    empty_exit_code: Option<u8>,
+    snapshot: Option<PathBuf>,
+    collapse_prefix: Option<String>,
+    collapse_keep_base: bool,
+    parallel_nix: bool,
+    cache_file: Option<PathBuf>,
+    include_result: bool,
+    profile_diff: Option<(u32, u32)>,
+    copy: bool,
+    filter_set: FilterSet,
+    dry_run_nix: bool,
+    nixpkgs_rev: Option<String>, // not a flag: set from the cache key under --show-rev
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    Auto, // terminal stdout, unless NO_COLOR / CLICOLOR / CLICOLOR_FORCE say otherwise
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Subcommand {
+    Warm,        // fill the cache, print nothing
+    Snapshot,    // write the list to `Opts.snapshot`
+    ProfileDiff, // diff the two generations in `Opts.profile_diff`
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SortBy {
+    Size,
+    Name,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GroupBy {
+    Output, // "foo [out, dev]"
+}
+
+// What the ignore set is evaluated from (--filter-set).
+#[derive(Clone, Copy, Default, PartialEq, Eq)]
+enum FilterSet {
+    #[default]
+    AllowedRequisites, // stdenv.allowedRequisites
+    Bootstrap,         // BOOTSTRAP_EXPR
+    None,              // nothing is ignored; nix isn't run
+}
+
+impl FilterSet {
+    // Never evaluated for None: main gives that an empty preset instead.
+    fn expr(self) -> &'static str {
+        match self {
+            FilterSet::AllowedRequisites | FilterSet::None => NIX_EXPR,
+            FilterSet::Bootstrap => BOOTSTRAP_EXPR,
+        }
+    }
+
+    // Each set is cached in its own file next to the others.
+    fn cache_suffix(self) -> &'static str {
+        match self {
+            FilterSet::AllowedRequisites | FilterSet::None => CACHE_SUFFIXES[0],
+            FilterSet::Bootstrap => CACHE_SUFFIXES[1],
+        }
+    }
+}
+
+impl fmt::Display for FilterSet {
+    // The --filter-set value that selects it.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FilterSet::AllowedRequisites => "allowed-requisites",
+            FilterSet::Bootstrap => "bootstrap",
+            FilterSet::None => "none",
+        })
+    }
+}
+
+fn value(args: &mut impl Iterator<Item = String>, flag: &str) -> Result<String, String> {
+    args.next().ok_or_else(|| format!("{flag} needs a value"))
+}
+
+impl Default for Opts {
+    fn default() -> Self {
+        Opts {
+            flake: "nixpkgs".into(),
+            diff: None,
+            ignore_hash: Vec::new(),
+            skip_regex: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            serve: None,
+            first: None,
+            no_default_ignore: false,
+            format: Format::default(),
+            template: None,
+            json_pretty: false,
+            sort_by: None,
+            group_by: None,
+            short_hash: None,
+            ttl: None,
+            refresh: false,
+            verbose: false,
+            subcommand: None,
+            force: false,
+            include_requisites: false,
+            output: None,
+            write_empty: false,
+            strip_version: false,
+            only_from: None,
+            path_delimiter: ':',
+            color: Color::Auto,
+            profile: None,
+            generation: None,
+            baseline: None,
+            update_baseline: false,
+            min_versions: Vec::new(),
+            fail_on_shadow: false,
+            ignore_file: None,
+            metrics: false,
+            explain_cache: false,
+            dedup_wrappers: false,
+            all_profiles: false,
+            case_insensitive: false,
+            since_last: false,
+            json_schema: false,
+            no_newline: false,
+            system: None,
+            search: None,
+            exact: false,
+            dump_config: false,
+            delta_from_login: false,
+            store: None,
+            show_rev: false,
+            skip_versioned: Vec::new(),
+            ignore_self: false,
+            explain_package: None,
+            with_counts: false,
+            tree: false,
+            no_dedup: false,
+            output_on_empty: None,
+            empty_exit_code: None,
+            snapshot: None,
+            collapse_prefix: None,
+            collapse_keep_base: false,
+            parallel_nix: false,
+            cache_file: None,
+            include_result: false,
+            profile_diff: None,
+            copy: false,
+            filter_set: FilterSet::default(),
+            dry_run_nix: false,
+            nixpkgs_rev: None,
+        }
+    }
+}
+
+// `format` is the default from NIX_PATH_PKGS_FORMAT; --format and friends override it.
+fn parse_args(mut args: impl Iterator<Item = String>, format: Format) -> Result<Opts, String> {
+    let mut opts = Opts { format, ..Opts::default() };
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--diff" => match (args.next(), args.next()) {
+                (Some(a), Some(b)) => opts.diff = Some((a.into(), b.into())),
+                _ => return Err("--diff needs two files".into()),
+            },
+            "--ignore-hash" => {
+                let prefix = value(&mut args, &arg)?;
+                // Store hashes are lowercase nix base32 (no e, o, u or t); any
+                // other prefix could never match.
+                if prefix.is_empty() || prefix.len() > 32 || !is_base32(&prefix) {
+                    return Err(format!("invalid hash prefix `{prefix}` (expected up to 32 nix base32 chars)"));
+                }
+                opts.ignore_hash.push(prefix);
+            }
+            "--skip-regex" => {
+                let pat = value(&mut args, &arg)?;
+                opts.skip_regex.push(pat.parse().map_err(|e| format!("--skip-regex `{pat}`: {e}"))?);
+            }
+            "--include" | "--exclude" => {
+                let pat = value(&mut args, &arg)?;
+                let glob = pat.parse().map_err(|e| format!("{arg} `{pat}`: {e}"))?;
+                let globs = if arg == "--include" { &mut opts.include } else { &mut opts.exclude };
+                globs.push(glob);
+            }
+            "--first" => match value(&mut args, &arg)?.parse() {
+                Ok(n) if n > 0 => opts.first = Some(n),
+                _ => return Err("--first needs a positive count".into()),
+            },
+            "--flake" => opts.flake = value(&mut args, &arg)?,
+            "--format" => opts.format = value(&mut args, &arg)?.parse()?,
+            "--pretty" => opts.format = Format::Table,
+            "--print-paths" => opts.format = Format::Paths,
+            "--raw-paths" => opts.format = Format::RawPaths,
+            "--list-names-only" => opts.format = Format::Names,
+            "--color" => match value(&mut args, &arg)?.as_str() {
+                "auto" => opts.color = Color::Auto,
+                "always" => opts.color = Color::Always,
+                "never" => opts.color = Color::Never,
+                other => return Err(format!("unknown color mode `{other}` (expected auto, always or never)")),
+            },
+            "--json-pretty" => opts.json_pretty = true,
+            "--json-compact" => opts.json_pretty = false,
+            "--format-template" => opts.template = Some(value(&mut args, &arg)?.parse()?),
+            "--no-default-ignore" => opts.no_default_ignore = true,
+            "--strip-version-always" => opts.strip_version = true,
+            "--profile" => opts.profile = Some(match value(&mut args, &arg)?.as_str() {
+                "current-system" => SYSTEM_PROFILE.into(),
+                other => other.into(),
+            }),
+            "--generation" => match value(&mut args, &arg)?.parse() {
+                Ok(n) => opts.generation = Some(n),
+                _ => return Err("--generation needs a generation number".into()),
+            },
+            "--require" | "--min-version" => match value(&mut args, &arg)?.split_once('=') {
+                Some((name, version)) if !name.is_empty() && !version.is_empty() => {
+                    opts.min_versions.push((name.into(), version.into()))
+                }
+                _ => return Err(format!("{arg} needs name=version")),
+            },
+            "--ignore-file" => opts.ignore_file = Some(value(&mut args, &arg)?.into()),
+            "--metrics" => opts.metrics = true,
+            "--explain-cache" => opts.explain_cache = true,
+            "--dedup-wrappers" => opts.dedup_wrappers = true,
+            "--all-profiles" => opts.all_profiles = true,
+            "--case-insensitive" => opts.case_insensitive = true,
+            "--since-last" => opts.since_last = true,
+            "--json-schema" => opts.json_schema = true,
+            "--dry-run-nix" => opts.dry_run_nix = true,
+            "--no-newline" => opts.no_newline = true,
+            "--exact" => opts.exact = true,
+            "--dump-config" => opts.dump_config = true,
+            "--delta-from-login" => opts.delta_from_login = true,
+            "--show-rev" => opts.show_rev = true,
+            "--ignore-self" => opts.ignore_self = true,
+            "--with-counts" => opts.with_counts = true,
+            "--tree" => opts.tree = true,
+            "--no-dedup" => opts.no_dedup = true,
+            "--collapse-prefix" => match value(&mut args, &arg)? {
+                sep if !sep.is_empty() => opts.collapse_prefix = Some(sep),
+                _ => return Err("--collapse-prefix needs a separator, e.g. -".into()),
+            },
+            "--collapse-keep-base" => opts.collapse_keep_base = true,
+            "--parallel-nix" => opts.parallel_nix = true,
+            "--include-result" => opts.include_result = true,
+            "--cache-file" => opts.cache_file = Some(value(&mut args, &arg)?.into()),
+            "--output-on-empty" => opts.output_on_empty = Some(value(&mut args, &arg)?),
+            "--empty-exit-code" => match value(&mut args, &arg)?.parse() {
+                Ok(code) => opts.empty_exit_code = Some(code),
+                _ => return Err("--empty-exit-code needs a number from 0 to 255".into()),
+            },
+            // PATH entries are only ever parsed as strings (no stat/readlink),
+            // which is what this asks for; accepted so scripts can pin it.
+            "--assume-store-paths" => {}
+            "--explain-package" => opts.explain_package = Some(value(&mut args, &arg)?),
+            "--skip-versioned" => match split_version(&value(&mut args, &arg)?) {
+                (name, version) if !name.is_empty() && !version.is_empty() => {
+                    opts.skip_versioned.push((name.into(), version.into()))
+                }
+                _ => return Err("--skip-versioned needs name-version, e.g. python3-3.11".into()),
+            },
+            "--store" => {
+                let root = value(&mut args, &arg)?;
+                let root = root.trim_end_matches('/');
+                if !root.starts_with('/') || root.contains([':', '?']) {
+                    return Err(format!("invalid --store `{root}` (expected an absolute directory, e.g. /mnt)"));
+                }
+                opts.store = Some(root.into());
+            }
+            "--system" => {
+                let system = value(&mut args, &arg)?;
+                if !is_valid_system(&system) {
+                    return Err(format!("invalid --system `{system}` (expected <arch>-<os>, e.g. x86_64-linux)"));
+                }
+                opts.system = Some(system);
+            }
+            "--fail-on-shadow" => opts.fail_on_shadow = true,
+            "--baseline" => opts.baseline = Some(value(&mut args, &arg)?.into()),
+            "--update-baseline" => opts.update_baseline = true,
+            "--only-from" => opts.only_from = Some(value(&mut args, &arg)?.into()),
+            "--path-delimiter" => opts.path_delimiter = parse_delimiter(&value(&mut args, &arg)?)?,
+            "--include-requisites" => opts.include_requisites = true,
+            "--sort-by" => match value(&mut args, &arg)?.as_str() {
+                "size" => opts.sort_by = Some(SortBy::Size),
+                "name" => opts.sort_by = Some(SortBy::Name),
+                other => return Err(format!("unknown sort key `{other}` (expected size or name)")),
+            },
+            "--short-hash" => opts.short_hash = opts.short_hash.or(Some(8)),
+            "--short-hash-length" => match value(&mut args, &arg)?.parse() {
+                Ok(n @ 1..=32) => opts.short_hash = Some(n),
+                _ => return Err("--short-hash-length needs a length from 1 to 32".into()),
+            },
+            "--filter-set" => match value(&mut args, &arg)?.as_str() {
+                "allowed-requisites" => opts.filter_set = FilterSet::AllowedRequisites,
+                "bootstrap" => opts.filter_set = FilterSet::Bootstrap,
+                "none" => opts.filter_set = FilterSet::None,
+                other => {
+                    return Err(format!("unknown filter set `{other}` (expected allowed-requisites, bootstrap or none)"));
+                }
+            },
+            "--exclude-stdenv-only" => opts.filter_set = FilterSet::Bootstrap,
+            "--group-by" => match value(&mut args, &arg)?.as_str() {
+                "output" => opts.group_by = Some(GroupBy::Output),
+                other => return Err(format!("unknown grouping `{other}` (expected output)")),
+            },
+            "--ttl" => opts.ttl = Some(value(&mut args, &arg)?),
+            "--refresh" => opts.refresh = true,
+            "-v" | "--verbose" => opts.verbose = true,
+            "-q" | "--quiet" => {} // see QUIET
+            "-o" | "--output" => opts.output = Some(value(&mut args, &arg)?.into()),
+            "--write-empty" => opts.write_empty = true,
+            "--copy" => opts.copy = true,
+            "--serve" => opts.serve = Some(value(&mut args, &arg)?.into()),
+            "--force" => opts.force = true,
+            "warm" if opts.subcommand.is_none() => opts.subcommand = Some(Subcommand::Warm),
+            "snapshot" if opts.subcommand.is_none() => {
+                opts.subcommand = Some(Subcommand::Snapshot);
+                opts.snapshot = Some(value(&mut args, &arg)?.into());
+            }
+            "profile-diff" | "--profile-diff" if opts.subcommand.is_none() => {
+                opts.subcommand = Some(Subcommand::ProfileDiff);
+                match (args.next().map(|a| a.parse()), args.next().map(|b| b.parse())) {
+                    (Some(Ok(a)), Some(Ok(b))) => opts.profile_diff = Some((a, b)),
+                    _ => return Err(format!("{arg} needs two generation numbers")),
+                }
+            }
+            // `nix-path-pkgs cargo`: only names containing "cargo"
+            search if !search.is_empty() && !search.starts_with('-') && opts.search.is_none() => {
+                opts.search = Some(arg)
+            }
+            _ => return Err(format!("unknown argument `{arg}`")),
+        }
+    }
+    if opts.force && opts.subcommand != Some(Subcommand::Warm) {
+        return Err("--force only applies to `warm`".into());
+    }
+    if opts.exact && opts.search.is_none() {
+        return Err("--exact needs a search term".into());
+    }
+    if opts.update_baseline && opts.baseline.is_none() {
+        return Err("--update-baseline needs --baseline <file>".into());
+    }
+    if opts.short_hash.is_some()
+        && (!matches!(opts.format, Format::Plain | Format::Paths) || opts.template.is_some() || opts.group_by.is_some())
+    {
+        return Err("--short-hash only applies to plain and paths output".into());
+    }
+    if opts.all_profiles && opts.profile.is_some() {
+        return Err("--all-profiles and --profile can't be combined".into());
+    }
+    if opts.profile_diff.is_some() && (opts.generation.is_some() || opts.all_profiles) {
+        return Err("profile-diff picks its own generations; drop --generation / --all-profiles".into());
+    }
+    if opts.generation.is_some() && opts.profile.is_none() {
+        return Err("--generation needs --profile".into());
+    }
+    if opts.filter_set != FilterSet::default() && opts.ignore_file.is_some() {
+        return Err("--filter-set doesn't apply to --ignore-file".into());
+    }
+    if opts.copy && opts.output.is_some() {
+        return Err("--copy only applies to stdout, not --output".into());
+    }
+    if opts.write_empty && opts.output.is_none() {
+        return Err("--write-empty only applies to --output".into());
+    }
+    if opts.template.is_some() && opts.format != Format::Plain {
+        return Err("--format-template only applies to plain output".into());
+    }
+    if opts.group_by.is_some() && (opts.format != Format::Plain || opts.template.is_some() || opts.sort_by.is_some()) {
+        return Err("--group-by only applies to plain, unsorted output without --format-template".into());
+    }
+    if opts.format == Format::RawPaths && opts.sort_by.is_some() {
+        return Err("--sort-by doesn't apply to --raw-paths".into());
+    }
+    if opts.no_newline && opts.format != Format::Plain {
+        return Err("--no-newline only applies to plain output".into());
+    }
+    if opts.json_pretty && !matches!(opts.format, Format::Json | Format::Ndjson) {
+        return Err("--json-pretty only applies to --format json or ndjson".into());
+    }
+    if opts.with_counts
+        && (!matches!(opts.format, Format::Plain | Format::Json | Format::Ndjson | Format::Toml) || opts.group_by.is_some())
+    {
+        return Err("--with-counts only applies to --format plain, json, ndjson or toml without --group-by".into());
+    }
+    if opts.output_on_empty.is_some() && matches!(opts.format, Format::Json | Format::Ndjson | Format::Toml) {
+        return Err("--output-on-empty doesn't apply to --format json, ndjson or toml".into());
+    }
+    if opts.collapse_prefix.is_some()
+        && (opts.format != Format::Plain
+            || opts.template.is_some()
+            || opts.short_hash.is_some()
+            || opts.group_by.is_some()
+            || opts.with_counts)
+    {
+        return Err("--collapse-prefix only applies to the plain name list".into());
+    }
+    if opts.collapse_keep_base && opts.collapse_prefix.is_none() {
+        return Err("--collapse-keep-base needs --collapse-prefix".into());
+    }
+    if opts.with_counts && opts.no_dedup {
+        return Err("--with-counts and --no-dedup can't be combined".into());
+    }
+    Ok(opts)
+}
+
+// "\0" / "nul" => NUL, otherwise exactly one character.
+fn parse_delimiter(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    match (s, chars.next(), chars.next()) {
+        ("\\0" | "nul" | "NUL", _, _) => Ok('\0'),
+        (_, Some(c), None) => Ok(c),
+        _ => Err(format!("invalid path delimiter `{s}` (expected one character or \\0)")),
+    }
+}
+
+fn main() -> ExitCode {
+    // Checked before parsing so even usage errors and env warnings stay quiet.
+    QUIET.store(env::args().skip(1).any(|a| a == "-q" || a == "--quiet"), Ordering::Relaxed);
+    let format = resolve_format(env::var("NIX_PATH_PKGS_FORMAT").ok());
+    let mut opts = match parse_args(env::args().skip(1), format) {
+        Ok(o) => o,
+        Err(e) => {
+            note!("nix-path-pkgs: {e}");
+            return ExitCode::from(2);
+        }
+    };
+    let _ = CONFIG.set(Config::resolve(&opts));
+    opts.refresh = config().refresh.0;
+
+    // What each setting resolved to and which layer set it; no nix involved.
+    if opts.dump_config {
+        print!("{}", config().dump(&opts));
+        return ExitCode::from(0);
+    }
+
+    // The JSON output contract; no nix involved.
+    if opts.json_schema {
+        print!("{}", format::json_schema());
+        return ExitCode::from(0);
+    }
+
+    // The nix commands a cache miss would run, for pasting into a shell.
+    if opts.dry_run_nix {
+        println!("{}", nix_command_line(&[&KEY_EVAL[..], &[&nix_expr(KEY_EXPR, &opts)]].concat()));
+        if opts.filter_set != FilterSet::None {
+            let expr = nix_expr(opts.filter_set.expr(), &opts);
+            println!("{}", nix_command_line(&[&REQUISITES_EVAL[..], &[&expr]].concat()));
+        }
+        return ExitCode::from(0);
+    }
+
+    // Diff two recorded PATHs; no nix involved.
+    if let Some((old, new)) = &opts.diff {
+        return run_diff(old, new, opts.path_delimiter);
+    }
+    // Outside a dev shell --delta-from-login just records the login PATH.
+    if opts.delta_from_login && !in_dev_shell() {
+        return record_login_path(opts.path_delimiter);
+    }
+
+    // Read the allowlist before any nix work so a typo fails fast.
+    let only = match &opts.only_from {
+        Some(file) => match load_names(file) {
+            Ok(names) => Some(names),
+            Err(e) => {
+                note!("nix-path-pkgs: cannot read {}: {e}", file.display());
+                return ExitCode::from(2);
+            }
+        },
+        None => None,
+    };
+
+    // --profile auto: the first profile this platform usually has.
+    if opts.profile.as_deref() == Some(Path::new("auto")) {
+        let candidates = profile_candidates();
+        let found = candidates.iter().find(|p| p.join("bin").exists() || p.join("sw/bin").exists());
+        if opts.verbose {
+            let listed: Vec<String> = candidates.iter().map(|p| p.display().to_string()).collect();
+            note!("nix-path-pkgs: profile search order: {}", listed.join(", "));
+        }
+        let Some(found) = found else {
+            note!("nix-path-pkgs: no nix profile found; pass --profile <dir>");
+            return ExitCode::from(2);
+        };
+        if opts.verbose {
+            note!("nix-path-pkgs: using profile {}", found.display());
+        }
+        opts.profile = Some(found.clone());
+    }
+
+    // profile-diff: two generations of --profile (the system profile by
+    // default); no nix involved.
+    if let Some((old, new)) = opts.profile_diff {
+        let profile = opts.profile.clone().unwrap_or_else(|| SYSTEM_PROFILE.into());
+        return run_profile_diff(&profile, old, new, opts.path_delimiter);
+    }
+
+    // --profile lists a profile's packages instead of PATH's.
+    let profile = match (&opts.profile, opts.generation) {
+        (Some(profile), Some(n)) => match generation_dir(profile, n) {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                note!("nix-path-pkgs: {e}");
+                return ExitCode::from(2);
+            }
+        },
+        (profile, _) => profile.clone(),
+    };
+    let profile_path = match &profile {
+        Some(profile) => match profile_entries(profile, opts.path_delimiter) {
+            Ok(entries) => Some(entries),
+            Err(e) => {
+                let hint = match e.kind() {
+                    io::ErrorKind::NotFound if profile == Path::new(SYSTEM_PROFILE) => " (not NixOS or nix-darwin?)",
+                    _ => "",
+                };
+                note!("nix-path-pkgs: cannot read profile {}: {e}{hint}", profile.display());
+                return ExitCode::from(2);
+            }
+        },
+        None if opts.all_profiles => match all_profile_entries(opts.path_delimiter) {
+            Some(entries) => Some(entries),
+            None => {
+                note!("nix-path-pkgs: NIX_PROFILES is unset or empty; nothing for --all-profiles to read");
+                return ExitCode::from(2);
+            }
+        },
+        None => None,
+    };
+
+    // --ignore-file: a pre-generated ignore set, so nix is never run.
+    let preset = match &opts.ignore_file {
+        Some(file) => match fs::read(file) {
+            Ok(bytes) => match bytes.strip_prefix(CACHE_MAGIC).unwrap_or(&bytes) {
+                json if is_string_array(json) => Some(parse_hashes(json)),
+                _ => {
+                    note!("nix-path-pkgs: {}: not a JSON array of store paths", file.display());
+                    return ExitCode::from(2);
+                }
+            },
+            Err(e) => {
+                note!("nix-path-pkgs: cannot read {}: {e}", file.display());
+                return ExitCode::from(2);
+            }
+        },
+        // --filter-set none: nothing to hide, so nothing to evaluate.
+        None if opts.filter_set == FilterSet::None => Some(IgnoreSet::default()),
+        None => None,
+    };
+
+    // cache TTL (secs). TTL=0 => no cache (no read, no write).
+    let mut ttl = config().ttl.0;
+    if ttl > 0 && cache_dir().is_none() && opts.cache_file.is_none() {
+        // Never fall back to a CWD-relative cache; it litters wherever the prompt runs.
+        note!("nix-path-pkgs: neither XDG_CACHE_HOME nor HOME is set; caching disabled");
+        ttl = 0;
+    }
+    // stale cache retention (secs). RETENTION=0 => no automatic cleanup.
+    let retention = config().retention.0;
+
+    if opts.explain_cache {
+        return run_explain_cache(&opts, ttl);
+    }
+
+    if let Some(sock) = &opts.serve {
+        return run_serve(sock, &opts, only.as_ref(), preset, ttl, retention);
+    }
+
+    if opts.subcommand == Some(Subcommand::Warm) {
+        return run_warm(&opts, ttl, retention);
+    }
+
+    let (mut ignore, cache, cache_key) = match preset {
+        Some(ignore) if opts.ignore_file.is_some() => (ignore, CacheUse::File, None),
+        Some(ignore) => (ignore, CacheUse::Off, None),
+        // --parallel-nix: on a cold cache both evals are needed anyway, so
+        // don't wait for the key before starting the requisites eval.
+        None if opts.parallel_nix
+            && ttl > 0
+            && pinned_cache_file().is_none()
+            && (opts.refresh || !any_fresh_cache(ttl)) =>
+        {
+            match load_ignore_parallel(&opts, ttl, retention) {
+                Ok((ignore, cache_key)) => (ignore, CacheUse::Miss, cache_key),
+                Err(e) => return report(&e),
+            }
+        }
+        None => {
+            // Get cache metadata once (avoid redundant nix calls); --show-rev
+            // wants the revision even with caching off. A --cache-file is
+            // read and written without a key, so it needs no key eval.
+            let cache_key = if (ttl > 0 && pinned_cache_file().is_none()) || opts.show_rev {
+                match get_cache_key(&opts) {
+                    Ok(key) => key,
+                    Err(e) => return report(&e),
+                }
+            } else {
+                None
+            };
+            if opts.verbose && ttl > 0 && pinned_cache_file().is_none() && cache_key.is_none() {
+                note!("nix-path-pkgs: couldn't determine the cache key (flake revision/system); caching is off for this run");
+            }
+            match load_ignore(&opts, ttl, retention, cache_key.as_deref()) {
+                Ok((ignore, cache)) => (ignore, cache, cache_key),
+                Err(e) => return report(&e),
+            }
+        }
+    };
+
+    if opts.ignore_self {
+        ignore.merge(self_closure(&opts));
+    }
+
+    // --show-rev also tags each JSON object with the revision, when known.
+    // The cache key is "<rev>-<system>".
+    if opts.show_rev {
+        opts.nixpkgs_rev = cache_key.as_deref().and_then(|k| k.split_once('-')).map(|(rev, _)| rev.to_string());
+    }
+    let skip = skip_set(&opts, config().default_ignore.0.as_deref());
+    let filter = make_filter(&ignore, &skip, only.as_ref(), &opts);
+
+    let mut path = profile_path.unwrap_or_else(|| env_path(opts.path_delimiter));
+    if opts.include_result {
+        let results = result_entries(opts.path_delimiter);
+        if !results.is_empty() {
+            if !path.is_empty() {
+                path.push(opts.path_delimiter);
+            }
+            path.push_str(&results);
+        }
+    }
+    if let Some(file) = &opts.baseline {
+        return run_baseline(file, &filter, &path, opts.update_baseline);
+    }
+    if let Some(file) = &opts.snapshot {
+        return run_snapshot(file, &filter, &path);
+    }
+    if let Some(name) = &opts.explain_package {
+        return run_explain_package(&filter, &path, name);
+    }
+    if opts.tree {
+        return run_tree(&filter, &path, &ignore);
+    }
+    if opts.since_last {
+        return run_since_last(&filter, &path);
+    }
+    if opts.delta_from_login {
+        return run_delta_from_login(&filter, &path);
+    }
+    if opts.metrics {
+        // Counters instead of the list; the walk is the same.
+        let stats = filter.for_each(&path, |_| {});
+        let hit = matches!(cache, CacheUse::Hit);
+        return match format::write_metrics(&mut io::stdout().lock(), &stats, hit) {
+            Ok(()) => ExitCode::from(0),
+            Err(_) => ExitCode::from(1),
+        };
+    }
+    let stats = match &opts.output {
+        Some(file) => write_output_file(file, &filter, &path, &opts).inspect_err(|e| {
+            note!("nix-path-pkgs: cannot write {}: {e}", file.display());
+        }),
+        None if opts.copy => write_and_copy(&filter, &path, &opts),
+        None => write_packages(&mut BufWriter::new(io::stdout().lock()), &filter, &path, &opts),
+    };
+    // --show-rev: the revision the ignore set came from, on stderr in every
+    // format (JSON output carries it as `nixpkgs_rev` too).
+    if opts.show_rev {
+        let rev = match (opts.nixpkgs_rev.as_deref(), cache) {
+            (Some(rev), _) => rev,
+            (None, CacheUse::File) => "unknown (--ignore-file)",
+            (None, _) if opts.filter_set == FilterSet::None => "none (--filter-set none)",
+            (None, _) => "unknown (flake has no revision?)",
+        };
+        note!("# nixpkgs {rev}");
+    }
+    if opts.verbose
+        && let Ok(s) = &stats
+    {
+        note!(
+            "scanned={} kept={} skipped={} ignored={} duplicates={} cache={cache}",
+            s.scanned, s.kept, s.skipped, s.ignored, s.duplicates
+        );
+        if CACHE_FALLBACK.load(Ordering::Relaxed) {
+            let dir = fallback_cache_dir().unwrap_or_default();
+            note!("nix-path-pkgs: cache dir not writable; cached under {} instead", dir.display());
+        }
+    }
+    // --fail-on-shadow: the same name in several versions means PATH order
+    // is silently picking one.
+    let shadowed = if opts.fail_on_shadow { filter.shadowed(&path) } else { Vec::new() };
+    for (name, versions) in &shadowed {
+        note!("nix-path-pkgs: {name} shadowed: {} (first wins)", versions.join(", "));
+    }
+    // Empty: 1, unless --output-on-empty printed a placeholder (0) or
+    // --empty-exit-code says otherwise.
+    let empty = opts.empty_exit_code.unwrap_or(if opts.output_on_empty.is_some() { 0 } else { 1 });
+    match stats {
+        _ if !shadowed.is_empty() => ExitCode::from(3),
+        Ok(s) if s.kept > 0 => ExitCode::from(0),
+        Ok(_) => ExitCode::from(empty),
+        Err(_) => ExitCode::from(1),
+    }
+}
+
+// 2: fix the invocation or flake; 4: nix itself failed; 5: cache unusable;
+// 127: no `nix` on PATH at all, as a shell reports a missing command.
+fn exit_code(e: &Error) -> u8 {
+    match e {
+        Error::NixNotFound(e) if e.kind() == io::ErrorKind::NotFound => 127,
+        Error::BadExpr { .. } | Error::NotStorePaths { .. } | Error::OutputTooLarge(_) => 2,
+        Error::NixNotFound(_) | Error::NixEvalFailed(_) | Error::Timeout(_) => 4,
+        Error::CacheIo { .. } => 5,
+    }
+}
+
+fn report(e: &Error) -> ExitCode {
+    note!("nix-path-pkgs: {e}");
+    ExitCode::from(exit_code(e))
+}
+
+// Where the ignore set came from, for the verbose summary.
+#[derive(Clone, Copy)]
+enum CacheUse {
+    Off,
+    Hit,
+    Miss,
+    File, // --ignore-file
+}
+
+impl fmt::Display for CacheUse {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            CacheUse::Off => "off",
+            CacheUse::Hit => "hit",
+            CacheUse::Miss => "miss",
+            CacheUse::File => "file",
+        })
+    }
+}
+
+// Requisite hashes from nix eval output (cached unless TTL=0). --refresh
+// skips the read but still writes the fresh result under the current key.
+fn load_ignore(opts: &Opts, ttl: u64, retention: u64, cache_key: Option<&str>) -> Result<(IgnoreSet, CacheUse), Error> {
+    if ttl == 0 {
+        return Ok((refresh(opts, false)?.1, CacheUse::Off));
+    }
+    // An unreadable cache is just a miss.
+    let cached = if opts.refresh { None } else { read_cache(ttl, cache_key).ok().flatten() };
+    match cached {
+        Some(bytes) => Ok((parse_hashes(&bytes), CacheUse::Hit)),
+        None => {
+            let (bytes, ignore) = refresh(opts, true)?;
+            let _ = write_cache(&bytes, cache_key, ttl, retention); // best-effort
+            Ok((ignore, CacheUse::Miss))
+        }
+    }
+}
+
+// `load_ignore` for a known miss, with the cache-key eval and the requisites
+// eval running side by side; the result is cached under the key as usual.
+fn load_ignore_parallel(opts: &Opts, ttl: u64, retention: u64) -> Result<(IgnoreSet, Option<String>), Error> {
+    let (cache_key, fetched) = thread::scope(|s| {
+        let eval = s.spawn(|| refresh(opts, true));
+        let cache_key = get_cache_key(opts);
+        (cache_key, eval.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+    });
+    let cache_key = cache_key?;
+    if opts.verbose && cache_key.is_none() {
+        note!("nix-path-pkgs: couldn't determine the cache key (flake revision/system); caching is off for this run");
+    }
+    let (bytes, ignore) = fetched?;
+    let _ = write_cache(&bytes, cache_key.as_deref(), ttl, retention); // best-effort
+    Ok((ignore, cache_key))
+}
+
+// Built-in skip list plus the second tier of common noise: replaceable via
+// NIX_PATH_PKGS_DEFAULT_IGNORE (passed in as `noise`), off with --no-default-ignore.
+fn skip_set<'a>(opts: &Opts, noise: Option<&'a str>) -> HashSet<&'a str> {
+    let mut skip: HashSet<&str> = SKIP.iter().copied().collect();
+    if !opts.no_default_ignore {
+        match noise {
+            Some(list) => skip.extend(list.split(',').map(str::trim).filter(|s| !s.is_empty())),
+            None => skip.extend(DEFAULT_IGNORE),
+        }
+    }
+    skip
+}
+
+// `--profile current-system`: NixOS and nix-darwin both link the system
+// environment here.
+const SYSTEM_PROFILE: &str = "/run/current-system/sw";
+
+// Profiles `--profile auto` tries, in order. NixOS users mostly install
+// system-wide, so the system environment comes first there; on macOS a
+// standalone install (per-user profile) is more common than nix-darwin.
+fn profile_candidates() -> Vec<PathBuf> {
+    let home = env::var("HOME").ok().filter(|h| !h.is_empty());
+    let user = |rel: &str| home.as_ref().map(|h| Path::new(h).join(rel));
+    let per_user = [user(".nix-profile"), user(".local/state/nix/profile")];
+    let system = [Some(PathBuf::from(SYSTEM_PROFILE))];
+    let default = [Some(PathBuf::from("/nix/var/nix/profiles/default"))];
+    let order = if cfg!(target_os = "macos") {
+        [per_user.as_slice(), &system, &default]
+    } else {
+        [system.as_slice(), &per_user, &default]
+    };
+    order.concat().into_iter().flatten().collect()
+}
+
+// Where NixOS keeps the system profile's generation links.
+const SYSTEM_PROFILES: &str = "/nix/var/nix/profiles/system";
+
+// `--generation N`: the profile's Nth generation. Profiles are a chain of
+// links ending in one named "<name>-<generation>-link" next to the "<name>"
+// link pointing at it, so follow the chain to that point and pick the
+// sibling for N.
+fn generation_dir(profile: &Path, generation: u32) -> Result<PathBuf, String> {
+    let number = |file: &str, name: &str| -> Option<u32> {
+        file.strip_prefix(name)?.strip_prefix('-')?.strip_suffix("-link")?.parse().ok()
+    };
+    // /run/current-system is a plain store path; its generations live elsewhere
+    let mut link = if profile == Path::new(SYSTEM_PROFILE) {
+        PathBuf::from(SYSTEM_PROFILES)
+    } else {
+        profile.to_path_buf()
+    };
+    for _ in 0..40 {
+        let Ok(target) = fs::read_link(&link) else {
+            break;
+        };
+        let target = link.parent().unwrap_or(Path::new("/")).join(target);
+        let name = link.file_name().and_then(|n| n.to_str()).unwrap_or_default();
+        if target.file_name().and_then(|f| f.to_str()).and_then(|f| number(f, name)).is_none() {
+            link = target;
+            continue;
+        }
+        let dir = target.parent().unwrap_or(Path::new("/"));
+        let wanted = dir.join(format!("{name}-{generation}-link"));
+        if wanted.exists() {
+            return Ok(wanted);
+        }
+        let mut have: Vec<u32> = fs::read_dir(dir)
+            .map(|entries| entries.flatten().filter_map(|e| number(e.file_name().to_str()?, name)).collect())
+            .unwrap_or_default();
+        have.sort_unstable();
+        let have: Vec<String> = have.iter().map(u32::to_string).collect();
+        return Err(format!("{} has no generation {generation} (have: {})", profile.display(), have.join(", ")));
+    }
+    Err(format!("{} isn't a profile with generations", profile.display()))
+}
+
+// A profile's bin/ links into each package's store path; turn those into a
+// PATH-like string (one `<store path>/bin` per package) for the normal walk.
+// A NixOS system profile (or generation) keeps them under sw/bin instead.
+fn profile_entries(profile: &Path, delimiter: char) -> io::Result<String> {
+    let bin = match profile.join("sw/bin") {
+        sw if !profile.join("bin").exists() && sw.exists() => sw,
+        _ => profile.join("bin"),
+    };
+    let mut targets = Vec::new();
+    if let Ok(whole) = fs::read_link(&bin) {
+        targets.push(whole); // single-package profile: bin/ itself is the link
+    } else {
+        let mut links: Vec<_> = fs::read_dir(&bin)?.collect::<io::Result<_>>()?;
+        links.sort_by_key(|e| e.file_name());
+        targets.extend(links.iter().filter_map(|e| fs::read_link(e.path()).ok()));
+    }
+
+    let mut seen = HashSet::new();
+    let mut entries = Vec::new();
+    for target in &targets {
+        if let Some(pkg) = target.to_str().and_then(Package::from_dir)
+            && seen.insert(pkg.path)
+        {
+            entries.push(format!("{}/bin", pkg.path));
+        }
+    }
+    Ok(entries.join(&delimiter.to_string()))
+}
+
+// --include-result: the bin dirs behind `nix-build`'s `result*` symlinks in
+// the current directory (result, result-dev, result-2, ...), in name order.
+// Links that don't point into the store are skipped.
+fn result_entries(delimiter: char) -> String {
+    let Ok(dir) = fs::read_dir(".") else {
+        return String::new();
+    };
+    let mut links: Vec<_> = dir
+        .flatten()
+        .filter(|e| e.file_name().to_str().is_some_and(|n| n.starts_with("result")))
+        .collect();
+    links.sort_by_key(|e| e.file_name());
+    let entries: Vec<String> = links
+        .iter()
+        .filter_map(|e| fs::read_link(e.path()).ok())
+        .filter_map(|target| Some(format!("{}/bin", Package::from_dir(target.to_str()?)?.path)))
+        .collect();
+    entries.join(&delimiter.to_string())
+}
+
+// --all-profiles: `profile_entries` of every profile in NIX_PROFILES, one
+// PATH-like string. NIX_PROFILES lists the lowest priority first, so walk
+// it backwards to get PATH's order. Listed profiles that don't exist (common)
+// are skipped. None if NIX_PROFILES is unset or empty.
+fn all_profile_entries(delimiter: char) -> Option<String> {
+    let raw = env::var("NIX_PROFILES").ok()?;
+    let profiles: Vec<&str> = raw.split_whitespace().collect();
+    if profiles.is_empty() {
+        return None;
+    }
+    let entries: Vec<String> = profiles
+        .iter()
+        .rev()
+        .filter_map(|p| profile_entries(Path::new(p), delimiter).ok())
+        .filter(|e| !e.is_empty())
+        .collect();
+    Some(entries.join(&delimiter.to_string()))
+}
+
+// PATH with any non-UTF-8 entries dropped (they can't be store paths we'd
+// list); env::var would reject the whole variable and lose the valid ones.
+fn env_path(delimiter: char) -> String {
+    let Some(raw) = env::var_os("PATH") else {
+        return String::new();
+    };
+    let raw = match raw.into_string() {
+        Ok(path) => return path,
+        Err(raw) => raw,
+    };
+    let mut buf = [0; 4];
+    let &[delim] = delimiter.encode_utf8(&mut buf).as_bytes() else {
+        return raw.to_string_lossy().into_owned(); // multi-byte delimiter: best effort
+    };
+    let entries: Vec<&str> = raw
+        .as_bytes()
+        .split(|&b| b == delim)
+        .filter_map(|entry| std::str::from_utf8(entry).ok())
+        .collect();
+    entries.join(&delimiter.to_string())
+}
+
+// One package name per line; blank lines and `#` comments are ignored.
+fn load_names(file: &Path) -> io::Result<HashSet<String>> {
+    Ok(fs::read_to_string(file)?
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(String::from)
+        .collect())
+}
+
+fn make_filter<'a>(
+    ignore: &'a IgnoreSet,
+    skip: &'a HashSet<&'a str>,
+    only: Option<&'a HashSet<String>>,
+    opts: &'a Opts,
+) -> Filter<'a> {
+    let mut filter = Filter::new(ignore, skip);
+    filter.only = only;
+    filter.delimiter = opts.path_delimiter;
+    filter.min_versions = &opts.min_versions;
+    filter.ignore_prefixes = &opts.ignore_hash;
+    filter.skip_patterns = &opts.skip_regex;
+    filter.include_globs = &opts.include;
+    filter.exclude_globs = &opts.exclude;
+    filter.first = opts.first;
+    filter.requisites_only = opts.include_requisites;
+    filter.dedup_wrappers = opts.dedup_wrappers;
+    filter.case_insensitive = opts.case_insensitive;
+    filter.search = opts.search.as_deref();
+    filter.search_exact = opts.exact;
+    filter.store_root = opts.store.as_deref().unwrap_or("");
+    filter.skip_versioned = &opts.skip_versioned;
+    filter.keep_duplicates = opts.no_dedup;
+    filter
+}
+
+// Write the kept packages in `format`, or the --output-on-empty placeholder
+// if there are none; returns how many there were.
+fn write_packages(out: &mut impl Write, filter: &Filter, path: &str, opts: &Opts) -> io::Result<Stats> {
+    let stats = write_list(out, filter, path, opts)?;
+    if stats.kept == 0
+        && let Some(text) = &opts.output_on_empty
+    {
+        let end = if opts.no_newline { "" } else { "\n" };
+        write!(out, "{text}{end}")?;
+        out.flush()?;
+    }
+    Ok(stats)
+}
+
+// The list itself; writes nothing (or `[]`) when it's empty.
+fn write_list(out: &mut impl Write, filter: &Filter, path: &str, opts: &Opts) -> io::Result<Stats> {
+    // --strip-version-always: bare names in every format.
+    let strip = opts.strip_version;
+    // --with-counts: PATH entries per name before dedup, from a separate
+    // full walk so the list itself can still stream.
+    let counts = opts.with_counts.then(|| filter.occurrences(path));
+    let count = |p: &Package| counts.as_ref().map(|c| c.get(&filter.dedup_key(p.name)).copied().unwrap_or(1));
+
+    // --raw-paths: PATH entries as they are, no name/version display.
+    if opts.format == Format::RawPaths {
+        let mut res = Ok(());
+        let stats = filter.for_each_dir(path, |dir| {
+            if res.is_ok() {
+                res = writeln!(out, "{dir}");
+            }
+        });
+        return res.and_then(|_| out.flush()).map(|_| stats);
+    }
+
+    // Unsorted NDJSON streams as the walk proceeds.
+    if opts.format == Format::Ndjson && opts.sort_by.is_none() {
+        // A failed write stops the walk instead of scanning the rest of PATH.
+        let mut walk = filter.iter(path);
+        for p in walk.by_ref() {
+            format::write_ndjson_line(out, &without_version(p, strip), count(&p), opts.nixpkgs_rev.as_deref(), opts.json_pretty)?;
+        }
+        return Ok(walk.stats());
+    }
+
+    // Unsorted plain output too: each name goes out as it's found instead
+    // of joining the whole list first.
+    if opts.format == Format::Plain && opts.group_by.is_none() && opts.sort_by.is_none() && opts.collapse_prefix.is_none() {
+        let mut walk = filter.iter(path);
+        write_plain(out, walk.by_ref().map(|p| without_version(p, strip)), count, opts)?;
+        return Ok(walk.stats());
+    }
+
+    // --first applies after sorting: walk everything, sort, then cut.
+    let walk = match opts.sort_by {
+        Some(_) => Filter { first: None, ..*filter },
+        None => *filter,
+    };
+    let mut pkgs = Vec::with_capacity(entries_hint(path, walk.delimiter, walk.first));
+    let mut stats = walk.for_each(path, |p| pkgs.push(without_version(p, strip)));
+    match opts.sort_by {
+        Some(SortBy::Size) => {
+            // Largest closure first; unknown sizes keep PATH order at the end.
+            let sizes = closure_sizes(&pkgs);
+            pkgs.sort_by_key(|p| Reverse(sizes.get(p.hash).copied()));
+        }
+        Some(SortBy::Name) => sort_by_name(&mut pkgs),
+        None => {}
+    }
+    if let Some(n) = opts.first {
+        pkgs.truncate(n);
+        stats.kept = pkgs.len();
+    }
+
+    // --no-newline: the one-line plain list without its line break, for
+    // embedding in a prompt string as is.
+    let end = if opts.no_newline { "" } else { "\n" };
+    // --short-hash: "git-1a2b3c4d" to tell same-named packages apart.
+    let name = |p: &Package| match opts.short_hash {
+        Some(len) => format::name_with_hash(p, len),
+        None => p.name.to_string(),
+    };
+    match opts.format {
+        Format::Plain if opts.group_by == Some(GroupBy::Output) => {
+            let groups: Vec<String> = filter.outputs(path).iter().map(|(name, outs)| format::output_group(name, outs)).collect();
+            if !groups.is_empty() {
+                write!(out, "{}{end}", groups.join(", "))?;
+            }
+        }
+        Format::Plain if let Some(sep) = &opts.collapse_prefix => {
+            let names: Vec<&str> = pkgs.iter().map(|p| p.name).collect();
+            let entries = format::collapse_prefixes(&names, sep, opts.collapse_keep_base);
+            if !entries.is_empty() {
+                write!(out, "{}{end}", entries.join(", "))?;
+            }
+        }
+        Format::Plain => write_plain(out, pkgs.iter().copied(), count, opts)?,
+        Format::Ndjson => {
+            for p in &pkgs {
+                format::write_ndjson_line(out, p, count(p), opts.nixpkgs_rev.as_deref(), opts.json_pretty)?;
+            }
+        }
+        Format::Json => format::write_json_array(out, &pkgs, count, opts.nixpkgs_rev.as_deref(), opts.json_pretty)?,
+        Format::Paths => {
+            for p in &pkgs {
+                writeln!(out, "{}\t{}", name(p), p.path)?;
+            }
+        }
+        Format::Env => format::write_env(out, &pkgs)?,
+        Format::Toml => format::write_toml(out, &pkgs, count)?,
+        Format::Names => {
+            for p in &pkgs {
+                writeln!(out, "{}", p.name)?;
+            }
+        }
+        Format::RawPaths => unreachable!("written during the walk"),
+        Format::Table if pkgs.is_empty() => {}
+        Format::Table => {
+            let columns = env::var("COLUMNS").ok().and_then(|c| c.parse().ok());
+            format::write_table(out, &pkgs, columns, use_color(opts))?;
+        }
+    }
+    out.flush()?;
+    Ok(stats)
+}
+
+// "git, ripgrep" plus the line break (unless --no-newline), written one
+// package at a time; nothing at all for an empty list. A `count` above one
+// is shown as "git (x2)".
+fn write_plain<'p>(
+    out: &mut impl Write,
+    pkgs: impl Iterator<Item = Package<'p>>,
+    count: impl Fn(&Package) -> Option<usize>,
+    opts: &Opts,
+) -> io::Result<()> {
+    let mut any = false;
+    for p in pkgs {
+        if any {
+            out.write_all(b", ")?;
+        }
+        any = true;
+        match (&opts.template, opts.short_hash) {
+            (Some(t), _) => out.write_all(t.render(&p).as_bytes())?,
+            (None, Some(len)) => out.write_all(format::name_with_hash(&p, len).as_bytes())?,
+            (None, None) => out.write_all(p.name.as_bytes())?,
+        }
+        if let Some(n) = count(&p).filter(|&n| n > 1) {
+            write!(out, " (x{n})")?;
+        }
+    }
+    if any && !opts.no_newline {
+        out.write_all(b"\n")?;
+    }
+    out.flush()
+}
+
+// --color, then the color env vars; a file or socket never counts as a terminal.
+fn use_color(opts: &Opts) -> bool {
+    let flag = match opts.color {
+        Color::Always => Some(true),
+        Color::Never => Some(false),
+        Color::Auto => None,
+    };
+    let tty = opts.output.is_none() && opts.serve.is_none() && io::stdout().is_terminal();
+    format::should_color(flag, |name| env::var(name).ok(), tty)
+}
+
+fn without_version(mut p: Package, strip: bool) -> Package {
+    if strip {
+        p.version = "";
+    }
+    p
+}
+
+// Same content as stdout, written to `file` in one go. An empty result leaves
+// the file alone unless --write-empty (so a snapshot isn't clobbered by a
+// run from an unusual shell).
+fn write_output_file(file: &Path, filter: &Filter, path: &str, opts: &Opts) -> io::Result<Stats> {
+    let mut buf = Vec::new();
+    let stats = write_packages(&mut buf, filter, path, opts)?;
+    if stats.kept > 0 || opts.write_empty || opts.output_on_empty.is_some() {
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(file, buf)?;
+    }
+    Ok(stats)
+}
+
+// --copy: the stdout output, also put on the clipboard. Without a usable
+// clipboard tool it's just a warning; stdout is printed either way.
+fn write_and_copy(filter: &Filter, path: &str, opts: &Opts) -> io::Result<Stats> {
+    let mut buf = Vec::new();
+    let stats = write_packages(&mut buf, filter, path, opts)?;
+    io::stdout().write_all(&buf)?;
+    if let Err(e) = copy_to_clipboard(&buf) {
+        note!("nix-path-pkgs: --copy: {e}");
+    }
+    Ok(stats)
+}
+
+// Clipboard tools to try, most specific first: pbcopy on macOS, wl-copy
+// under Wayland, then the X11 ones.
+fn clipboard_commands() -> Vec<&'static [&'static str]> {
+    let mut tools: Vec<&'static [&'static str]> = Vec::new();
+    if cfg!(target_os = "macos") {
+        tools.push(&["pbcopy"]);
+    }
+    if env::var_os("WAYLAND_DISPLAY").is_some() {
+        tools.push(&["wl-copy"]);
+    }
+    tools.push(&["xclip", "-selection", "clipboard"]);
+    tools.push(&["xsel", "--clipboard", "--input"]);
+    tools
+}
+
+// Pipe `text` into the first clipboard tool that's installed.
+fn copy_to_clipboard(text: &[u8]) -> Result<(), String> {
+    for tool in clipboard_commands() {
+        let spawned = Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(format!("cannot run {}: {e}", tool[0])),
+        };
+        let written = child.stdin.take().map_or(Ok(()), |mut stdin| stdin.write_all(text));
+        return match (written, child.wait()) {
+            (Ok(()), Ok(status)) if status.success() => Ok(()),
+            (Err(e), _) | (_, Err(e)) => Err(format!("{} failed: {e}", tool[0])),
+            (_, Ok(status)) => Err(format!("{} failed ({status})", tool[0])),
+        };
+    }
+    Err("no clipboard tool found (pbcopy, wl-copy, xclip or xsel)".into())
+}
+
+// Closure sizes by store hash. A store path's closure never changes, so known
+// sizes are kept in the cache dir and only new packages hit `nix path-info`.
+fn closure_sizes(pkgs: &[Package]) -> HashMap<String, u64> {
+    let file = cache_dir().map(|d| d.join("closure-sizes"));
+    let known = file.as_ref().and_then(|f| fs::read_to_string(f).ok()).unwrap_or_default();
+    let mut sizes: HashMap<String, u64> = known
+        .lines()
+        .filter_map(|l| l.split_once(' '))
+        .filter_map(|(h, n)| Some((h.to_string(), n.parse().ok()?)))
+        .collect();
 
-fn main() -> ExitCode {
-    // cache TTL (secs). TTL=0 => no cache (no read, no write).
-    let ttl: u64 = env::var("NIX_PATH_PKGS_CACHE_TTL")
-        .ok()
-        .and_then(|s| s.parse().ok())
-        .unwrap_or(3600);
-
-    // Get cache metadata once (avoid redundant nix calls)
-    let cache_key = if ttl > 0 {
-        get_cache_key()
-    } else {
-        None
+    let missing: Vec<&str> = pkgs.iter().filter(|p| !sizes.contains_key(p.hash)).map(|p| p.path).collect();
+    if missing.is_empty() {
+        return sizes;
+    }
+    let Ok(o) = nix().args(["path-info", "--closure-size"]).args(&missing).output() else {
+        return sizes;
     };
 
-    // nix eval output (cached unless TTL=0)
-    let bytes = if ttl == 0 {
-        refresh(false, None)
-    } else {
-        read_cache(ttl, cache_key.as_deref())
-            .ok()
-            .flatten()
-            .unwrap_or_else(|| refresh(true, cache_key.as_deref()))
+    let mut new = String::new();
+    for (hash, size) in parse_path_info_sizes(&String::from_utf8_lossy(&o.stdout)) {
+        new.push_str(&format!("{hash} {size}\n"));
+        sizes.insert(hash.to_string(), size);
+    }
+    if let Some(f) = file
+        && !new.is_empty()
+    {
+        // best-effort
+        let _ = fs::create_dir_all(f.parent().unwrap_or(&f))
+            .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&f))
+            .and_then(|mut fh| fh.write_all(new.as_bytes()));
+    }
+    sizes
+}
+
+// Direct references by store hash, as full store paths. Like closure sizes
+// they never change, so known ones are kept in the cache dir ("<hash> <ref>
+// <ref>..." per line) and only new packages hit `nix-store --query`, one
+// call each since its output doesn't say which path a reference belongs to.
+fn store_references(pkgs: &[Package]) -> HashMap<String, Vec<String>> {
+    let file = cache_dir().map(|d| d.join("references"));
+    let known = file.as_ref().and_then(|f| fs::read_to_string(f).ok()).unwrap_or_default();
+    let mut refs: HashMap<String, Vec<String>> = known
+        .lines()
+        .filter_map(|l| {
+            let mut words = l.split(' ');
+            Some((words.next().filter(|h| !h.is_empty())?.to_string(), words.map(String::from).collect()))
+        })
+        .collect();
+
+    let missing: Vec<&Package> = pkgs.iter().filter(|p| !refs.contains_key(p.hash)).collect();
+    let mut new = String::new();
+    for p in missing {
+        let mut cmd = Command::new("nix-store");
+        if let Some(root) = &config().store.0 {
+            cmd.args(["--store", root]);
+        }
+        let Ok(o) = cmd.args(["--query", "--references", p.path]).output() else {
+            break; // no nix-store at all
+        };
+        if !o.status.success() {
+            continue;
+        }
+        let text = String::from_utf8_lossy(&o.stdout);
+        let paths: Vec<String> = text.lines().filter(|l| store_hash(l).is_some()).map(String::from).collect();
+        let line: Vec<&str> = std::iter::once(p.hash).chain(paths.iter().map(String::as_str)).collect();
+        new.push_str(&line.join(" "));
+        new.push('\n');
+        refs.insert(p.hash.to_string(), paths);
+    }
+    if let Some(f) = file
+        && !new.is_empty()
+    {
+        // best-effort
+        let _ = fs::create_dir_all(f.parent().unwrap_or(&f))
+            .and_then(|_| fs::OpenOptions::new().create(true).append(true).open(&f))
+            .and_then(|mut fh| fh.write_all(new.as_bytes()));
+    }
+    refs
+}
+
+// --tree: the kept packages with their direct references underneath, marking
+// the ones the ignore set hides. Exit 1 when nothing is kept.
+fn run_tree(filter: &Filter, path: &str, ignore: &IgnoreSet) -> ExitCode {
+    let pkgs = filter.packages(path);
+    if pkgs.is_empty() {
+        return ExitCode::from(1);
+    }
+    let refs = store_references(&pkgs);
+    match format::write_tree(&mut BufWriter::new(io::stdout().lock()), &pkgs, &refs, ignore) {
+        Ok(()) => ExitCode::from(0),
+        Err(_) => ExitCode::from(1),
+    }
+}
+
+// Removes the socket file when the server exits normally; see
+// `remove_socket_on_signal` for Ctrl-C and `kill`.
+struct SocketGuard<'a>(&'a Path);
+
+impl Drop for SocketGuard<'_> {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0); // best-effort
+    }
+}
+
+// The socket the signal handler unlinks, set once before it's installed.
+static SERVE_SOCKET: OnceLock<std::ffi::CString> = OnceLock::new();
+
+// SIGINT/SIGTERM/SIGHUP would otherwise kill the server without running
+// SocketGuard (and release builds abort on panic, which skips it too), so
+// unlink the socket from the handler and exit as the shell would report it.
+fn remove_socket_on_signal(sock: &Path) {
+    type Handler = extern "C" fn(i32);
+    unsafe extern "C" {
+        fn signal(signum: i32, handler: Handler) -> usize;
+        fn unlink(path: *const std::ffi::c_char) -> i32;
+        fn _exit(status: i32) -> !;
+    }
+    extern "C" fn on_signal(signum: i32) {
+        // Only async-signal-safe calls in here.
+        if let Some(path) = SERVE_SOCKET.get() {
+            // SAFETY: `path` is a NUL-terminated string that lives for the process.
+            unsafe { unlink(path.as_ptr()) };
+        }
+        // SAFETY: _exit never returns and runs no destructors.
+        unsafe { _exit(128 + signum) }
+    }
+    let Ok(path) = std::ffi::CString::new(sock.as_os_str().as_bytes()) else {
+        return;
     };
-    let ignore = parse_hashes(&bytes);
+    let _ = SERVE_SOCKET.set(path);
+    for signum in [1, 2, 15] {
+        // SIGHUP, SIGINT, SIGTERM
+        // SAFETY: `on_signal` only touches an initialized OnceLock and calls
+        // unlink and _exit.
+        unsafe { signal(signum, on_signal) };
+    }
+}
 
-    // Walk $PATH in order; keep first occurrence only.
-    let mut ordered: Vec<&str> = Vec::with_capacity(32);
-    let mut seen: HashSet<&str> = HashSet::with_capacity(32);
+// Answer each connection with the package list. A client may send its own PATH
+// as the first line; otherwise the server's PATH is used. The ignore set is only
+// reloaded when the cache key changes (checked at most once per TTL; never
+// with TTL=0).
+fn run_serve(
+    sock: &Path,
+    opts: &Opts,
+    only: Option<&HashSet<String>>,
+    preset: Option<IgnoreSet>,
+    ttl: u64,
+    retention: u64,
+) -> ExitCode {
+    // A socket nobody answers on is left over from a server that died; one
+    // that answers belongs to a live server, which mustn't be cut off.
+    if UnixStream::connect(sock).is_ok() {
+        note!("nix-path-pkgs: {}: already being served", sock.display());
+        return ExitCode::from(2);
+    }
+    // Only ever unlink a socket: --serve pointed at a regular file is a typo,
+    // not something to delete.
+    match fs::symlink_metadata(sock) {
+        Ok(m) if m.file_type().is_socket() => {
+            let _ = fs::remove_file(sock);
+        }
+        Ok(_) => {
+            note!("nix-path-pkgs: {}: exists and is not a socket", sock.display());
+            return ExitCode::from(2);
+        }
+        Err(_) => {}
+    }
+    let listener = match UnixListener::bind(sock) {
+        Ok(l) => l,
+        Err(e) => {
+            note!("nix-path-pkgs: {}: {e}", sock.display());
+            return ExitCode::from(2);
+        }
+    };
+    let _guard = SocketGuard(sock);
+    remove_socket_on_signal(sock);
 
-    let path = env::var("PATH").unwrap_or_default();
-    for dir in path.split(':').filter(|s| !s.is_empty()) {
-        if let Some((h, name)) = hash_and_name(dir) {
-            if ignore.contains(h) || SKIP.contains(&name) || name.is_empty() {
-                continue;
-            }
-            if seen.insert(name) {
-                ordered.push(name);
+    let own_path = env_path(opts.path_delimiter);
+    let skip = skip_set(opts, config().default_ignore.0.as_deref());
+    // A preset (--ignore-file) never goes stale, and with TTL=0 there's no
+    // cache to go stale: evaluate once at startup rather than per connection.
+    let fixed = preset.is_some() || ttl == 0;
+    let mut cache_key = match ttl {
+        _ if fixed => None,
+        _ => match get_cache_key(opts) {
+            Ok(key) => key,
+            Err(e) => return report(&e),
+        },
+    };
+    let loaded = match preset {
+        Some(ignore) => Ok(ignore),
+        None => load_ignore(opts, ttl, retention, cache_key.as_deref()).map(|(ignore, _)| ignore),
+    };
+    let mut ignore = match loaded {
+        Ok(ignore) => ignore,
+        Err(e) => return report(&e),
+    };
+    let mut checked = Instant::now();
+
+    for stream in listener.incoming() {
+        let Ok(mut stream) = stream else {
+            continue;
+        };
+
+        if !fixed && checked.elapsed() >= Duration::from_secs(ttl) {
+            let key = get_cache_key(opts).unwrap_or_default();
+            if key.is_none() || key != cache_key {
+                // Keep answering with the old set rather than dying mid-session.
+                match load_ignore(opts, ttl, retention, key.as_deref()) {
+                    Ok((fresh, _)) => ignore = fresh,
+                    Err(e) => note!("nix-path-pkgs: {e}; keeping the previous ignore set"),
+                }
+                cache_key = key;
             }
+            checked = Instant::now();
+        }
+
+        let mut line = String::new();
+        let _ = stream.set_read_timeout(Some(Duration::from_millis(100)));
+        let _ = BufReader::new(&stream).read_line(&mut line);
+        let path = match line.trim() {
+            "" => own_path.as_str(),
+            p => p,
+        };
+
+        // Client may already be gone; nothing to do about it.
+        let filter = make_filter(&ignore, &skip, only, opts);
+        if let Ok(Stats { kept: 0, .. }) = write_packages(&mut stream, &filter, path, opts)
+            && opts.output_on_empty.is_none()
+        {
+            let _ = writeln!(stream);
         }
     }
 
-    if !ordered.is_empty() {
-        println!("{}", ordered.join(", "));
-        ExitCode::from(0)
-    } else {
-        ExitCode::from(1)
+    ExitCode::from(0)
+}
+
+// --ttl / NIX_PATH_PKGS_CACHE_TTL: "90", "30m", "2h", "1d". Bad values warn and use 3600.
+fn resolve_ttl(raw: Option<String>) -> u64 {
+    match raw.as_deref().map(str::trim) {
+        None | Some("") => 3600,
+        Some(s) => parse_duration_secs(s).unwrap_or_else(|| {
+            note!("nix-path-pkgs: invalid cache TTL `{s}`; using 3600");
+            3600
+        }),
     }
 }
 
-fn get_cache_key() -> Option<String> {
-    // Get revision-system key in one nix call (no JSON parsing needed)
-    let output = Command::new("nix")
-        .args([
-            "eval",
-            "--impure",
-            "--raw",
-            "--expr",
-            r#""${(builtins.getFlake "nixpkgs").rev}-${builtins.currentSystem}""#,
-        ])
-        .output()
-        .ok()?;
+// NIX_PATH_PKGS_FORMAT: any --format value. Bad values warn and use plain.
+fn resolve_format(raw: Option<String>) -> Format {
+    match raw.as_deref().map(str::trim) {
+        None | Some("") => Format::default(),
+        Some(s) => s.parse().unwrap_or_else(|e| {
+            note!("nix-path-pkgs: NIX_PATH_PKGS_FORMAT: {e}; using plain");
+            Format::default()
+        }),
+    }
+}
 
-    if output.status.success() {
-        String::from_utf8(output.stdout).ok()
-    } else {
-        None
+// `name` as a number, or `default` when it's unset or not one.
+fn env_secs(name: &'static str, default: u64) -> (u64, Source) {
+    match env::var(name).ok().and_then(|s| s.parse().ok()) {
+        Some(n) => (n, Source::Env(name)),
+        None => (default, Source::Default),
+    }
+}
+
+// Which layer a setting's value came from.
+#[derive(Clone, Copy)]
+enum Source {
+    Flag,
+    Env(&'static str),
+    Default,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::Flag => f.write_str("flag"),
+            Source::Env(name) => write!(f, "env {name}"),
+            Source::Default => f.write_str("default"),
+        }
     }
 }
 
-fn refresh(write_cache_after: bool, cache_key: Option<&str>) -> Vec<u8> {
-    let o = Command::new("nix")
-        .args(["eval", "--impure", "--json", "--expr", NIX_EXPR])
-        .output()
-        .expect("failed to exec `nix`");
-    if !o.status.success() {
-        panic!("nix eval failed:\n{}", String::from_utf8_lossy(&o.stderr));
+// Settings a flag or an env var can set, resolved once per run (flag, then
+// env, then the default) so every reader agrees and --dump-config can say
+// where each came from.
+struct Config {
+    format: (Format, Source),
+    ttl: (u64, Source),                       // secs; 0 => no cache
+    retention: (u64, Source),                 // secs; 0 => no cleanup
+    default_ignore: (Option<String>, Source), // None => DEFAULT_IGNORE
+    refresh: (bool, Source),
+    nix_timeout: (u64, Source),               // secs; 0 => no limit
+    nix_max_output_mb: (u64, Source),         // MiB of nix stdout; 0 => no limit
+    nix_attempts: (u64, Source),
+    nix_backoff_ms: (u64, Source),
+    failure_ttl: (u64, Source),               // secs; 0 => no negative cache
+    store: (Option<String>, Source),          // --store root; None => nix's default
+    cache_file: (Option<PathBuf>, Source),    // --cache-file; None => keyed file in cache_dir
+    filter_set: (FilterSet, Source),
+}
+
+impl Config {
+    fn resolve(opts: &Opts) -> Config {
+        let var = |name: &'static str| env::var(name).ok().map(|v| (v, Source::Env(name)));
+        // A flag that repeats the env/default value is reported as that layer.
+        // (parse_args already warned about a bad NIX_PATH_PKGS_FORMAT.)
+        let env_format: Option<Format> = env::var("NIX_PATH_PKGS_FORMAT").ok().and_then(|v| v.trim().parse().ok());
+        let format_source = if opts.format != env_format.unwrap_or_default() {
+            Source::Flag
+        } else if env_format.is_some() {
+            Source::Env("NIX_PATH_PKGS_FORMAT")
+        } else {
+            Source::Default
+        };
+        let ttl = match opts.ttl.clone().map(|t| (t, Source::Flag)).or_else(|| var("NIX_PATH_PKGS_CACHE_TTL")) {
+            Some((raw, source)) => (resolve_ttl(Some(raw)), source),
+            None => (resolve_ttl(None), Source::Default),
+        };
+        let refresh = if opts.refresh {
+            (true, Source::Flag)
+        } else if env::var("NIX_PATH_PKGS_REFRESH").is_ok_and(|v| v == "1") {
+            (true, Source::Env("NIX_PATH_PKGS_REFRESH"))
+        } else {
+            (false, Source::Default)
+        };
+        Config {
+            format: (opts.format, format_source),
+            ttl,
+            retention: env_secs("NIX_PATH_PKGS_CACHE_RETENTION", 86400),
+            default_ignore: match var("NIX_PATH_PKGS_DEFAULT_IGNORE") {
+                Some((list, source)) => (Some(list), source),
+                None => (None, Source::Default),
+            },
+            refresh,
+            nix_timeout: env_secs("NIX_PATH_PKGS_NIX_TIMEOUT", 0),
+            nix_max_output_mb: env_secs("NIX_PATH_PKGS_NIX_MAX_OUTPUT_MB", 16),
+            nix_attempts: env_secs("NIX_PATH_PKGS_NIX_ATTEMPTS", 3),
+            nix_backoff_ms: env_secs("NIX_PATH_PKGS_NIX_BACKOFF_MS", 100),
+            failure_ttl: env_secs("NIX_PATH_PKGS_FAILURE_TTL", 30),
+            store: match &opts.store {
+                Some(root) => (Some(root.clone()), Source::Flag),
+                None => (None, Source::Default),
+            },
+            cache_file: match &opts.cache_file {
+                Some(file) => (Some(file.clone()), Source::Flag),
+                None => (None, Source::Default),
+            },
+            filter_set: match opts.filter_set {
+                FilterSet::AllowedRequisites => (FilterSet::AllowedRequisites, Source::Default),
+                set => (set, Source::Flag),
+            },
+        }
     }
-    if write_cache_after {
-        let _ = write_cache(&o.stdout, cache_key); // best-effort
+
+    // --dump-config: one "key = value  # source" line per setting, in a
+    // fixed order. Lists are comma-separated and sorted.
+    fn dump(&self, opts: &Opts) -> String {
+        let mut skip: Vec<&str> = skip_set(opts, self.default_ignore.0.as_deref()).into_iter().collect();
+        skip.sort_unstable();
+        let default_ignore = match (&self.default_ignore.0, opts.no_default_ignore) {
+            (_, true) => ("none".to_string(), Source::Flag),
+            (Some(list), _) => (list.clone(), self.default_ignore.1),
+            (None, _) => (DEFAULT_IGNORE.join(","), Source::Default),
+        };
+        let flake_source = if opts.flake == Opts::default().flake { Source::Default } else { Source::Flag };
+        let (system, system_source) = match &opts.system {
+            Some(system) => (system.as_str(), Source::Flag),
+            None => ("builtins.currentSystem", Source::Default),
+        };
+        let delimiter_source = if opts.path_delimiter == ':' { Source::Default } else { Source::Flag };
+        let cache = cache_dir().map_or("none".into(), |d| d.display().to_string());
+        let quiet = QUIET.load(Ordering::Relaxed);
+        let lines: [(&str, String, String); 19] = [
+            ("format", self.format.0.to_string(), self.format.1.to_string()),
+            ("ttl", self.ttl.0.to_string(), self.ttl.1.to_string()),
+            ("retention", self.retention.0.to_string(), self.retention.1.to_string()),
+            ("refresh", self.refresh.0.to_string(), self.refresh.1.to_string()),
+            ("default_ignore", default_ignore.0, default_ignore.1.to_string()),
+            ("skip", skip.join(","), "built-in + default_ignore".into()),
+            ("flake", opts.flake.clone(), flake_source.to_string()),
+            ("filter_set", self.filter_set.0.to_string(), self.filter_set.1.to_string()),
+            ("system", system.into(), system_source.to_string()),
+            ("path_delimiter", format!("{:?}", opts.path_delimiter), delimiter_source.to_string()),
+            ("store", self.store.0.clone().unwrap_or_else(|| "default".into()), self.store.1.to_string()),
+            ("cache_dir", cache, "env XDG_CACHE_HOME or HOME".into()),
+            ("cache_file", self.cache_file.0.as_ref().map_or("auto".into(), |f| f.display().to_string()), self.cache_file.1.to_string()),
+            ("nix_timeout", self.nix_timeout.0.to_string(), self.nix_timeout.1.to_string()),
+            ("nix_max_output_mb", self.nix_max_output_mb.0.to_string(), self.nix_max_output_mb.1.to_string()),
+            ("nix_attempts", self.nix_attempts.0.to_string(), self.nix_attempts.1.to_string()),
+            ("nix_backoff_ms", self.nix_backoff_ms.0.to_string(), self.nix_backoff_ms.1.to_string()),
+            ("failure_ttl", self.failure_ttl.0.to_string(), self.failure_ttl.1.to_string()),
+            ("quiet", quiet.to_string(), if quiet { Source::Flag } else { Source::Default }.to_string()),
+        ];
+        lines.iter().map(|(key, value, source)| format!("{key} = {value}  # {source}\n")).collect()
     }
-    o.stdout
 }
 
-fn parse_hashes(json: &[u8]) -> HashSet<String> {
-    let Ok(text) = std::str::from_utf8(json) else {
-        return HashSet::new();
+// This run's Config; resolved from the defaults if main hasn't set it yet.
+fn config() -> &'static Config {
+    CONFIG.get_or_init(|| Config::resolve(&Opts::default()))
+}
+
+const KEY_EXPR: &str = r#""${(builtins.getFlake @flake@).rev}-${@system@}""#;
+
+// `nix` args ahead of the expression for the cache-key and requisites evals.
+const KEY_EVAL: [&str; 4] = ["eval", "--impure", "--raw", "--expr"];
+const REQUISITES_EVAL: [&str; 4] = ["eval", "--impure", "--json", "--expr"];
+
+// Substitute the flake reference into an expression as a nix string literal.
+fn with_flake(expr: &str, flake: &str) -> String {
+    let quoted = flake.replace('\\', "\\\\").replace('"', "\\\"").replace("${", "\\${");
+    expr.replace("@flake@", &format!("\"{quoted}\""))
+}
+
+// `expr` for this run: --system (already validated, so no quoting needed)
+// or the evaluating machine's, then the flake.
+fn nix_expr(expr: &str, opts: &Opts) -> String {
+    let system = match &opts.system {
+        Some(system) => format!("\"{system}\""),
+        None => "builtins.currentSystem".into(),
     };
+    with_flake(&expr.replace("@system@", &system), &opts.flake)
+}
+
+fn get_cache_key(opts: &Opts) -> Result<Option<String>, Error> {
+    // Get revision-system key in one nix call (no JSON parsing needed).
+    // Only a bad flake is fatal; anything else just means no caching.
+    let Ok(output) = run_nix(&[&KEY_EVAL[..], &[&nix_expr(KEY_EXPR, opts)]].concat()) else {
+        return Ok(None);
+    };
+
+    if output.status.success() {
+        return Ok(String::from_utf8(output.stdout).ok());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if is_bad_expr(&stderr) {
+        return Err(Error::BadExpr { flake: opts.flake.clone(), stderr: stderr.into_owned() });
+    }
+    Ok(None)
+}
 
-    // Fast path: extract hashes directly from JSON array
-    // Format: ["/nix/store/<hash>-...", ...]
-    // Pre-allocate with estimated capacity
-    let mut hashes = HashSet::with_capacity(64);
-    let mut i = 0;
-    let bytes = text.as_bytes();
+// `nix`, told to keep its own warnings down under --quiet and pointed at
+// the --store root if there is one.
+fn nix() -> Command {
+    let mut cmd = Command::new("nix");
+    if QUIET.load(Ordering::Relaxed) {
+        cmd.arg("--quiet");
+    }
+    if let Some(root) = &config().store.0 {
+        cmd.args(["--store", root]);
+    }
+    cmd
+}
 
-    while i < bytes.len() {
-        // Look for "/nix/store/" pattern
-        if bytes.get(i..i + 11) == Some(b"/nix/store/") {
-            let hash_start = i + 11;
-            let hash_end = hash_start + 32;
+// --dry-run-nix: `nix()` with `args` as one shell-quoted line.
+fn nix_command_line(args: &[&str]) -> String {
+    let mut cmd = nix();
+    cmd.args(args);
+    let argv = std::iter::once(cmd.get_program()).chain(cmd.get_args());
+    argv.map(|a| format::shell_quote(&a.to_string_lossy())).collect::<Vec<_>>().join(" ")
+}
 
-            // Validate hash position and dash separator
-            if hash_end < bytes.len()
-                && bytes.get(hash_end) == Some(&b'-')
-                && text.is_char_boundary(hash_start)
-                && text.is_char_boundary(hash_end)
-            {
-                hashes.insert(text[hash_start..hash_end].to_string());
-                i = hash_end;
-            } else {
-                i += 1;
+// `nix <args>`, killed after NIX_PATH_PKGS_NIX_TIMEOUT seconds (0 => no limit)
+// and cut off past NIX_PATH_PKGS_NIX_MAX_OUTPUT_MB of stdout (0 => no limit),
+// so a runaway expression can't take the prompt's memory with it.
+fn run_nix(args: &[&str]) -> Result<Output, Error> {
+    run_nix_scanning(args, false).map(|(output, _)| output)
+}
+
+// `run_nix`, plus (with `scan`) the store hashes in stdout, picked out chunk
+// by chunk as nix writes them instead of after it exits.
+fn run_nix_scanning(args: &[&str], scan: bool) -> Result<(Output, IgnoreSet), Error> {
+    let limit = Duration::from_secs(config().nix_timeout.0);
+    let max_mib = config().nix_max_output_mb.0;
+    let max_bytes = max_mib.saturating_mul(1 << 20);
+
+    let mut child = nix()
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(Error::NixNotFound)?;
+    // Drain both pipes off-thread so a chatty nix can't stall on a full pipe.
+    // stdout stops one byte past the cap; closing it then ends nix with EPIPE.
+    let cap = if max_bytes == 0 { u64::MAX } else { max_bytes + 1 };
+    let stdout = child.stdout.take().map(|pipe| {
+        thread::spawn(move || {
+            let (mut buf, mut scanner) = (Vec::new(), HashScanner::default());
+            let mut pipe = pipe.take(cap);
+            let mut chunk = vec![0; 64 * 1024];
+            loop {
+                match pipe.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if scan {
+                            scanner.feed(&chunk[..n]);
+                        }
+                        buf.extend_from_slice(&chunk[..n]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(_) => break,
+                }
             }
-        } else {
-            i += 1;
+            (buf, scanner.finish())
+        })
+    });
+    let stderr = child.stderr.take().map(|mut pipe| {
+        thread::spawn(move || {
+            let mut buf = Vec::new();
+            let _ = pipe.read_to_end(&mut buf);
+            buf
+        })
+    });
+
+    let status = if limit.is_zero() {
+        child.wait().map_err(Error::NixNotFound)?
+    } else {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait().map_err(Error::NixNotFound)? {
+                break status;
+            }
+            if start.elapsed() >= limit {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(Error::Timeout(limit));
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    };
+    let (stdout, hashes) = stdout.and_then(|t| t.join().ok()).unwrap_or_default();
+    if max_bytes > 0 && stdout.len() as u64 > max_bytes {
+        return Err(Error::OutputTooLarge(max_mib));
+    }
+    let stderr = stderr.and_then(|t| t.join().ok()).unwrap_or_default();
+    Ok((Output { status, stdout, stderr }, hashes))
+}
+
+// Requisite store paths as nix's JSON, with the hashes already parsed out of
+// it while it streamed in. `caching` enables the negative cache:
+// a failure within the last few seconds is replayed instead of spawning nix
+// again, so a prompt doesn't re-hang on every render while nix is down.
+// --refresh always tries.
+fn refresh(opts: &Opts, caching: bool) -> Result<(Vec<u8>, IgnoreSet), Error> {
+    let window = config().failure_ttl.0;
+    let remember = caching && window > 0;
+    if remember
+        && !opts.refresh
+        && let Some(err) = recent_failure(opts, Duration::from_secs(window))
+    {
+        note!("nix-path-pkgs: replaying a nix failure from the last {window}s (--refresh retries now)");
+        return Err(Error::NixEvalFailed(err));
+    }
+
+    let result = eval_requisites(opts);
+    if remember {
+        match &result {
+            Ok(_) => clear_failure(),
+            Err(Error::NixEvalFailed(stderr)) => record_failure(opts, stderr),
+            Err(Error::NixNotFound(e)) if e.kind() == io::ErrorKind::NotFound => {} // fails instantly anyway
+            Err(e @ (Error::NixNotFound(_) | Error::Timeout(_))) => record_failure(opts, &e.to_string()),
+            Err(_) => {} // config errors fail fast anyway
+        }
+    }
+    result
+}
+
+fn eval_requisites(opts: &Opts) -> Result<(Vec<u8>, IgnoreSet), Error> {
+    // Transient failures (locked db, flaky substituter) get retried with
+    // exponential backoff; anything else fails on the first attempt.
+    let attempts = config().nix_attempts.0.max(1);
+    let mut backoff = Duration::from_millis(config().nix_backoff_ms.0);
+    let mut attempt = 1;
+    let expr = nix_expr(opts.filter_set.expr(), opts);
+    loop {
+        let (o, hashes) = run_nix_scanning(&[&REQUISITES_EVAL[..], &[&expr]].concat(), true)?;
+        let json = json_payload(&o.stdout);
+        if o.status.success() && !is_string_array(json) {
+            return Err(Error::NotStorePaths { flake: opts.flake.clone() });
+        }
+        if o.status.success() && json.len() < o.stdout.len() {
+            // Noise ahead of the JSON may name store paths of its own.
+            return Ok((json.to_vec(), parse_hashes(json)));
+        }
+        if o.status.success() {
+            return Ok((o.stdout, hashes));
+        }
+        let stderr = String::from_utf8_lossy(&o.stderr).into_owned();
+        if is_bad_expr(&stderr) {
+            return Err(Error::BadExpr { flake: opts.flake.clone(), stderr });
         }
+        if attempt >= attempts || !is_transient_nix_error(&stderr) {
+            return Err(Error::NixEvalFailed(stderr));
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
     }
+}
+
+// Negative cache: the last nix eval error, kept for NIX_PATH_PKGS_FAILURE_TTL.
+// Its first line records what was evaluated, so a failure for one flake
+// (or system, filter set, store) isn't replayed for another.
+fn failure_file() -> Option<PathBuf> {
+    cache_dir().map(|d| d.join("last-nix-failure"))
+}
 
-    hashes
+fn failure_inputs(opts: &Opts) -> String {
+    let system = opts.system.as_deref().unwrap_or("");
+    let store = opts.store.as_deref().unwrap_or("");
+    format!("{}\t{system}\t{}\t{store}", opts.flake, opts.filter_set)
 }
 
-// "/nix/store/<hash>-bash-5.3/bin" => ("<hash>", "bash")
-fn hash_and_name(dir: &str) -> Option<(&str, &str)> {
-    if !dir.starts_with("/nix/store/") || dir.len() < 44 || dir.as_bytes().get(43) != Some(&b'-') {
+fn recent_failure(opts: &Opts, window: Duration) -> Option<String> {
+    let file = failure_file()?;
+    let age = fs::metadata(&file).ok()?.modified().ok()?.elapsed().ok()?;
+    if age >= window {
         return None;
     }
-    let hash = dir.get(11..43)?;
-    let rest = dir.get(44..)?;                       // after "<hash>-"
-    let item = rest.split('/').next().unwrap_or(""); // "bash-5.3p3"
-    let b = item.as_bytes();
-    let mut cut = item.len();
-    for i in 0..b.len() {
-        if b[i] == b'-' && b.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
-            cut = i;
-            break;
+    let text = fs::read_to_string(file).ok()?;
+    let (inputs, err) = text.split_once('\n')?;
+    (inputs == failure_inputs(opts)).then(|| err.to_string())
+}
+
+fn record_failure(opts: &Opts, err: &str) {
+    if let Some(file) = failure_file()
+        && fs::create_dir_all(file.parent().unwrap_or(&file)).is_ok()
+    {
+        let _ = fs::write(file, format!("{}\n{err}", failure_inputs(opts))); // best-effort
+    }
+}
+
+fn clear_failure() {
+    if let Some(file) = failure_file() {
+        let _ = fs::remove_file(file); // usually absent
+    }
+}
+
+// Make sure the cache for the current key is fresh; a fresh cache is left
+// alone unless --force. Meant for `nix-path-pkgs warm &` in shell startup.
+fn run_warm(opts: &Opts, ttl: u64, retention: u64) -> ExitCode {
+    if ttl == 0 {
+        note!("nix-path-pkgs: caching is disabled (TTL=0); nothing to warm");
+        return ExitCode::from(0);
+    }
+    if opts.filter_set == FilterSet::None {
+        note!("nix-path-pkgs: --filter-set none evaluates nothing; nothing to warm");
+        return ExitCode::from(0);
+    }
+    let key = match get_cache_key(opts) {
+        Ok(Some(key)) => key,
+        Ok(None) => {
+            note!("nix-path-pkgs: couldn't determine the cache key; nothing to warm");
+            return ExitCode::from(1);
         }
+        Err(e) => return report(&e),
+    };
+    if !opts.force && read_cache(ttl, Some(&key)).ok().flatten().is_some() {
+        return ExitCode::from(0);
+    }
+    // Filling the cache is the whole point here, so a write failure is an error.
+    match refresh(opts, true).and_then(|(bytes, _)| write_cache(&bytes, Some(&key), ttl, retention)) {
+        Ok(()) => ExitCode::from(0),
+        Err(e) => report(&e),
     }
-    Some((hash, &item[..cut]))
 }
 
-// XDG cache helpers
-fn cache_dir() -> PathBuf {
-    if let Ok(xdg) = env::var("XDG_CACHE_HOME") {
-        if !xdg.is_empty() {
-            return Path::new(&xdg).join("nix-path-pkgs");
+// Compare the kept packages against a saved list (JSON array or NDJSON of
+// package objects). Prints the diff and exits 3 on drift; --update-baseline
+// rewrites the file instead.
+fn run_baseline(file: &Path, filter: &Filter, path: &str, update: bool) -> ExitCode {
+    let pkgs = filter.packages(path);
+    if update {
+        let lines: Vec<String> = pkgs.iter().map(format::json_object).collect();
+        let text = format!("[\n{}\n]\n", lines.join(",\n"));
+        if let Err(e) = file.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(file, text)) {
+            note!("nix-path-pkgs: cannot write {}: {e}", file.display());
+            return ExitCode::from(2);
+        }
+        return ExitCode::from(0);
+    }
+
+    let saved = match fs::read_to_string(file) {
+        Ok(text) => text,
+        Err(e) => {
+            note!("nix-path-pkgs: {}: {e} (create it with --update-baseline)", file.display());
+            return ExitCode::from(2);
+        }
+    };
+    let Some(saved) = format::parse_package_list(&saved) else {
+        note!("nix-path-pkgs: {}: not a JSON package list", file.display());
+        return ExitCode::from(2);
+    };
+    let old: Vec<(&str, &str)> = saved.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+    let new: Vec<(&str, &str)> = pkgs.iter().map(|p| (p.name, p.version)).collect();
+    let lines = diff_packages(&old, &new);
+    for line in &lines {
+        println!("{line}");
+    }
+    ExitCode::from(if lines.is_empty() { 0 } else { 3 })
+}
+
+// `snapshot <file>`: the kept packages as a pretty JSON array of package
+// objects (the --json-schema shape), sorted by name so snapshots diff cleanly
+// in git. Output flags don't apply; it's what --baseline reads back.
+fn run_snapshot(file: &Path, filter: &Filter, path: &str) -> ExitCode {
+    let mut pkgs = filter.packages(path);
+    sort_by_name(&mut pkgs);
+    let mut text = Vec::new();
+    let written = format::write_json_array(&mut text, &pkgs, |_| None, None, true)
+        .and_then(|_| file.parent().map_or(Ok(()), fs::create_dir_all))
+        .and_then(|_| fs::write(file, text));
+    if let Err(e) = written {
+        note!("nix-path-pkgs: cannot write {}: {e}", file.display());
+        return ExitCode::from(2);
+    }
+    ExitCode::from(0)
+}
+
+// --ignore-self: the closure of this binary's own store path, so a
+// nix-installed nix-path-pkgs doesn't list its own dependencies. A closure
+// never changes, so it's cached per store hash with no TTL. Best-effort:
+// anything going wrong just means nothing extra is ignored.
+fn self_closure(opts: &Opts) -> IgnoreSet {
+    let exe = env::current_exe().and_then(fs::canonicalize).ok();
+    let root = opts.store.as_deref().unwrap_or("");
+    let Some(pkg) = exe.as_ref().and_then(|e| e.to_str()).and_then(|e| Package::from_dir(in_store_root(e, root))) else {
+        if opts.verbose {
+            note!("nix-path-pkgs: not running from the nix store; --ignore-self has nothing to hide");
+        }
+        return IgnoreSet::default();
+    };
+    let file = cache_dir().map(|d| d.join(format!("self-closure-{}", pkg.hash)));
+    if let Some(text) = file.as_ref().and_then(|f| fs::read(f).ok()) {
+        return parse_hashes(&text);
+    }
+    let closure = match run_nix(&["path-info", "--recursive", pkg.path]) {
+        Ok(o) if o.status.success() => o.stdout,
+        _ => {
+            note!("nix-path-pkgs: couldn't query the closure of {}; --ignore-self skipped", pkg.path);
+            return IgnoreSet::default();
+        }
+    };
+    if let Some(f) = file {
+        let _ = fs::create_dir_all(f.parent().unwrap_or(&f)).and_then(|_| fs::write(&f, &closure)); // best-effort
+    }
+    parse_hashes(&closure)
+}
+
+// nix-shell and `nix develop` both set IN_NIX_SHELL ("impure" / "pure").
+fn in_dev_shell() -> bool {
+    env::var_os("IN_NIX_SHELL").is_some_and(|v| !v.is_empty())
+}
+
+// --delta-from-login's baseline: the login shell's PATH, one entry per line.
+fn login_path_file() -> Option<PathBuf> {
+    Some(cache_dir()?.join("login-path"))
+}
+
+// --delta-from-login outside a dev shell: (re)record this PATH as the login
+// baseline. Nothing is "added" here, so exit 1.
+fn record_login_path(delimiter: char) -> ExitCode {
+    let Some(file) = login_path_file() else {
+        note!("nix-path-pkgs: --delta-from-login needs a cache dir (XDG_CACHE_HOME or HOME)");
+        return ExitCode::from(2);
+    };
+    let entries: Vec<String> = env_path(delimiter).split(delimiter).map(String::from).collect();
+    if let Err(e) = fs::create_dir_all(file.parent().unwrap_or(&file)).and_then(|_| fs::write(&file, entries.join("\n"))) {
+        note!("nix-path-pkgs: cannot write {}: {e}", file.display());
+        return ExitCode::from(2);
+    }
+    ExitCode::from(1)
+}
+
+// --delta-from-login inside a dev shell: "+name" for each kept package the
+// login PATH didn't have and "~name (old -> new)" for each it had at another
+// version, one per line like --diff. Nothing added => exit 1.
+fn run_delta_from_login(filter: &Filter, path: &str) -> ExitCode {
+    let Some(login) = login_path_file().and_then(|f| fs::read_to_string(f).ok()) else {
+        note!("nix-path-pkgs: no login PATH recorded; run --delta-from-login once outside a dev shell");
+        return ExitCode::from(2);
+    };
+    let new: Vec<(&str, &str)> = filter.packages(path).iter().map(|p| (p.name, p.version)).collect();
+    let lines: Vec<String> = diff_packages(&path_packages(&login, '\n'), &new)
+        .into_iter()
+        .filter(|l| !l.starts_with('-'))
+        .collect();
+    if lines.is_empty() {
+        return ExitCode::from(1);
+    }
+    for line in lines {
+        println!("{line}");
+    }
+    ExitCode::from(0)
+}
+
+// --since-last: only what changed since the previous --since-last run
+// ("+jq, -git, ~rg (13.0 -> 14.0)" on one line), then remember this run's
+// packages for the next. Nothing changed (or no earlier run) => exit 1.
+fn run_since_last(filter: &Filter, path: &str) -> ExitCode {
+    let Some(file) = cache_dir().map(|d| d.join("last-run")) else {
+        note!("nix-path-pkgs: --since-last needs a cache dir (XDG_CACHE_HOME or HOME)");
+        return ExitCode::from(2);
+    };
+    let pkgs = filter.packages(path);
+    let saved = fs::read_to_string(&file).ok().and_then(|text| format::parse_package_list(&text));
+
+    let lines: Vec<String> = pkgs.iter().map(format::json_object).collect();
+    if let Err(e) = fs::create_dir_all(file.parent().unwrap_or(&file)).and_then(|_| fs::write(&file, lines.join("\n"))) {
+        note!("nix-path-pkgs: cannot write {}: {e}", file.display());
+    }
+
+    let Some(saved) = saved else {
+        return ExitCode::from(1); // first run: nothing to compare against
+    };
+    let old: Vec<(&str, &str)> = saved.iter().map(|(n, v)| (n.as_str(), v.as_str())).collect();
+    let new: Vec<(&str, &str)> = pkgs.iter().map(|p| (p.name, p.version)).collect();
+    let changes = diff_packages(&old, &new);
+    if changes.is_empty() {
+        return ExitCode::from(1);
+    }
+    println!("{}", changes.join(", "));
+    ExitCode::from(0)
+}
+
+// --explain-package: every PATH entry for one name and what the walk did
+// with it ("kept", "ignored", "skipped", "duplicate"). Exit 1 if none.
+fn run_explain_package(filter: &Filter, path: &str, name: &str) -> ExitCode {
+    let found = filter.explain(path, name);
+    if found.is_empty() {
+        note!("nix-path-pkgs: no `{name}` on PATH");
+        return ExitCode::from(1);
+    }
+    for (pkg, verdict) in found {
+        let version = if pkg.version.is_empty() { "-" } else { pkg.version };
+        println!("{verdict:<9} {} {version}  {}", pkg.name, pkg.path);
+    }
+    ExitCode::from(0)
+}
+
+fn run_diff(old_file: &Path, new_file: &Path, delimiter: char) -> ExitCode {
+    let read = |p: &Path| {
+        fs::read_to_string(p).map_err(|e| note!("nix-path-pkgs: {}: {e}", p.display()))
+    };
+    let (Ok(old), Ok(new)) = (read(old_file), read(new_file)) else {
+        return ExitCode::from(2);
+    };
+    let lines = diff_packages(&path_packages(old.trim(), delimiter), &path_packages(new.trim(), delimiter));
+    if lines.is_empty() {
+        return ExitCode::from(1);
+    }
+    for line in lines {
+        println!("{line}");
+    }
+    ExitCode::from(0)
+}
+
+// profile-diff: what generation `new` added, removed or changed the version
+// of relative to `old`, in --diff's format. Exit 1 if nothing changed.
+fn run_profile_diff(profile: &Path, old: u32, new: u32, delimiter: char) -> ExitCode {
+    let entries = |generation| {
+        let dir = generation_dir(profile, generation)?;
+        profile_entries(&dir, delimiter).map_err(|e| format!("cannot read {}: {e}", dir.display()))
+    };
+    let (old_path, new_path) = match (entries(old), entries(new)) {
+        (Ok(old), Ok(new)) => (old, new),
+        (Err(e), _) | (_, Err(e)) => {
+            note!("nix-path-pkgs: {e}");
+            return ExitCode::from(2);
         }
+    };
+    let lines = diff_packages(&path_packages(&old_path, delimiter), &path_packages(&new_path, delimiter));
+    if lines.is_empty() {
+        return ExitCode::from(1);
     }
-    Path::new(&env::var("HOME").unwrap_or_else(|_| ".".into())).join(".cache/nix-path-pkgs")
+    for line in lines {
+        println!("{line}");
+    }
+    ExitCode::from(0)
+}
+
+// XDG cache helpers
+// Set once a write under the XDG/HOME cache dir has failed and the temp-dir
+// fallback worked; every later cache access in this process goes there.
+static CACHE_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+fn cache_dir() -> Option<PathBuf> {
+    if CACHE_FALLBACK.load(Ordering::Relaxed) {
+        return fallback_cache_dir();
+    }
+    let nonempty = |var| env::var(var).ok().filter(|v: &String| !v.is_empty());
+    if let Some(xdg) = nonempty("XDG_CACHE_HOME") {
+        return Some(Path::new(&xdg).join("nix-path-pkgs"));
+    }
+    nonempty("HOME").map(|home| Path::new(&home).join(".cache/nix-path-pkgs"))
+}
+
+// One per FilterSet that's evaluated, so cleanup recognizes all of them.
+const CACHE_SUFFIXES: [&str; 2] = ["-stdenv-allowed-requisites.json", "-stdenv-bootstrap.json"];
+
+// This run's --filter-set's file suffix.
+fn cache_suffix() -> &'static str {
+    config().filter_set.0.cache_suffix()
+}
+
+fn cache_file(cache_key: &str) -> Option<PathBuf> {
+    if let Some(pinned) = pinned_cache_file() {
+        return Some(pinned.to_path_buf());
+    }
+    Some(cache_dir()?.join(format!("{cache_key}{}", cache_suffix())))
+}
+
+// --cache-file: used as is for every key. Keeping it to one nixpkgs rev is
+// up to the user, so it gets no fallback dir and no cleanup.
+fn pinned_cache_file() -> Option<&'static Path> {
+    config().cache_file.0.as_deref()
+}
+
+// Per-user cache dir under the temp dir, for read-only XDG/HOME caches.
+// None without a user name: "nix-path-pkgs-" would be shared by everyone.
+fn fallback_cache_dir() -> Option<PathBuf> {
+    let user = env::var("USER").or_else(|_| env::var("LOGNAME")).unwrap_or_default();
+    (!user.is_empty()).then(|| env::temp_dir().join(format!("nix-path-pkgs-{user}")))
+}
+
+// Whether only we could have put anything in `dir`: ours, and not group or
+// world writable. The temp dir is shared, so another user may have created
+// the fallback dir first (and mode(0o700) does nothing to an existing one).
+fn is_private_dir(dir: &Path) -> bool {
+    fs::metadata(dir).is_ok_and(|m| m.is_dir() && m.uid() == euid() && m.permissions().mode() & 0o022 == 0)
+}
+
+// The effective uid, from the libc std already links.
+fn euid() -> u32 {
+    unsafe extern "C" {
+        fn geteuid() -> u32;
+    }
+    // SAFETY: geteuid takes no arguments and always succeeds.
+    unsafe { geteuid() }
+}
+
+// A cache file left in the fallback dir by an earlier run, unless someone
+// else could have written it.
+fn fallback_cache_file(cache_key: &str) -> Option<PathBuf> {
+    let dir = fallback_cache_dir().filter(|d| is_private_dir(d))?;
+    Some(dir.join(format!("{cache_key}{}", cache_suffix())))
 }
 
-fn cache_file(cache_key: &str) -> PathBuf {
-    cache_dir().join(format!("{}-stdenv-allowed-requisites.json", cache_key))
+// "<rev>-<system>" => "<rev>"
+fn key_rev(cache_key: &str) -> &str {
+    cache_key.split_once('-').map_or(cache_key, |(rev, _)| rev)
 }
 
-// Clean up old cache files (older than 1 day)
-fn cleanup_old_cache() -> io::Result<()> {
-    let dir = cache_dir();
+// Clean up ignore-set caches older than `retention`, and those for a
+// nixpkgs rev other than `current_key`'s once they're past `grace` (so the
+// directory doesn't grow by one file per update). Runs at most once per
+// process; the plain age sweep leaves small directories alone.
+fn cleanup_old_cache(retention: Duration, current_key: &str, grace: Duration) -> io::Result<()> {
+    static DONE: AtomicBool = AtomicBool::new(false);
+    if DONE.swap(true, Ordering::Relaxed) {
+        return Ok(());
+    }
+
+    let Some(dir) = cache_dir() else {
+        return Ok(());
+    };
     if !dir.exists() {
         return Ok(());
     }
 
-    let now = SystemTime::now();
-    let one_day = Duration::from_secs(86400);
+    let entries: Vec<_> = fs::read_dir(&dir)?.collect::<io::Result<_>>()?;
+    let sweep = entries.len() >= CLEANUP_MIN_ENTRIES;
+    let current_rev = key_rev(current_key);
 
-    for entry in fs::read_dir(&dir)? {
-        let entry = entry?;
+    let now = SystemTime::now();
+    for entry in entries {
         let path = entry.path();
 
         if !path.is_file() {
             continue;
         }
 
-        if let Ok(metadata) = fs::metadata(&path) {
-            if let Ok(modified) = metadata.modified() {
-                if let Ok(age) = now.duration_since(modified) {
-                    if age > one_day {
-                        let _ = fs::remove_file(&path); // best-effort
-                    }
-                }
-            }
+        // Only ignore-set caches; login-path, last-run, closure-sizes and
+        // the like are state that has to outlive any retention period.
+        let Some(key) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| CACHE_SUFFIXES.iter().find_map(|suffix| name.strip_suffix(suffix)).map(str::to_owned))
+        else {
+            continue;
+        };
+        let superseded = key_rev(&key) != current_rev;
+        if let Ok(metadata) = fs::metadata(&path)
+            && let Ok(modified) = metadata.modified()
+            && let Ok(age) = now.duration_since(modified)
+            && ((sweep && age > retention) || (superseded && age > grace))
+        {
+            let _ = fs::remove_file(&path); // best-effort
         }
     }
 
     Ok(())
 }
-fn read_cache(ttl_secs: u64, cache_key: Option<&str>) -> io::Result<Option<Vec<u8>>> {
-    let Some(key) = cache_key else {
-        return Ok(None);
+
+// The cache file for `key` if there is one: at `p`, or where an earlier run
+// fell back to.
+fn find_cache(p: PathBuf, key: &str) -> Option<(PathBuf, fs::Metadata)> {
+    match fs::metadata(&p) {
+        Ok(m) => Some((p, m)),
+        Err(_) => fallback_cache_file(key).and_then(|f| Some((f.clone(), fs::metadata(f).ok()?))),
+    }
+}
+
+// The cache file this run would read: the pinned one (no key needed), else
+// the one for `key`.
+fn locate_cache(cache_key: Option<&str>) -> Option<(PathBuf, fs::Metadata)> {
+    match (pinned_cache_file(), cache_key) {
+        (Some(p), _) => fs::metadata(p).ok().map(|m| (p.to_path_buf(), m)),
+        (None, Some(key)) => find_cache(cache_file(key)?, key),
+        (None, None) => None,
+    }
+}
+
+// Whether any requisites cache (for whatever key) is younger than `ttl`; if
+// not, this run is a miss whatever the key turns out to be.
+fn any_fresh_cache(ttl: u64) -> bool {
+    let fresh = |dir: PathBuf| {
+        fs::read_dir(dir).is_ok_and(|entries| {
+            entries.flatten().any(|e| {
+                e.file_name().to_str().is_some_and(|n| n.ends_with(cache_suffix()))
+                    && e.metadata().ok().and_then(|m| cache_age(&m)).is_some_and(|age| age <= Duration::from_secs(ttl))
+            })
+        })
+    };
+    if let Some(p) = pinned_cache_file() {
+        return fs::metadata(p).ok().and_then(|m| cache_age(&m)).is_some_and(|age| age <= Duration::from_secs(ttl));
+    }
+    cache_dir().is_some_and(fresh) || fallback_cache_dir().filter(|d| is_private_dir(d)).is_some_and(fresh)
+}
+
+// Time since the cache file was written; None if the clock went backwards.
+fn cache_age(meta: &fs::Metadata) -> Option<Duration> {
+    meta.modified().ok().and_then(|t| SystemTime::now().duration_since(t).ok())
+}
+
+// --explain-cache: what the next run would do with the cache, and why.
+// Prints a report and stops; the PATH isn't walked.
+fn run_explain_cache(opts: &Opts, ttl: u64) -> ExitCode {
+    let key = match get_cache_key(opts) {
+        Ok(key) => key,
+        Err(e) => return report(&e),
+    };
+    let found = locate_cache(key.as_deref());
+    let age = found.as_ref().and_then(|(_, meta)| cache_age(meta));
+
+    let verdict = match (&key, &found) {
+        _ if ttl == 0 => "miss (caching disabled)",
+        (None, _) if pinned_cache_file().is_none() => "miss (no cache key: the flake revision/system eval failed)",
+        _ if opts.refresh => "miss (--refresh)",
+        (_, None) => "miss (no cache file)",
+        _ if age.is_some_and(|a| a <= Duration::from_secs(ttl)) => "hit",
+        _ => "miss (older than the TTL)",
     };
-    let p = cache_file(key);
+    let path = match (&found, &key) {
+        (Some((p, _)), _) => p.display().to_string(),
+        (None, Some(k)) => cache_file(k).map_or("none".into(), |p| p.display().to_string()),
+        (None, None) => pinned_cache_file().map_or("unknown".into(), |p| p.display().to_string()),
+    };
+    println!("key:    {}", key.as_deref().unwrap_or("unknown"));
+    println!("file:   {path}");
+    println!("exists: {}", if found.is_some() { "yes" } else { "no" });
+    println!("age:    {}", age.map_or("-".into(), |a| format!("{}s", a.as_secs())));
+    println!("ttl:    {ttl}s");
+    println!("next:   {verdict}");
+    ExitCode::from(0)
+}
 
-    let meta = match fs::metadata(&p) {
-        Ok(m) => m,
-        Err(_) => return Ok(None),
+fn read_cache(ttl_secs: u64, cache_key: Option<&str>) -> Result<Option<Vec<u8>>, Error> {
+    let Some((p, meta)) = locate_cache(cache_key) else {
+        return Ok(None);
     };
 
-    if meta
-        .modified()
-        .ok()
-        .and_then(|t| SystemTime::now().duration_since(t).ok())
-        .is_some_and(|d| d <= Duration::from_secs(ttl_secs))
-    {
-        return Ok(Some(fs::read(&p)?));
+    if cache_age(&meta).is_some_and(|d| d <= Duration::from_secs(ttl_secs)) {
+        let bytes = fs::read(&p).map_err(|source| Error::CacheIo { path: p.clone(), source })?;
+        return Ok(bytes.strip_prefix(CACHE_MAGIC).map(<[u8]>::to_vec));
     }
 
     Ok(None)
 }
 
-fn write_cache(bytes: &[u8], cache_key: Option<&str>) -> io::Result<()> {
+// `bytes` is nix's JSON exactly as printed, never a re-serialized IgnoreSet
+// (whose iteration order differs per process), so the same eval always
+// writes a byte-identical file.
+fn write_cache(bytes: &[u8], cache_key: Option<&str>, ttl: u64, retention: u64) -> Result<(), Error> {
+    let contents = [CACHE_MAGIC, bytes].concat();
+    if let Some(p) = pinned_cache_file() {
+        let write = || {
+            fs::create_dir_all(p.parent().unwrap_or(p))?;
+            fs::write(p, &contents)
+        };
+        return write().map_err(|source| Error::CacheIo { path: p.to_path_buf(), source });
+    }
     let Some(key) = cache_key else {
         return Ok(());
     };
-    let p = cache_file(key);
-
-    fs::create_dir_all(cache_dir())?;
-    fs::write(&p, bytes)?;
+    let Some(p) = cache_file(key) else {
+        return Ok(());
+    };
+    let write = |p: &Path| {
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(p.parent().unwrap_or(p))?;
+        fs::write(p, &contents)
+    };
+    if let Err(source) = write(&p) {
+        // A read-only cache dir shouldn't make every run cold: retry under
+        // the temp dir and keep caching there for the rest of the process.
+        let fallback = fallback_cache_dir().map(|d| d.join(format!("{key}{}", cache_suffix())));
+        let written = fallback.is_some_and(|f| {
+            let dir = f.parent().unwrap_or(&f);
+            fs::DirBuilder::new().recursive(true).mode(0o700).create(dir).is_ok() && is_private_dir(dir) && write(&f).is_ok()
+        });
+        if CACHE_FALLBACK.load(Ordering::Relaxed) || !written {
+            return Err(Error::CacheIo { path: p, source });
+        }
+        CACHE_FALLBACK.store(true, Ordering::Relaxed);
+    }
 
-    // Clean up old cache files
-    let _ = cleanup_old_cache(); // best-effort
+    // Clean up old cache files (retention=0 => never). Superseded revs get
+    // one TTL of grace: past that they'd be treated as stale anyway.
+    if retention > 0 {
+        let _ = cleanup_old_cache(Duration::from_secs(retention), key, Duration::from_secs(ttl)); // best-effort
+    }
 
     Ok(())
 }
@@ -0,0 +1,302 @@
+// Small regex for --skip-regex: literals, `.`, `[...]` classes, `\d \w \s`
+// (and their negations), `* + ?`, `^ $`, `(...)` groups and `|`. Matching
+// is an unanchored search over sets of text positions, never backtracking.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone)]
+pub struct Regex {
+    root: Node, // a Group of the top-level `|` alternatives
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Char(char),
+    Any,
+    Class { ranges: Vec<(char, char)>, negated: bool },
+    Start,
+    End,
+    Group(Vec<Vec<Node>>),
+    Repeat { node: Box<Node>, min: usize, max: Option<usize> },
+}
+
+impl Node {
+    // Single-char atoms only; anchors, groups and repeats go through `ends`.
+    fn matches(&self, c: char) -> bool {
+        match self {
+            Node::Char(want) => c == *want,
+            Node::Any => true,
+            Node::Class { ranges, negated } => ranges.iter().any(|&(lo, hi)| (lo..=hi).contains(&c)) != *negated,
+            _ => false,
+        }
+    }
+}
+
+impl Regex {
+    // Whether the pattern matches anywhere in `s`.
+    pub fn is_match(&self, s: &str) -> bool {
+        let text: Vec<char> = s.chars().collect();
+        let starts = vec![true; text.len() + 1];
+        ends(std::slice::from_ref(&self.root), &text, starts).contains(&true)
+    }
+}
+
+impl FromStr for Regex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { chars: s.chars().collect(), pos: 0 };
+        let alts = parser.alternation()?;
+        match parser.peek() {
+            None => Ok(Regex { root: Node::Group(alts) }),
+            Some(_) => Err("unmatched `)`".into()),
+        }
+    }
+}
+
+// Positions where `nodes` can end, given the positions it may start at
+// (`starts[i]` for text index `i`). Tracking sets of positions instead of
+// backtracking keeps nested repeats like `(a|aa)*c` polynomial in the
+// length of the name rather than exponential.
+fn ends(nodes: &[Node], text: &[char], starts: Vec<bool>) -> Vec<bool> {
+    nodes.iter().fold(starts, |at, node| match node {
+        Node::Start => at.iter().enumerate().map(|(i, &ok)| ok && i == 0).collect(),
+        Node::End => at.iter().enumerate().map(|(i, &ok)| ok && i == text.len()).collect(),
+        Node::Group(alts) => alts.iter().fold(vec![false; at.len()], |mut all, seq| {
+            union(&mut all, &ends(seq, text, at.clone()));
+            all
+        }),
+        Node::Repeat { node, min, max } => repeat(node, *min, *max, text, at),
+        atom => {
+            let mut next = vec![false; at.len()];
+            for (i, &c) in text.iter().enumerate() {
+                next[i + 1] = at[i] && atom.matches(c);
+            }
+            next
+        }
+    })
+}
+
+// `min` passes of `node`, then up to `max` more (until nothing new turns up
+// when unbounded); every position reached from `min` on is an end.
+fn repeat(node: &Node, min: usize, max: Option<usize>, text: &[char], starts: Vec<bool>) -> Vec<bool> {
+    let node = std::slice::from_ref(node);
+    let mut frontier = starts;
+    for _ in 0..min {
+        frontier = ends(node, text, frontier);
+    }
+    let mut seen = frontier.clone();
+    let mut count = min;
+    while frontier.contains(&true) && max.is_none_or(|m| count < m) {
+        let next = ends(node, text, frontier);
+        frontier = next.iter().zip(&seen).map(|(&n, &s)| n && !s).collect();
+        union(&mut seen, &next);
+        count += 1;
+    }
+    seen
+}
+
+fn union(into: &mut [bool], other: &[bool]) {
+    for (a, &b) in into.iter_mut().zip(other) {
+        *a |= b;
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        Some(c)
+    }
+
+    // seq ('|' seq)*, stopping before a `)` or the end.
+    fn alternation(&mut self) -> Result<Vec<Vec<Node>>, String> {
+        let mut alts = vec![self.sequence()?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.sequence()?);
+        }
+        Ok(alts)
+    }
+
+    fn sequence(&mut self) -> Result<Vec<Node>, String> {
+        let mut seq = Vec::new();
+        while let Some(c) = self.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            self.pos += 1;
+            let (min, max) = match c {
+                '*' => (0, None),
+                '+' => (1, None),
+                '?' => (0, Some(1)),
+                _ => {
+                    seq.push(self.atom(c)?);
+                    continue;
+                }
+            };
+            match seq.pop() {
+                Some(node @ (Node::Char(_) | Node::Any | Node::Class { .. } | Node::Group(_))) => {
+                    seq.push(Node::Repeat { node: Box::new(node), min, max })
+                }
+                _ => return Err(format!("nothing to repeat before `{c}`")),
+            }
+        }
+        Ok(seq)
+    }
+
+    fn atom(&mut self, c: char) -> Result<Node, String> {
+        Ok(match c {
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '(' => {
+                let alts = self.alternation()?;
+                if self.next() != Some(')') {
+                    return Err("unclosed `(`".into());
+                }
+                Node::Group(alts)
+            }
+            '[' => self.class()?,
+            '\\' => self.escape()?,
+            c => Node::Char(c),
+        })
+    }
+
+    // After a `\`: a shorthand class or the literal char.
+    fn escape(&mut self) -> Result<Node, String> {
+        let c = self.next().ok_or("trailing `\\`")?;
+        Ok(match shorthand(c.to_ascii_lowercase()) {
+            Some(ranges) => Node::Class { ranges: ranges.to_vec(), negated: c.is_ascii_uppercase() },
+            None => Node::Char(c),
+        })
+    }
+
+    // After a `[`: optional `^`, then chars and `a-z` ranges up to `]`. A `]`
+    // right after the opening (or `^`) is literal.
+    fn class(&mut self) -> Result<Node, String> {
+        let negated = self.peek() == Some('^');
+        if negated {
+            self.pos += 1;
+        }
+        let mut ranges = Vec::new();
+        loop {
+            let lo = match self.next() {
+                None => return Err("unclosed `[`".into()),
+                Some(']') if !ranges.is_empty() => break,
+                Some('\\') => {
+                    let c = self.next().ok_or("unclosed `[`")?;
+                    match shorthand(c) {
+                        Some(more) => {
+                            ranges.extend_from_slice(more);
+                            continue;
+                        }
+                        None => c,
+                    }
+                }
+                Some(c) => c,
+            };
+            let hi = match (self.peek(), self.chars.get(self.pos + 1)) {
+                (Some('-'), Some(&hi)) if hi != ']' => {
+                    self.pos += 2;
+                    hi
+                }
+                _ => lo,
+            };
+            if hi < lo {
+                return Err(format!("bad range `{lo}-{hi}`"));
+            }
+            ranges.push((lo, hi));
+        }
+        Ok(Node::Class { ranges, negated })
+    }
+}
+
+// Ranges for `\d`, `\w` and `\s`.
+fn shorthand(c: char) -> Option<&'static [(char, char)]> {
+    match c {
+        'd' => Some(&[('0', '9')]),
+        'w' => Some(&[('a', 'z'), ('A', 'Z'), ('0', '9'), ('_', '_')]),
+        's' => Some(&[(' ', ' '), ('\t', '\r')]),
+        _ => None,
+    }
+}
+
+// Shell glob for --include / --exclude: `*` (any run), `?` (one char),
+// `[...]` classes (`[!...]` or `[^...]` negated, `a-z` ranges) and `\` to
+// escape. Unlike Regex it must match the whole name.
+#[derive(Debug, Clone)]
+pub struct Glob {
+    tokens: Vec<Token>,
+}
+
+#[derive(Debug, Clone)]
+enum Token {
+    Star,
+    One(Node), // a Char, Any or Class
+}
+
+impl Glob {
+    pub fn matches(&self, s: &str) -> bool {
+        let text: Vec<char> = s.chars().collect();
+        // Classic single-star backtracking: on a mismatch, let the last `*`
+        // swallow one more char and retry from there.
+        let (mut t, mut i) = (0, 0);
+        let mut star: Option<(usize, usize)> = None;
+        while i < text.len() {
+            match self.tokens.get(t) {
+                Some(Token::Star) => {
+                    star = Some((t, i));
+                    t += 1;
+                }
+                Some(Token::One(node)) if node.matches(text[i]) => {
+                    t += 1;
+                    i += 1;
+                }
+                _ => match star {
+                    Some((st, si)) => {
+                        star = Some((st, si + 1));
+                        t = st + 1;
+                        i = si + 1;
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[t..].iter().all(|tok| matches!(tok, Token::Star))
+    }
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser { chars: s.chars().collect(), pos: 0 };
+        let mut tokens = Vec::new();
+        while let Some(c) = parser.next() {
+            tokens.push(match c {
+                '*' => Token::Star,
+                '?' => Token::One(Node::Any),
+                '[' => {
+                    // `[!...]` is the shell spelling of `[^...]`
+                    if parser.peek() == Some('!') {
+                        parser.chars[parser.pos] = '^';
+                    }
+                    Token::One(parser.class()?)
+                }
+                '\\' => Token::One(Node::Char(parser.next().ok_or("trailing `\\`")?)),
+                c => Token::One(Node::Char(c)),
+            });
+        }
+        Ok(Glob { tokens })
+    }
+}
@@ -78,10 +78,10 @@ fn test_empty_path() {
         .output()
         .expect("Failed to execute binary");
 
-    // Should exit with 1 (no packages) or panic if nix fails (exit code None)
-    // Either is acceptable for this edge case
+    // Should exit with 1 (no packages), 4 if nix fails or 127 if it isn't found
+    // Any is acceptable for this edge case
     assert!(
-        output.status.code() == Some(1) || output.status.code().is_none(),
+        matches!(output.status.code(), Some(1 | 4 | 127)),
         "Should handle empty PATH (got exit code: {:?})",
         output.status.code()
     );
@@ -99,9 +99,9 @@ fn test_non_nix_path() {
         .expect("Failed to execute binary");
 
     // Should exit with 1 (no nix packages found), succeed if PATH has nix paths,
-    // or panic if nix fails (exit code None)
+    // or exit 4 / 127 if nix fails or isn't found
     assert!(
-        output.status.code() == Some(1) || output.status.success() || output.status.code().is_none(),
+        matches!(output.status.code(), Some(0 | 1 | 4 | 127)),
         "Should handle non-nix paths gracefully (got exit code: {:?})",
         output.status.code()
     );
@@ -220,7 +220,7 @@ fn test_performance_regression() {
     use std::time::Instant;
 
     let start = Instant::now();
-    let output = Command::new(get_binary_path())
+    let _output = Command::new(get_binary_path())
         .output()
         .expect("Failed to execute binary");
     let duration = start.elapsed();
@@ -265,3 +265,2375 @@ fn test_invalid_ttl_values() {
         );
     }
 }
+
+// Directory holding a stub `nix` running `script`, named so tests don't clash.
+fn stub_nix_dir(name: &str, script: &str) -> std::path::PathBuf {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-{name}-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let nix = dir.join("nix");
+    if !nix.exists() {
+        let tmp = dir.join("nix.tmp");
+        std::fs::write(&tmp, format!("#!/bin/sh\n{script}")).unwrap();
+        std::fs::set_permissions(&tmp, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::rename(&tmp, &nix).unwrap();
+    }
+    dir
+}
+
+// Stub `nix` answering the cache-key and requisites evals, so pipeline tests
+// don't depend on a real nix install.
+fn fake_nix_dir() -> std::path::PathBuf {
+    stub_nix_dir(
+        "fake-nix",
+        "case \"$*\" in\n\
+           *path-info*) for p; do case \"$p\" in\n\
+               */nix/store/*-git-*) echo \"$p  100\" ;;\n\
+               */nix/store/*-ripgrep-*) echo \"$p  300\" ;;\n\
+             esac; done ;;\n\
+           *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+           *--json*) printf '[\"/nix/store/00000000000000000000000000000000-bash-5.2\"]' ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    )
+}
+
+// The binary set up against the stub nix with the given store dirs on PATH
+// (cache disabled unless the caller overrides the TTL).
+fn store_path_command(store_dirs: &[&str]) -> Command {
+    let mut path = fake_nix_dir().display().to_string();
+    for dir in store_dirs {
+        path.push(':');
+        path.push_str(dir);
+    }
+    let mut cmd = Command::new(get_binary_path());
+    cmd.env("PATH", path).env("NIX_PATH_PKGS_CACHE_TTL", "0");
+    cmd
+}
+
+fn run_with_store_path(store_dirs: &[&str], args: &[&str]) -> std::process::Output {
+    store_path_command(store_dirs)
+        .args(args)
+        .output()
+        .expect("Failed to execute binary")
+}
+
+fn write_temp(name: &str, contents: &str) -> std::path::PathBuf {
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn test_path_delimiter() {
+    let old = write_temp("nul-old", "/nix/store/12345678901234567890123456789012-git-2.39.0/bin\0/usr/bin\0");
+    let new = write_temp(
+        "nul-new",
+        "/nix/store/22345678901234567890123456789012-git-2.40.1/bin\0\
+         /nix/store/bbcdfghijklmnpqrsvwxyz1234567890-cargo-watch-8.4.0/bin\0",
+    );
+
+    let output = Command::new(get_binary_path())
+        .args(["--path-delimiter", "\\0", "--diff"])
+        .args([old.as_os_str(), new.as_os_str()])
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "~git (2.39.0 -> 2.40.1)\n+cargo-watch\n");
+
+    let output = Command::new(get_binary_path())
+        .args(["--path-delimiter", "::"])
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(output.status.code(), Some(2), "Multi-char delimiter should exit 2");
+}
+
+#[test]
+fn test_diff_mode() {
+    let old = write_temp(
+        "diff-old",
+        "/nix/store/12345678901234567890123456789012-git-2.39.0/bin:\
+         /nix/store/abcdfghijklmnpqrsvwxyz1234567890-ripgrep-14.0.0/bin:/usr/bin\n",
+    );
+    let new = write_temp(
+        "diff-new",
+        "/nix/store/22345678901234567890123456789012-git-2.40.1/bin:\
+         /nix/store/bbcdfghijklmnpqrsvwxyz1234567890-cargo-watch-8.4.0/bin\n",
+    );
+
+    let output = Command::new(get_binary_path())
+        .args(["--diff".as_ref(), old.as_os_str(), new.as_os_str()])
+        .output()
+        .expect("Failed to execute binary");
+
+    assert!(output.status.success(), "Diff with changes should exit 0");
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "~git (2.39.0 -> 2.40.1)\n-ripgrep\n+cargo-watch\n"
+    );
+
+    // Identical PATHs => nothing to report
+    let output = Command::new(get_binary_path())
+        .args(["--diff".as_ref(), old.as_os_str(), old.as_os_str()])
+        .output()
+        .expect("Failed to execute binary");
+
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_unknown_argument() {
+    let output = Command::new(get_binary_path())
+        .arg("--no-such-flag")
+        .output()
+        .expect("Failed to execute binary");
+
+    assert_eq!(output.status.code(), Some(2), "Unknown flags should exit 2");
+}
+
+#[test]
+fn test_ignore_hash_prefix() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep\n");
+
+    let output = run_with_store_path(&dirs, &["--ignore-hash", "1111"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ripgrep\n");
+
+    let output = run_with_store_path(&dirs, &["--ignore-hash", "1111", "--ignore-hash", "2222"]);
+    assert_eq!(output.status.code(), Some(1), "Everything ignored should exit 1");
+
+    // Not nix base32 (or not lowercase), so it could never match
+    for bad in ["abcde", "1111T", "0o", "x".repeat(33).as_str(), ""] {
+        let output = run_with_store_path(&dirs, &["--ignore-hash", bad]);
+        assert_eq!(output.status.code(), Some(2), "{bad:?} should be rejected");
+    }
+}
+
+#[test]
+fn test_skip_regex() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ghostty-nightly/bin",
+        "/nix/store/33333333333333333333333333333333-firefox-unwrapped-120.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--skip-regex", "^ghostty", "--skip-regex", "-unwrapped$"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+
+    let output = run_with_store_path(&dirs, &["--skip-regex", "(ghostty"]);
+    assert_eq!(output.status.code(), Some(2), "Invalid regex should exit 2");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("unclosed `(`"));
+}
+
+#[test]
+fn test_include_requisites() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--include-requisites"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "bash\n");
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn test_output_file() {
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-output-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let file = dir.join("nested/pkgs.txt");
+    let file_arg = file.to_str().unwrap();
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+
+    let output = run_with_store_path(&dirs, &["--output", file_arg]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty(), "Nothing should go to stdout");
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "git\n");
+
+    // An empty result keeps the previous snapshot...
+    let output = run_with_store_path(&[], &["--output", file_arg]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "git\n");
+
+    // ...unless asked to write it anyway
+    let output = run_with_store_path(&[], &["--output", file_arg, "--write-empty"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "");
+
+    let output = run_with_store_path(&[], &["--write-empty"]);
+    assert_eq!(output.status.code(), Some(2), "--write-empty needs --output");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_strip_version_always() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+
+    let output = run_with_store_path(&dirs, &["--format-template", "{name}@{version}", "--strip-version-always"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git@\n");
+
+    let output = run_with_store_path(&dirs, &["--format", "ndjson", "--strip-version-always"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains(r#""version":"""#), "Version should be empty: {stdout}");
+}
+
+#[test]
+fn test_only_from_file() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+    let list = write_temp("only-from.txt", "# curated\nripgrep\n\njq\n");
+
+    let output = run_with_store_path(&dirs, &["--only-from", list.to_str().unwrap()]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ripgrep\n");
+
+    let output = run_with_store_path(&dirs, &["--only-from", "/nonexistent/only-from.txt"]);
+    assert_eq!(output.status.code(), Some(2), "Missing allowlist should exit 2");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot read"));
+}
+
+#[test]
+fn test_pretty_table() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+
+    let output = run_with_store_path(&dirs, &["--pretty"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("NAME  VERSION  HASH      PATH"), "No color when piped");
+    assert!(lines.next().unwrap().starts_with("git   2.40.1   11111111  /nix/store/"));
+
+    let output = run_with_store_path(&dirs, &["--format", "table", "--color", "always"]);
+    assert!(output.stdout.starts_with(b"\x1b[1mNAME"));
+
+    let output = store_path_command(&dirs).env("CLICOLOR_FORCE", "1").arg("--pretty").output().unwrap();
+    assert!(output.stdout.starts_with(b"\x1b[1mNAME"), "CLICOLOR_FORCE colors a pipe");
+    let output = store_path_command(&dirs)
+        .env("CLICOLOR_FORCE", "1")
+        .args(["--pretty", "--color", "never"])
+        .output()
+        .unwrap();
+    assert!(output.stdout.starts_with(b"NAME"), "--color beats CLICOLOR_FORCE");
+
+    let output = run_with_store_path(&dirs, &["--color", "sometimes"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_profile() {
+    use std::os::unix::fs::symlink;
+
+    let profile = env::temp_dir().join(format!("nix-path-pkgs-profile-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&profile);
+    std::fs::create_dir_all(profile.join("bin")).unwrap();
+    // buildEnv-style links; targets needn't exist
+    for (link, target) in [
+        ("git", "/nix/store/11111111111111111111111111111111-git-2.40.1/bin/git"),
+        ("git-shell", "/nix/store/11111111111111111111111111111111-git-2.40.1/bin/git-shell"),
+        ("rg", "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin/rg"),
+    ] {
+        symlink(target, profile.join("bin").join(link)).unwrap();
+    }
+
+    // PATH only has the stub nix; the packages come from the profile
+    let output = run_with_store_path(&[], &["--profile", profile.to_str().unwrap()]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep\n");
+
+    let output = run_with_store_path(&[], &["--profile", "/nonexistent/profile"]);
+    assert_eq!(output.status.code(), Some(2), "Missing profile should exit 2");
+
+    let _ = std::fs::remove_dir_all(&profile);
+}
+
+#[test]
+fn test_all_profiles() {
+    use std::os::unix::fs::symlink;
+
+    let root = env::temp_dir().join(format!("nix-path-pkgs-all-profiles-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    for (profile, link, target) in [
+        ("default", "git", "/nix/store/11111111111111111111111111111111-git-2.39.0/bin/git"),
+        ("default", "jq", "/nix/store/22222222222222222222222222222222-jq-1.7/bin/jq"),
+        ("user", "git", "/nix/store/33333333333333333333333333333333-git-2.40.1/bin/git"),
+    ] {
+        std::fs::create_dir_all(root.join(profile).join("bin")).unwrap();
+        symlink(target, root.join(profile).join("bin").join(link)).unwrap();
+    }
+    let profiles = ["default", "missing", "user"].map(|p| root.join(p).display().to_string()).join(" ");
+
+    let output = store_path_command(&[])
+        .env("NIX_PROFILES", &profiles)
+        .args(["--all-profiles", "--format", "paths"])
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    // The user profile comes last in NIX_PROFILES, so its git wins, as on PATH
+    assert!(stdout.starts_with("git\t/nix/store/33333333333333333333333333333333-git-2.40.1\n"), "{stdout}");
+    assert!(stdout.contains("jq\t") && stdout.lines().count() == 2);
+
+    let output = store_path_command(&[]).env_remove("NIX_PROFILES").arg("--all-profiles").output().unwrap();
+    assert_eq!(output.status.code(), Some(2), "Unset NIX_PROFILES should exit 2");
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_profile_auto() {
+    use std::os::unix::fs::symlink;
+
+    let home = env::temp_dir().join(format!("nix-path-pkgs-auto-profile-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&home);
+    std::fs::create_dir_all(home.join(".nix-profile/bin")).unwrap();
+    symlink(
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin/git",
+        home.join(".nix-profile/bin/git"),
+    )
+    .unwrap();
+    let run = |home: &std::path::Path| {
+        store_path_command(&[])
+            .env("HOME", home)
+            .args(["--profile", "auto", "--verbose"])
+            .output()
+            .unwrap()
+    };
+
+    let output = run(&home);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("profile search order: ") && stderr.contains(".nix-profile"), "{stderr}");
+    // A real system profile on the test machine would win on Linux
+    if !std::path::Path::new("/run/current-system/sw/bin").exists() {
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+        assert!(stderr.contains(&format!("using profile {}", home.join(".nix-profile").display())));
+
+        let output = run(&home.join("empty"));
+        assert_eq!(output.status.code(), Some(2), "No profile anywhere should exit 2");
+    }
+
+    let _ = std::fs::remove_dir_all(&home);
+}
+
+#[test]
+fn test_profile_generation() {
+    use std::os::unix::fs::symlink;
+
+    // profiles/home -> home-2-link; each generation is a buildEnv with bin/ links
+    let root = env::temp_dir().join(format!("nix-path-pkgs-generations-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    for (generation, target) in [
+        ("gen1", "/nix/store/11111111111111111111111111111111-git-2.39.0/bin/git"),
+        ("gen2", "/nix/store/22222222222222222222222222222222-git-2.40.1/bin/git"),
+    ] {
+        std::fs::create_dir_all(root.join(generation).join("bin")).unwrap();
+        symlink(target, root.join(generation).join("bin/git")).unwrap();
+    }
+    let profiles = root.join("profiles");
+    std::fs::create_dir_all(&profiles).unwrap();
+    symlink(root.join("gen1"), profiles.join("home-1-link")).unwrap();
+    symlink(root.join("gen2"), profiles.join("home-2-link")).unwrap();
+    symlink("home-2-link", profiles.join("home")).unwrap();
+    // ~/.nix-profile style: one more hop in front
+    symlink(profiles.join("home"), root.join("nix-profile")).unwrap();
+    let profile = root.join("nix-profile");
+    let profile = profile.to_str().unwrap();
+
+    let output = run_with_store_path(&[], &["--profile", profile, "--format", "paths"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("-git-2.40.1"));
+    let output = run_with_store_path(&[], &["--profile", profile, "--generation", "1", "--format", "paths"]);
+    assert!(String::from_utf8_lossy(&output.stdout).contains("-git-2.39.0"));
+
+    let output = run_with_store_path(&[], &["--profile", profile, "--generation", "9"]);
+    assert_eq!(output.status.code(), Some(2), "Missing generation should exit 2");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no generation 9 (have: 1, 2)"));
+
+    let output = run_with_store_path(&[], &["--profile", root.join("gen1").to_str().unwrap(), "--generation", "1"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run_with_store_path(&[], &["--generation", "1"]);
+    assert_eq!(output.status.code(), Some(2), "--generation needs --profile");
+
+    let output = run_with_store_path(&[], &["profile-diff", "1", "2", "--profile", profile]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "~git (2.39.0 -> 2.40.1)\n");
+    let output = run_with_store_path(&[], &["profile-diff", "2", "2", "--profile", profile]);
+    assert_eq!(output.status.code(), Some(1), "Same generation: nothing changed");
+    let output = run_with_store_path(&[], &["profile-diff", "1", "9", "--profile", profile]);
+    assert_eq!(output.status.code(), Some(2), "Missing generation should exit 2");
+    let output = run_with_store_path(&[], &["profile-diff", "1"]);
+    assert_eq!(output.status.code(), Some(2), "Needs two generations");
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_baseline_drift() {
+    let file = env::temp_dir().join(format!("nix-path-pkgs-baseline-{}/pkgs.json", std::process::id()));
+    let file_arg = file.to_str().unwrap();
+    let before = [
+        "/nix/store/11111111111111111111111111111111-git-2.39.0/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+    let after = [
+        "/nix/store/33333333333333333333333333333333-git-2.40.1/bin",
+        "/nix/store/44444444444444444444444444444444-fd-9.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&before, &["--baseline", file_arg]);
+    assert_eq!(output.status.code(), Some(2), "Missing baseline should exit 2");
+
+    let output = run_with_store_path(&before, &["--baseline", file_arg, "--update-baseline"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(std::fs::read_to_string(&file).unwrap().starts_with("[\n{\"name\":\"git\""));
+
+    let output = run_with_store_path(&before, &["--baseline", file_arg]);
+    assert_eq!(output.status.code(), Some(0), "Unchanged PATH matches the baseline");
+    assert!(output.stdout.is_empty());
+
+    let output = run_with_store_path(&after, &["--baseline", file_arg]);
+    assert_eq!(output.status.code(), Some(3), "Drift should exit 3");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "~git (2.39.0 -> 2.40.1)\n-ripgrep\n+fd\n");
+
+    let _ = std::fs::remove_dir_all(file.parent().unwrap());
+}
+
+#[test]
+fn test_require_min_version() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.39.0/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--require", "git=2.40"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ripgrep\n");
+
+    let output = run_with_store_path(&dirs, &["--min-version", "git=2.39"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep\n");
+
+    let output = run_with_store_path(&dirs, &["--require", "git"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_print_paths() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--print-paths"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "git\t/nix/store/11111111111111111111111111111111-git-2.40.1\n\
+         ripgrep\t/nix/store/22222222222222222222222222222222-ripgrep-14.0.0\n"
+    );
+}
+
+#[test]
+fn test_fail_on_shadow() {
+    let clean = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+    let output = run_with_store_path(&clean, &["--fail-on-shadow"]);
+    assert_eq!(output.status.code(), Some(0), "Clean PATH exits 0");
+
+    let shadowed = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+        "/nix/store/33333333333333333333333333333333-git-2.39.0/bin",
+        "/nix/store/44444444444444444444444444444444-ripgrep-13.0.0/bin",
+    ];
+    let output = run_with_store_path(&shadowed, &["--fail-on-shadow", "--first", "1"]);
+    assert_eq!(output.status.code(), Some(3), "Conflicts exit 3");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "Normal output is unchanged");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("git shadowed: 2.40.1, 2.39.0"), "Got: {stderr}");
+    assert!(stderr.contains("ripgrep shadowed: 14.0.0, 13.0.0"), "Walk continues past --first");
+}
+
+#[test]
+fn test_non_utf8_path_entry() {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut path = fake_nix_dir().into_os_string().into_vec();
+    path.extend_from_slice(b":/nix/store/11111111111111111111111111111111-git-2.40.1/bin");
+    path.extend_from_slice(b":/nix/store/22222222222222222222222222222222-bad\xff-1.0/bin");
+    path.extend_from_slice(b":/nix/store/33333333333333333333333333333333-ripgrep-14.0.0/bin");
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", OsString::from_vec(path))
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep\n", "Valid entries survive");
+}
+
+#[test]
+fn test_serve_mode() {
+    use std::io::{Read, Write};
+    use std::os::unix::net::UnixStream;
+
+    let sock = env::temp_dir().join(format!("nix-path-pkgs-serve-{}.sock", std::process::id()));
+    let nix_log = env::temp_dir().join(format!("nix-path-pkgs-serve-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&nix_log);
+    // The fake nix, logging each call
+    let nix = stub_nix_dir(
+        "logging-nix",
+        &format!("echo \"$*\" >> {}\nexec {}/nix \"$@\"\n", nix_log.display(), fake_nix_dir().display()),
+    );
+    // A dead server's socket is taken over
+    let _ = std::fs::remove_file(&sock);
+    drop(std::os::unix::net::UnixListener::bind(&sock).unwrap());
+    let mut server = Command::new(get_binary_path())
+        .args(["--serve".as_ref(), sock.as_os_str()])
+        .env("PATH", &nix)
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .spawn()
+        .expect("Failed to execute binary");
+
+    let connect = || {
+        for _ in 0..100 {
+            if let Ok(s) = UnixStream::connect(&sock) {
+                return s;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        }
+        panic!("Server socket never came up");
+    };
+
+    // Client-supplied PATH
+    let mut stream = connect();
+    stream
+        .write_all(
+            b"/nix/store/00000000000000000000000000000000-bash-5.2/bin:\
+              /nix/store/11111111111111111111111111111111-git-2.40.1/bin\n",
+        )
+        .unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "git\n");
+
+    // A client hanging up early must not take the server down
+    drop(connect());
+    let mut stream = connect();
+    stream.write_all(b"/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin\n").unwrap();
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply).unwrap();
+    assert_eq!(reply, "ripgrep\n");
+
+    // A live one isn't
+    let output = Command::new(get_binary_path()).args(["--serve".as_ref(), sock.as_os_str()]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("already being served"));
+
+    // SIGTERM: the socket is cleaned up, not left for the next run to trip on
+    unsafe extern "C" {
+        fn kill(pid: i32, sig: i32) -> i32;
+    }
+    // SAFETY: signals our own child, which is still running.
+    assert_eq!(unsafe { kill(server.id() as i32, 15) }, 0);
+    let status = server.wait().unwrap();
+    assert_eq!(status.code(), Some(143));
+    assert!(!sock.exists(), "Socket should be removed on SIGTERM");
+
+    // TTL=0: one requisites eval at startup, not one per connection
+    let evals = std::fs::read_to_string(&nix_log).unwrap_or_default();
+    assert_eq!(evals.lines().filter(|l| l.contains("--json")).count(), 1, "{evals}");
+    let _ = std::fs::remove_file(&nix_log);
+}
+
+#[test]
+fn test_serve_keeps_regular_file() {
+    let file = write_temp("serve-not-a-socket.txt", "notes\n");
+    let output = Command::new(get_binary_path())
+        .args(["--serve".as_ref(), file.as_os_str()])
+        .env("PATH", fake_nix_dir())
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not a socket"));
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), "notes\n", "A regular file must survive --serve");
+}
+
+#[test]
+fn test_first_n() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+        "/nix/store/33333333333333333333333333333333-fd-9.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--first", "1"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+
+    // More than available => everything, still exit 0
+    let output = run_with_store_path(&dirs, &["--first", "10"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep, fd\n");
+
+    let output = run_with_store_path(&[], &["--first", "1"]);
+    assert_eq!(output.status.code(), Some(1), "Nothing to show should still exit 1");
+
+    let output = run_with_store_path(&dirs, &["--first", "0"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_old_schema_cache_is_refreshed() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-schema-{}", std::process::id()));
+    let cache_dir = cache_home.join("nix-path-pkgs");
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    // Pre-schema cache file: bare JSON that would hide git
+    let file = cache_dir
+        .join("c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json");
+    std::fs::write(&file, r#"["/nix/store/11111111111111111111111111111111-git-2.40.1"]"#).unwrap();
+
+    let output = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .output()
+        .expect("Failed to execute binary");
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "Old cache should be ignored");
+    let rewritten = std::fs::read(&file).unwrap();
+    assert!(rewritten.starts_with(b"nix-path-pkgs cache v1\n"), "Cache should be rewritten");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_default_ignore_tier() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-ncurses-6.4/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.40.1/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+
+    let output = run_with_store_path(&dirs, &["--no-default-ignore"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ncurses, git\n");
+
+    // Env list replaces the built-in one
+    let output = store_path_command(&dirs)
+        .env("NIX_PATH_PKGS_DEFAULT_IGNORE", "git")
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ncurses\n");
+}
+
+#[test]
+fn test_no_home_does_not_cache_in_cwd() {
+    let cwd = env::temp_dir().join(format!("nix-path-pkgs-nohome-{}", std::process::id()));
+    std::fs::create_dir_all(&cwd).unwrap();
+
+    let output = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env_remove("HOME")
+        .env_remove("XDG_CACHE_HOME")
+        .current_dir(&cwd)
+        .output()
+        .expect("Failed to execute binary");
+
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("caching disabled"));
+    assert!(!cwd.join(".cache").exists(), "Cache must not be written relative to CWD");
+
+    let _ = std::fs::remove_dir_all(&cwd);
+}
+
+#[test]
+fn test_ndjson_format() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-rustup/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--format", "ndjson"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        concat!(
+            r#"{"name":"git","version":"2.40.1","hash":"11111111111111111111111111111111","path":"/nix/store/11111111111111111111111111111111-git-2.40.1"}"#,
+            "\n",
+            r#"{"name":"rustup","version":"","hash":"22222222222222222222222222222222","path":"/nix/store/22222222222222222222222222222222-rustup"}"#,
+            "\n",
+        )
+    );
+
+    let output = run_with_store_path(&[], &["--format", "ndjson"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty(), "No packages => no lines");
+
+    let output = run_with_store_path(&dirs, &["--format", "bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_json_format() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let git = r#"{"name":"git","version":"2.40.1","hash":"11111111111111111111111111111111","path":"/nix/store/11111111111111111111111111111111-git-2.40.1"}"#;
+
+    let output = run_with_store_path(&dirs, &["--format", "json"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("[{git}]\n"));
+
+    let output = run_with_store_path(&dirs, &["--format", "json", "--json-pretty"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("[\n  {\n    \"name\": \"git\",\n"));
+    assert!(stdout.ends_with("  }\n]\n"), "Exactly one trailing newline");
+
+    let output = run_with_store_path(&dirs, &["--format", "ndjson", "--json-pretty"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("{\n  \"name\": \"git\",\n"));
+    let output = run_with_store_path(&dirs, &["--format", "ndjson", "--json-pretty", "--json-compact"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{git}\n"));
+
+    let output = run_with_store_path(&[], &["--format", "json"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(output.stdout, b"[]\n", "Empty result is still valid JSON");
+
+    let output = run_with_store_path(&dirs, &["--json-pretty"]);
+    assert_eq!(output.status.code(), Some(2), "--json-pretty needs a JSON format");
+}
+
+#[test]
+fn test_format_template() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--format-template", "{name}@{version}"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git@2.40.1, ripgrep@14.0.0\n");
+
+    // Unknown placeholders fail at startup
+    let output = run_with_store_path(&dirs, &["--format-template", "{name} {nope}"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_flake_not_found() {
+    let nix = stub_nix_dir(
+        "no-flake",
+        "echo \"error: cannot find flake 'flake:nixpkgs' in the flake registries\" >&2\nexit 1\n",
+    );
+
+    for ttl in ["0", "3600"] {
+        let output = Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", ttl)
+            .output()
+            .expect("Failed to execute binary");
+
+        assert_eq!(output.status.code(), Some(2), "Missing flake should exit 2 (TTL={ttl})");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("flake not in registry"), "Got: {stderr}");
+        assert!(!stderr.contains("panicked"));
+    }
+}
+
+#[test]
+fn test_flakes_disabled() {
+    let nix = stub_nix_dir(
+        "flakes-off",
+        "echo \"error: experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it\" >&2\nexit 1\n",
+    );
+
+    for ttl in ["0", "3600"] {
+        let output = Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", ttl)
+            .output()
+            .expect("Failed to execute binary");
+
+        assert_eq!(output.status.code(), Some(2), "Flakes off should exit 2 (TTL={ttl})");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("experimental-features = nix-command flakes"), "Got: {stderr}");
+        assert!(!stderr.contains("panicked"));
+    }
+}
+
+#[test]
+fn test_eval_not_a_list() {
+    let nix = stub_nix_dir(
+        "object-json",
+        "case \"$*\" in\n\
+           *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+           *) printf '{\"out\":\"/nix/store/11111111111111111111111111111111-git-2.40.1\"}' ;;\n\
+         esac\n",
+    );
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .output()
+        .expect("Failed to execute binary");
+
+    assert_eq!(output.status.code(), Some(2), "Object-shaped eval output should exit 2");
+    assert!(output.stdout.is_empty(), "No list from a garbage ignore set");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("didn't evaluate to a list of store paths"));
+
+    let file = write_temp("object-ignore.json", r#"{"a":"/nix/store/11111111111111111111111111111111-git-2.40.1"}"#);
+    let output = run_with_store_path(&[], &["--ignore-file", file.to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_sort_by_size() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-sizes-{}", std::process::id()));
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-fd-9.0.0/bin",
+        "/nix/store/33333333333333333333333333333333-ripgrep-14.0.0/bin",
+    ];
+
+    let output = store_path_command(&dirs)
+        .args(["--sort-by", "size"])
+        .env("XDG_CACHE_HOME", &cache_home)
+        .output()
+        .expect("Failed to execute binary");
+
+    // Unknown size (fd) falls back to the end
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ripgrep, git, fd\n");
+
+    let cached = std::fs::read_to_string(cache_home.join("nix-path-pkgs/closure-sizes")).unwrap();
+    assert!(cached.contains("11111111111111111111111111111111 100\n"));
+    assert!(cached.contains("33333333333333333333333333333333 300\n"));
+
+    // --first cuts the sorted list, not PATH order
+    let output = store_path_command(&dirs)
+        .args(["--sort-by", "size", "--first", "1"])
+        .env("XDG_CACHE_HOME", &cache_home)
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ripgrep\n");
+    let output = store_path_command(&dirs)
+        .args(["--sort-by", "name", "--first", "1"])
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "fd\n");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_human_ttl_units() {
+    let git = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-ttl-{}", std::process::id()));
+
+    for (ttl, warns) in [("30m", false), ("2h", false), ("1d", false), ("soon", true)] {
+        let output = store_path_command(&git)
+            .env("NIX_PATH_PKGS_CACHE_TTL", ttl)
+            .env("XDG_CACHE_HOME", &cache_home)
+            .output()
+            .expect("Failed to execute binary");
+
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(stderr.contains("invalid cache TTL"), warns, "TTL {ttl}: {stderr}");
+    }
+
+    // --ttl wins over the env var
+    let output = store_path_command(&git)
+        .args(["--ttl", "0"])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "soon")
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.stderr.is_empty());
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_refresh_rewrites_cache() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-refresh-{}", std::process::id()));
+    let cache_dir = cache_home.join("nix-path-pkgs");
+    std::fs::create_dir_all(&cache_dir).unwrap();
+
+    // Fresh, current-schema cache that hides git
+    let file = cache_dir
+        .join("c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json");
+    std::fs::write(
+        &file,
+        "nix-path-pkgs cache v1\n[\"/nix/store/11111111111111111111111111111111-git-2.40.1\"]",
+    )
+    .unwrap();
+
+    let run = |args: &[&str], refresh_env: Option<&str>| {
+        let mut cmd = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"]);
+        cmd.args(args)
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &cache_home);
+        if let Some(v) = refresh_env {
+            cmd.env("NIX_PATH_PKGS_REFRESH", v);
+        }
+        cmd.output().expect("Failed to execute binary")
+    };
+
+    assert_eq!(run(&[], None).status.code(), Some(1), "Cache hit should hide git");
+    assert_eq!(String::from_utf8_lossy(&run(&["--refresh"], None).stdout), "git\n");
+
+    // The refreshed result was written back, so a plain run now sees it too
+    assert_eq!(String::from_utf8_lossy(&run(&[], None).stdout), "git\n");
+    assert!(!std::fs::read_to_string(&file).unwrap().contains("11111111111111111111111111111111"));
+
+    std::fs::write(
+        &file,
+        "nix-path-pkgs cache v1\n[\"/nix/store/11111111111111111111111111111111-git-2.40.1\"]",
+    )
+    .unwrap();
+    assert_eq!(String::from_utf8_lossy(&run(&[], Some("1")).stdout), "git\n");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_verbose_summary() {
+    let dirs = [
+        "/usr/bin",
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.39.0/bin",
+        "/nix/store/33333333333333333333333333333333-ghostty-1.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--verbose"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "stdout stays clean");
+    // The stub nix dir is the first PATH entry
+    assert_eq!(
+        String::from_utf8_lossy(&output.stderr).lines().last(),
+        Some("scanned=6 kept=1 skipped=1 ignored=1 duplicates=1 cache=off")
+    );
+}
+
+#[test]
+fn test_verbose_reports_missing_cache_key() {
+    // Key eval fails, requisites eval works: caching silently can't happen
+    let nix = stub_nix_dir(
+        "no-key",
+        "case \"$*\" in\n\
+           *--json*) printf '[]' ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-no-key-{}", std::process::id()));
+    let run = |args: &[&str]| {
+        Command::new(get_binary_path())
+            .args(args)
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &cache_home)
+            .output()
+            .expect("Failed to execute binary")
+    };
+
+    let output = run(&["--verbose"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "Fallback behavior is unchanged");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("couldn't determine the cache key"), "Got: {stderr}");
+
+    let output = run(&[]);
+    assert!(output.stderr.is_empty(), "Only reported under --verbose");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_failure_is_cached_briefly() {
+    // Key eval works, requisites eval fails (and logs each attempt)
+    let nix = stub_nix_dir(
+        "broken-eval",
+        "case \"$*\" in\n\
+           *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+           *--json*) echo eval >> \"$NIX_STUB_LOG\"; echo 'error: evaluation aborted' >&2; exit 1 ;;\n\
+         esac\n",
+    );
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-broken-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_home);
+    std::fs::create_dir_all(&cache_home).unwrap();
+    let log = cache_home.join("evals.log");
+    let run_with = |envs: &[(&str, &str)], args: &[&str]| {
+        Command::new(get_binary_path())
+            .args(args)
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &cache_home)
+            .env("NIX_STUB_LOG", &log)
+            .envs(envs.iter().copied())
+            .output()
+            .expect("Failed to execute binary")
+    };
+    let run = |envs: &[(&str, &str)]| run_with(envs, &[]);
+    let evals = || std::fs::read_to_string(&log).unwrap_or_default().lines().count();
+
+    assert!(!run(&[]).status.success());
+    assert_eq!(evals(), 1);
+
+    // Within the window: fail fast with the remembered error, no spawn
+    let output = run(&[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("evaluation aborted"));
+    assert_eq!(evals(), 1, "Recent failure should skip nix eval");
+
+    // Window disabled => tries again
+    assert!(!run(&[("NIX_PATH_PKGS_FAILURE_TTL", "0")]).status.success());
+    assert_eq!(evals(), 2);
+
+    // A failure is only replayed for the same inputs: another flake or
+    // filter set still gets its own eval
+    assert!(!run_with(&[], &["--flake", "github:other/flake"]).status.success());
+    assert_eq!(evals(), 3, "Another flake's failure shouldn't block this one");
+    assert!(!run(&[]).status.success());
+    assert_eq!(evals(), 4, "Nor the other way round");
+    assert!(!run_with(&[], &["--filter-set", "bootstrap"]).status.success());
+    assert_eq!(evals(), 5);
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_nix_failure_exit_codes() {
+    let git = "/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+
+    // No nix at all
+    let output = Command::new(get_binary_path())
+        .env("PATH", git)
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(output.status.code(), Some(127), "Missing nix should exit 127");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("`nix` not found on PATH; is nix installed?"));
+
+    // ...every time, not replayed from the failure cache as a plain nix error
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-no-nix-{}", std::process::id()));
+    for _ in 0..2 {
+        let output = Command::new(get_binary_path())
+            .env("PATH", git)
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &cache_home)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(127));
+    }
+    let _ = std::fs::remove_dir_all(&cache_home);
+
+    // nix that hangs past the timeout
+    let nix = stub_nix_dir("slow", "while :; do :; done\n"); // no `sleep` on this PATH
+    let start = std::time::Instant::now();
+    let output = Command::new(get_binary_path())
+        .env("PATH", format!("{}:{git}", nix.display()))
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .env("NIX_PATH_PKGS_NIX_TIMEOUT", "1")
+        .output()
+        .expect("Failed to execute binary");
+    assert_eq!(output.status.code(), Some(4), "Timed out eval should exit 4");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("longer than 1s"));
+    assert!(start.elapsed() < std::time::Duration::from_secs(4), "nix should have been killed");
+}
+
+#[test]
+fn test_superseded_rev_cache_is_pruned() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-prune-{}", std::process::id()));
+    let dir = cache_home.join("nix-path-pkgs");
+    let _ = std::fs::remove_dir_all(&cache_home);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+    let old_rev = dir.join("0000000000000000000000000000000000000000-x86_64-linux-stdenv-allowed-requisites.json");
+    let recent_rev = dir.join("1111111111111111111111111111111111111111-x86_64-linux-stdenv-allowed-requisites.json");
+    let other = dir.join("closure-sizes");
+    for file in [&old_rev, &recent_rev, &other] {
+        std::fs::write(file, "nix-path-pkgs cache v1\n[]").unwrap();
+    }
+    for file in [&old_rev, &other] {
+        std::fs::File::options().write(true).open(file).unwrap().set_modified(two_hours_ago).unwrap();
+    }
+
+    let output = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .output()
+        .expect("Failed to execute binary");
+    assert!(output.status.success());
+
+    assert!(!old_rev.exists(), "Superseded rev past the TTL should be pruned");
+    assert!(recent_rev.exists(), "Superseded rev within the TTL is kept");
+    assert!(other.exists(), "Small dirs skip the plain age sweep");
+    assert!(dir.join("c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json").exists());
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_ignore_file_skips_nix() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+    ];
+    // Both the cache-file format and bare `nix eval --json` output work
+    let cached = write_temp(
+        "ignore-cached.json",
+        "nix-path-pkgs cache v1\n[\"/nix/store/11111111111111111111111111111111-git-2.40.1\"]",
+    );
+    let bare = write_temp("ignore-bare.json", "[\"/nix/store/00000000000000000000000000000000-bash-5.2\"]");
+
+    // No nix on PATH at all
+    let run = |args: &[&str]| {
+        Command::new(get_binary_path())
+            .args(args)
+            .env("PATH", dirs.join(":"))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .output()
+            .expect("Failed to execute binary")
+    };
+
+    let output = run(&["--ignore-file", cached.to_str().unwrap(), "--verbose"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "bash\n");
+    assert!(String::from_utf8_lossy(&output.stderr).ends_with("cache=file\n"));
+
+    let output = run(&["--ignore-file", bare.to_str().unwrap()]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+
+    let output = run(&["--ignore-file", "/nonexistent/ignore.json"]);
+    assert_eq!(output.status.code(), Some(2), "A missing --ignore-file never falls back to nix");
+}
+
+#[test]
+fn test_metrics() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ghostty-1.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--metrics"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("# TYPE nix_path_pkgs_total gauge\nnix_path_pkgs_total 1\n"), "Got: {stdout}");
+    assert!(stdout.contains("\nnix_path_pkgs_skipped 1\n"));
+    assert!(stdout.contains("\nnix_path_pkgs_ignored 1\n"));
+    assert!(stdout.contains("\nnix_path_pkgs_cache_hit 0\n"), "TTL=0 never hits");
+    assert!(!stdout.contains("git"), "No package list");
+}
+
+#[test]
+fn test_warm_subcommand() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-warm-{}", std::process::id()));
+    let file = cache_home
+        .join("nix-path-pkgs/c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json");
+    let run = |args: &[&str]| {
+        store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+            .args(args)
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &cache_home)
+            .output()
+            .expect("Failed to execute binary")
+    };
+
+    let output = run(&["warm"]);
+    assert!(output.status.success());
+    assert!(output.stdout.is_empty(), "warm prints no package list");
+    assert!(file.exists());
+
+    // Fresh cache is respected (stale marker survives) unless --force
+    let marked = "nix-path-pkgs cache v1\n[]";
+    std::fs::write(&file, marked).unwrap();
+    assert!(run(&["warm"]).status.success());
+    assert_eq!(std::fs::read_to_string(&file).unwrap(), marked);
+
+    assert!(run(&["warm", "--force"]).status.success());
+    assert_ne!(std::fs::read_to_string(&file).unwrap(), marked);
+
+    assert_eq!(run(&["--force"]).status.code(), Some(2), "--force needs warm");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_cache_fallback_when_unwritable() {
+    let root = env::temp_dir().join(format!("nix-path-pkgs-ro-cache-{}", std::process::id()));
+    let tmp = root.join("tmp");
+    std::fs::create_dir_all(&tmp).unwrap();
+    // A file where the cache dir should be: creating it fails even as root.
+    let blocked = root.join("blocked");
+    std::fs::write(&blocked, "").unwrap();
+
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let run = || {
+        store_path_command(&dirs)
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &blocked)
+            .env("TMPDIR", &tmp)
+            .env("USER", "tester")
+            .arg("--verbose")
+            .output()
+            .unwrap()
+    };
+
+    let output = run();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("cache=miss") && stderr.contains("nix-path-pkgs-tester"), "{stderr}");
+    assert!(tmp.join("nix-path-pkgs-tester").read_dir().unwrap().next().is_some());
+
+    // The next run finds it there
+    let output = run();
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cache=hit"));
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_group_by_output() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-foo-dev/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.40.1/bin",
+        "/nix/store/33333333333333333333333333333333-foo/bin",
+        "/nix/store/44444444444444444444444444444444-foo-man/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo-dev, git, foo, foo-man\n", "Flat by default");
+
+    let output = run_with_store_path(&dirs, &["--group-by", "output"]);
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo [dev, out, man], git\n");
+
+    let output = run_with_store_path(&dirs, &["--group-by", "output", "--format", "ndjson"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run_with_store_path(&dirs, &["--group-by", "name"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_format_env_default() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let run = |format: &str, args: &[&str]| {
+        store_path_command(&dirs).env("NIX_PATH_PKGS_FORMAT", format).args(args).output().unwrap()
+    };
+
+    let output = run("paths", &[]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "git\t/nix/store/11111111111111111111111111111111-git-2.40.1\n"
+    );
+
+    let output = run("paths", &["--format", "plain"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "--format beats the env var");
+
+    let output = run("yaml", &[]);
+    assert!(output.status.success(), "Bad env value isn't fatal");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("NIX_PATH_PKGS_FORMAT"));
+}
+
+#[test]
+fn test_quiet() {
+    let nix = stub_nix_dir(
+        "quiet",
+        "case \"$1\" in\n\
+           --quiet) echo \"error: cannot find flake 'flake:nixpkgs' in the flake registries\" >&2; exit 1 ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let run = |args: &[&str]| {
+        Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+            .env("NIX_PATH_PKGS_FORMAT", "bogus")
+            .args(args)
+            .output()
+            .expect("Failed to execute binary")
+    };
+
+    // The stub only fails as "bad flake" when handed --quiet
+    let output = run(&["--quiet", "--verbose"]);
+    assert_eq!(output.status.code(), Some(2), "Exit code is unchanged");
+    assert!(output.stderr.is_empty(), "Got: {}", String::from_utf8_lossy(&output.stderr));
+
+    let output = run(&["-q", "--bogus"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stderr.is_empty());
+
+    let output = run(&[]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("NIX_PATH_PKGS_FORMAT"));
+}
+
+#[test]
+fn test_short_hash() {
+    let dirs = [
+        "/nix/store/1a2b3c4d5f6g7h8i9j0k1l2m3n4p5q6r-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-ripgrep-14.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--short-hash"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git-1a2b3c4d, ripgrep-22222222\n");
+
+    let output = run_with_store_path(&dirs, &["--short-hash-length", "4", "--print-paths"]);
+    assert!(String::from_utf8_lossy(&output.stdout).starts_with("git-1a2b\t/nix/store/1a2b"));
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, ripgrep\n", "Unchanged without the flag");
+
+    for args in [&["--short-hash-length", "0"][..], &["--short-hash-length", "33"], &["--short-hash", "--format", "json"]] {
+        assert_eq!(run_with_store_path(&dirs, args).status.code(), Some(2), "{args:?}");
+    }
+}
+
+#[test]
+fn test_explain_cache() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-explain-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_home);
+    let run = |ttl: &str, args: &[&str]| {
+        store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+            .env("XDG_CACHE_HOME", &cache_home)
+            .env("NIX_PATH_PKGS_CACHE_TTL", ttl)
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    let output = run("3600", &["--explain-cache"]);
+    assert!(output.status.success());
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("key:    c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux\n"), "{stdout}");
+    assert!(stdout.contains("exists: no\n") && stdout.contains("next:   miss (no cache file)\n"));
+    assert!(!stdout.contains("git"), "No package list");
+
+    assert!(run("3600", &[]).status.success());
+    let stdout = String::from_utf8_lossy(&run("3600", &["--explain-cache"]).stdout).into_owned();
+    assert!(stdout.contains("exists: yes\n") && stdout.contains("ttl:    3600s\n") && stdout.ends_with("next:   hit\n"), "{stdout}");
+
+    let stdout = String::from_utf8_lossy(&run("0", &["--explain-cache"]).stdout).into_owned();
+    assert!(stdout.ends_with("next:   miss (caching disabled)\n"));
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_dedup_wrappers() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-foo-wrapped-1.2/bin",
+        "/nix/store/22222222222222222222222222222222-foo-1.2/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo-wrapped, foo\n");
+    let output = run_with_store_path(&dirs, &["--dedup-wrappers"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "foo\n");
+}
+
+#[test]
+fn test_raw_paths() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.39.0/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/33333333333333333333333333333333-ghostty-1.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &["--raw-paths"]);
+    assert!(output.status.success());
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin\n\
+         /nix/store/22222222222222222222222222222222-git-2.39.0/bin\n",
+        "Requisites and skipped names dropped, repeated dirs listed once"
+    );
+
+    let output = run_with_store_path(&dirs[..1], &["--format", "raw-paths"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_case_insensitive() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-Git-2.41.0/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.40.1/bin",
+        "/nix/store/33333333333333333333333333333333-Ghostty-1.0.0/bin",
+    ];
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Git, git, Ghostty\n");
+    let output = run_with_store_path(&dirs, &["--case-insensitive"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "Git\n");
+}
+
+#[test]
+fn test_since_last() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-since-last-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_home);
+    let run = |dirs: &[&str]| {
+        store_path_command(dirs)
+            .env("XDG_CACHE_HOME", &cache_home)
+            .arg("--since-last")
+            .output()
+            .unwrap()
+    };
+    let git = "/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+    let jq = "/nix/store/22222222222222222222222222222222-jq-1.7/bin";
+
+    let output = run(&[git]);
+    assert_eq!(output.status.code(), Some(1), "First run only records");
+    assert!(output.stdout.is_empty());
+
+    let output = run(&[git, jq]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "+jq\n");
+
+    let output = run(&[git, jq]);
+    assert_eq!(output.status.code(), Some(1), "No change => exit 1");
+    assert!(output.stdout.is_empty());
+
+    let output = run(&[jq]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "-git\n");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_json_schema_needs_no_nix() {
+    let output = Command::new(get_binary_path())
+        .env("PATH", "")
+        .arg("--json-schema")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.starts_with("{\n  \"$schema\""), "{stdout}");
+    assert!(stdout.contains("\"additionalProperties\": false"), "{stdout}");
+}
+
+#[test]
+fn test_no_newline() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let output = run_with_store_path(&dirs, &["--no-newline"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git");
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "newline by default");
+
+    // Line-based formats keep their line breaks, so asking otherwise is an error
+    for format in ["json", "ndjson", "paths", "names", "toml"] {
+        let output = run_with_store_path(&dirs, &["--no-newline", "--format", format]);
+        assert_eq!(output.status.code(), Some(2), "{format}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("--no-newline only applies to plain output"));
+    }
+}
+
+#[test]
+fn test_system_override() {
+    // Logs every expression it's asked to evaluate
+    let nix = stub_nix_dir(
+        "system-log",
+        "echo \"$*\" >> \"$NIX_STUB_LOG\"\n\
+         case \"$*\" in\n\
+           *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-aarch64-darwin' ;;\n\
+           *--json*) printf '[]' ;;\n\
+         esac\n",
+    );
+    let log = env::temp_dir().join(format!("nix-path-pkgs-system-{}.log", std::process::id()));
+    let _ = std::fs::remove_file(&log);
+    let output = Command::new(get_binary_path())
+        .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+        .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+        .env("NIX_STUB_LOG", &log)
+        .args(["--system", "aarch64-darwin"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    let calls = std::fs::read_to_string(&log).unwrap();
+    assert!(calls.contains("legacyPackages.${\"aarch64-darwin\"}"), "{calls}");
+    assert!(!calls.contains("currentSystem"), "{calls}");
+    let _ = std::fs::remove_file(&log);
+
+    for bad in ["x86_64", "x86_64-", "X86_64-linux", "x86_64-linux\"", ""] {
+        let output = Command::new(get_binary_path()).args(["--system", bad]).output().unwrap();
+        assert_eq!(output.status.code(), Some(2), "{bad:?}");
+        assert!(String::from_utf8_lossy(&output.stderr).contains("invalid --system"), "{bad:?}");
+    }
+}
+
+#[test]
+fn test_positional_search() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+        "/nix/store/33333333333333333333333333333333-cargo-watch-8.4.0/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["cargo"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cargo, cargo-watch\n");
+
+    let output = run_with_store_path(&dirs, &["--exact", "cargo"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cargo\n");
+
+    let output = run_with_store_path(&dirs, &["jq"]);
+    assert_eq!(output.status.code(), Some(1), "No match => exit 1");
+
+    let output = run_with_store_path(&dirs, &["--exact"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = run_with_store_path(&dirs, &["cargo", "git"]);
+    assert_eq!(output.status.code(), Some(2), "One search term only");
+}
+
+#[test]
+fn test_dump_config() {
+    let output = Command::new(get_binary_path())
+        .env("PATH", "")
+        .env("NIX_PATH_PKGS_CACHE_TTL", "2h")
+        .env("NIX_PATH_PKGS_FORMAT", "ndjson")
+        .env_remove("NIX_PATH_PKGS_NIX_ATTEMPTS")
+        .args(["--dump-config", "--flake", "github:NixOS/nixpkgs"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "No nix needed");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    for line in [
+        "format = ndjson  # env NIX_PATH_PKGS_FORMAT\n",
+        "ttl = 7200  # env NIX_PATH_PKGS_CACHE_TTL\n",
+        "flake = github:NixOS/nixpkgs  # flag\n",
+        "nix_attempts = 3  # default\n",
+    ] {
+        assert!(stdout.contains(line), "{line:?} in {stdout}");
+    }
+
+    let output = Command::new(get_binary_path())
+        .env("PATH", "")
+        .env("NIX_PATH_PKGS_CACHE_TTL", "2h")
+        .args(["--dump-config", "--ttl", "0"])
+        .output()
+        .unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("ttl = 0  # flag\n"), "Flag beats env");
+}
+
+#[test]
+fn test_format_env() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-watch-8.4.0/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--format", "env"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "NIX_PKG_GIT=2.40.1\nNIX_PKG_CARGO_WATCH=8.4.0\n");
+}
+
+#[test]
+fn test_nix_output_cap() {
+    // 2 MiB of requisites JSON
+    let nix = stub_nix_dir(
+        "huge-eval",
+        "case \"$*\" in\n\
+           *--json*) s=x; i=0; while [ $i -lt 21 ]; do s=\"$s$s\"; i=$((i+1)); done; printf '[\"%s\"]' \"$s\" ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let run = |max: &str| {
+        Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+            .env("NIX_PATH_PKGS_NIX_MAX_OUTPUT_MB", max)
+            .output()
+            .unwrap()
+    };
+
+    let output = run("1");
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("nix printed more than 1 MiB"), "{stderr}");
+
+    let output = run("0");
+    assert_eq!(output.status.code(), Some(0), "0 => no cap");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+}
+
+#[test]
+fn test_delta_from_login() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-login-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_home);
+    let git_old = "/nix/store/11111111111111111111111111111111-git-2.39.0/bin";
+    let git = "/nix/store/22222222222222222222222222222222-git-2.40.1/bin";
+    let jq = "/nix/store/33333333333333333333333333333333-jq-1.7/bin";
+    let cargo = "/nix/store/44444444444444444444444444444444-cargo-1.75.0/bin";
+    let run = |dirs: &[&str], dev_shell: bool| {
+        let mut cmd = store_path_command(dirs);
+        cmd.env("XDG_CACHE_HOME", &cache_home).env_remove("IN_NIX_SHELL").arg("--delta-from-login");
+        if dev_shell {
+            cmd.env("IN_NIX_SHELL", "impure");
+        }
+        cmd.output().unwrap()
+    };
+
+    let output = run(&[cargo], true);
+    assert_eq!(output.status.code(), Some(2), "No baseline yet");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("outside a dev shell"));
+
+    let output = run(&[git_old, jq], false);
+    assert_eq!(output.status.code(), Some(1), "Login shell only records");
+    assert!(output.stdout.is_empty());
+
+    let output = run(&[cargo, git, jq], true);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "~git (2.39.0 -> 2.40.1)\n+cargo\n");
+
+    let output = run(&[jq], true);
+    assert_eq!(output.status.code(), Some(1), "Nothing added");
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_list_names_only() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-watch-8.4.0/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--list-names-only", "--color", "always"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\ncargo-watch\n");
+    assert!(output.stderr.is_empty());
+
+    let output = run_with_store_path(&[], &["--list-names-only"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_store_root() {
+    // Only answers when pointed at the chroot store
+    let nix = stub_nix_dir(
+        "chroot-store",
+        "case \"$*\" in\n\
+           *--store\\ /mnt*--json*) printf '[\"/nix/store/00000000000000000000000000000000-bash-5.2\"]' ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let run = |args: &[&str]| {
+        Command::new(get_binary_path())
+            .env(
+                "PATH",
+                format!(
+                    "{}:/mnt/nix/store/00000000000000000000000000000000-bash-5.2/bin:/mnt/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+                    nix.display()
+                ),
+            )
+            .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    let output = run(&["--store", "/mnt/"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "bash is the chroot's stdenv");
+
+    for bad in ["mnt", "/mnt:/x"] {
+        assert_eq!(run(&["--store", bad]).status.code(), Some(2), "{bad}");
+    }
+}
+
+#[test]
+fn test_show_rev() {
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    // Caching is off here, so the revision is fetched just for --show-rev
+    let output = run_with_store_path(&dirs, &["--show-rev"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "# nixpkgs c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec\n");
+
+    // JSON output carries it on each object too
+    let output = run_with_store_path(&dirs, &["--show-rev", "--format", "ndjson"]);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.trim_end().ends_with(r#""nixpkgs_rev":"c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec"}"#), "{stdout}");
+    let output = run_with_store_path(&dirs, &["--format", "json"]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("nixpkgs_rev"));
+
+    let ignore = write_temp("show-rev-ignore.json", "[]");
+    let output = run_with_store_path(&dirs, &["--show-rev", "--ignore-file", ignore.to_str().unwrap()]);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("# nixpkgs unknown (--ignore-file)"));
+    let output = run_with_store_path(&dirs, &["--show-rev", "--format", "json", "--ignore-file", ignore.to_str().unwrap()]);
+    assert!(!String::from_utf8_lossy(&output.stdout).contains("nixpkgs_rev"), "unknown revisions are left out");
+}
+
+#[test]
+fn test_skip_versioned() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-python3-3.11.8/bin",
+        "/nix/store/22222222222222222222222222222222-python3-3.12.1/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--skip-versioned", "python3-3.11", "--format", "paths"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("-python3-3.12.1"));
+
+    let output = run_with_store_path(&dirs, &["--skip-versioned", "python3"]);
+    assert_eq!(output.status.code(), Some(2), "Needs a version");
+}
+
+#[test]
+fn test_plain_output_large_list() {
+    let dirs: Vec<String> = (1..=300).map(|i| format!("/nix/store/{:032}-tool{i}-1.0/bin", i)).collect();
+    let dirs: Vec<&str> = dirs.iter().map(String::as_str).collect();
+    let expected: Vec<String> = (1..=300).map(|i| format!("tool{i}")).collect();
+
+    let output = run_with_store_path(&dirs, &[]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n", expected.join(", ")));
+
+    let output = run_with_store_path(&dirs, &["--first", "2", "--no-newline"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "tool1, tool2");
+}
+
+#[test]
+fn test_ignore_self() {
+    // The binary as if installed in a (chroot) store, with git in its closure
+    let root = env::temp_dir().join(format!("nix-path-pkgs-self-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let self_path = "/nix/store/55555555555555555555555555555555-nix-path-pkgs-0.1";
+    let bin = root.join(&self_path[1..]).join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::copy(get_binary_path(), bin.join("nix-path-pkgs")).unwrap();
+    let nix = stub_nix_dir(
+        "self-closure",
+        "case \"$*\" in\n\
+           *path-info*) echo /nix/store/55555555555555555555555555555555-nix-path-pkgs-0.1; \
+                        echo /nix/store/11111111111111111111111111111111-git-2.40.1 ;;\n\
+           *--json*) printf '[]' ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let run = |args: &[&str]| {
+        Command::new(bin.join("nix-path-pkgs"))
+            .env(
+                "PATH",
+                format!(
+                    "{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin:/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+                    nix.display()
+                ),
+            )
+            .env("NIX_PATH_PKGS_CACHE_TTL", "0")
+            .env("XDG_CACHE_HOME", root.join("cache"))
+            .args(["--store", root.to_str().unwrap()])
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    assert_eq!(String::from_utf8_lossy(&run(&[]).stdout), "git, cargo\n");
+    let output = run(&["--ignore-self"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cargo\n", "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(root.join("cache/nix-path-pkgs/self-closure-55555555555555555555555555555555").exists());
+
+    // Not from the store: nothing extra hidden
+    let output = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+        .args(["--ignore-self", "--verbose"])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("not running from the nix store"));
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
+#[test]
+fn test_sort_by_name_ignores_locale() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-apple-1.0/bin",
+        "/nix/store/33333333333333333333333333333333-_x-1.0/bin",
+        "/nix/store/44444444444444444444444444444444-Zed-1.0/bin",
+    ];
+    for locale in ["C", "tr_TR.UTF-8", "sv_SE.ISO-8859-1", "no-such-locale"] {
+        let output = store_path_command(&dirs)
+            .env("LC_ALL", locale)
+            .env("LC_COLLATE", locale)
+            .args(["--sort-by", "name"])
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Zed, _x, apple, git\n", "LC_ALL={locale}");
+    }
+}
+
+#[test]
+fn test_explain_package() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+        "/nix/store/33333333333333333333333333333333-git-2.39.0/bin",
+    ];
+    let output = store_path_command(&dirs).args(["--explain-package", "git"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "kept      git 2.40.1  /nix/store/11111111111111111111111111111111-git-2.40.1\n\
+         duplicate git 2.39.0  /nix/store/33333333333333333333333333333333-git-2.39.0\n"
+    );
+
+    let output = store_path_command(&dirs).args(["--explain-package", "jq"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn test_with_counts() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+        "/nix/store/33333333333333333333333333333333-git-2.39.0/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+    ];
+    let run = |args: &[&str]| {
+        let output = store_path_command(&dirs).arg("--with-counts").args(args).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    };
+    assert_eq!(run(&[]), "git (x3), cargo\n");
+    assert_eq!(run(&["--sort-by", "name"]), "cargo, git (x3)\n");
+
+    let ndjson = run(&["--format", "ndjson"]);
+    let lines: Vec<&str> = ndjson.lines().collect();
+    assert!(lines[0].ends_with(r#""occurrences":3}"#), "{ndjson}");
+    assert!(lines[1].ends_with(r#""occurrences":1}"#), "{ndjson}");
+    assert!(run(&["--format", "json"]).contains(r#""occurrences":3}"#));
+
+    let output = store_path_command(&dirs).args(["--with-counts", "--format", "table"]).output().unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_assume_store_paths_is_literal() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/root/.nix-profile/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+    ];
+    let output = store_path_command(&dirs).arg("--assume-store-paths").output().unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, cargo\n");
+}
+
+#[test]
+fn test_tree() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-tree-{}", std::process::id()));
+    // `nix` here is never reached (the fake one comes first on PATH); the
+    // dir is just somewhere to put a stub `nix-store`.
+    let tools = stub_nix_dir("tree-tools", "exit 1\n");
+    let nix_store = tools.join("nix-store");
+    std::fs::write(
+        &nix_store,
+        "#!/bin/sh\n\
+         case \"$3\" in\n\
+           */nix/store/*-git-*) echo /nix/store/00000000000000000000000000000000-bash-5.2\n\
+              echo /nix/store/44444444444444444444444444444444-curl-8.4.0\n\
+              echo \"$3\" ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&nix_store, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+    ];
+    let run = |tools: &std::path::Path| {
+        let path = format!("{}:{}:{}", fake_nix_dir().display(), tools.display(), dirs.join(":"));
+        store_path_command(&[]).env("PATH", path).env("XDG_CACHE_HOME", &cache_home).arg("--tree").output().unwrap()
+    };
+
+    let output = run(&tools);
+    assert_eq!(output.status.code(), Some(0));
+    let expected = "git 2.40.1\n├── bash-5.2 (ignored)\n└── curl-8.4.0\ncargo 1.75.0\n└── ?\n";
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+    let cached = std::fs::read_to_string(cache_home.join("nix-path-pkgs/references")).unwrap();
+    assert!(cached.starts_with("11111111111111111111111111111111 /nix/store/00000000000000000000000000000000-bash-5.2 "));
+
+    // Cached: git's references no longer need nix-store
+    std::fs::remove_file(&nix_store).unwrap();
+    assert_eq!(String::from_utf8_lossy(&run(&tools).stdout), expected);
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_no_dedup_keeps_repeats_in_path_order() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin",
+        "/nix/store/33333333333333333333333333333333-git-2.39.0/bin",
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--no-dedup"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, cargo, git\n");
+
+    let output = run_with_store_path(&dirs, &["--no-dedup", "--raw-paths"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), format!("{}\n{}\n{}\n", dirs[0], dirs[1], dirs[2]));
+
+    // Still exit 1 when nothing qualifies
+    let output = run_with_store_path(&dirs[3..], &["--no-dedup"]);
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[test]
+fn test_output_on_empty() {
+    let bash = ["/nix/store/00000000000000000000000000000000-bash-5.2/bin"];
+    let output = run_with_store_path(&bash, &["--output-on-empty", "∅"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "∅\n");
+
+    let output = run_with_store_path(&bash, &["--output-on-empty", "no-nix", "--no-newline", "--empty-exit-code", "1"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "no-nix");
+
+    // Only when empty
+    let git = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let output = run_with_store_path(&git, &["--output-on-empty", "∅"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+
+    // The default is unchanged: nothing, exit 1
+    let output = run_with_store_path(&bash, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(output.stdout.is_empty());
+
+    let output = run_with_store_path(&bash, &["--output-on-empty", "x", "--format", "json"]);
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn test_nix_warnings_on_stdout_before_json() {
+    let dir = stub_nix_dir(
+        "noisy-stdout",
+        "case \"$*\" in\n\
+           *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+           *--json*) echo \"warning: Git tree '/etc/nixos' is dirty\"\n\
+              echo 'trace: /nix/store/11111111111111111111111111111111-git-2.40.1'\n\
+              printf '[\"/nix/store/00000000000000000000000000000000-bash-5.2\"]' ;;\n\
+           *) exit 1 ;;\n\
+         esac\n",
+    );
+    let path = format!(
+        "{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin:/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        dir.display()
+    );
+    let output = Command::new(get_binary_path()).env("PATH", path).env("NIX_PATH_PKGS_CACHE_TTL", "0").output().unwrap();
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    // The traced path isn't a requisite; only the JSON counts
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+}
+
+#[test]
+fn test_snapshot_is_sorted_and_reads_back_as_baseline() {
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-snapshot-{}", std::process::id()));
+    let file = dir.join("sub/tools.json");
+    let file_arg = file.to_str().unwrap();
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-ripgrep-14.0.0/bin",
+        "/nix/store/22222222222222222222222222222222-git-2.40.1/bin",
+    ];
+
+    // Output flags don't change the schema
+    let output = run_with_store_path(&dirs, &["snapshot", file_arg, "--format", "table", "--strip-version-always"]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.stdout.is_empty());
+    let text = std::fs::read_to_string(&file).unwrap();
+    assert!(text.starts_with("[\n  {\n    \"name\": \"git\",\n    \"version\": \"2.40.1\","), "{text}");
+    assert!(text.find("\"ripgrep\"").unwrap() > text.find("\"git\"").unwrap());
+
+    let output = run_with_store_path(&dirs, &["--baseline", file_arg]);
+    assert_eq!(output.status.code(), Some(0), "{}", String::from_utf8_lossy(&output.stdout));
+
+    let output = run_with_store_path(&[], &["snapshot"]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_include_exclude_globs() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-watch-8.4.0/bin",
+        "/nix/store/33333333333333333333333333333333-firefox-unwrapped-120.0/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--include", "cargo-*", "--include", "git"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, cargo-watch\n");
+    let output = run_with_store_path(&dirs, &["--exclude", "*-unwrapped"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git, cargo-watch\n");
+
+    let output = run_with_store_path(&dirs, &["--exclude", "[z-a]*"]);
+    assert_eq!(output.status.code(), Some(2));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("--exclude `[z-a]*`: bad range"));
+}
+
+#[test]
+fn test_collapse_prefix() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-cargo-1.75.0/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-watch-8.4.0/bin",
+        "/nix/store/33333333333333333333333333333333-git-2.40.1/bin",
+        "/nix/store/44444444444444444444444444444444-cargo-edit-0.12.2/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--collapse-prefix", "-"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cargo (+2 subcommands), git\n");
+    let output = run_with_store_path(&dirs, &["--collapse-prefix", "-", "--collapse-keep-base"]);
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "cargo, cargo-* (2), git\n");
+
+    for bad in [&["--collapse-prefix", ""][..], &["--collapse-keep-base"], &["--collapse-prefix", "-", "--format", "json"]] {
+        assert_eq!(run_with_store_path(&dirs, bad).status.code(), Some(2), "{bad:?}");
+    }
+}
+
+#[test]
+fn test_cache_bytes_are_reproducible() {
+    let paths: Vec<String> = (1..=9).map(|i| format!("\"/nix/store/{}-pkg{i}-1.0\"", i.to_string().repeat(32))).collect();
+    let dir = stub_nix_dir(
+        "reproducible",
+        &format!(
+            "case \"$*\" in\n\
+               *--raw*) printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+               *--json*) printf '[{}]' ;;\n\
+             esac\n",
+            paths.join(",")
+        ),
+    );
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-reproducible-{}", std::process::id()));
+    let file = cache_home
+        .join("nix-path-pkgs/c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json");
+    let snapshot = || {
+        let output = Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-pkg1-1.0/bin", dir.display()))
+            .env("XDG_CACHE_HOME", &cache_home)
+            .arg("--refresh")
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1), "{}", String::from_utf8_lossy(&output.stderr));
+        std::fs::read(&file).unwrap()
+    };
+
+    let first = snapshot();
+    for _ in 0..3 {
+        assert_eq!(snapshot(), first, "same eval => same cache bytes");
+    }
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_parallel_nix_overlaps_evals_on_cold_cache() {
+    // Each eval marks that it started, then spins (no `sleep` on this PATH)
+    // until the other one has too; "overlap" is only logged if they met.
+    let nix = stub_nix_dir(
+        "parallel",
+        "meet() { : > \"$NIX_STUB_DIR/$1\"; i=0\n\
+           while [ ! -e \"$NIX_STUB_DIR/$2\" ] && [ $i -lt 300000 ]; do i=$((i+1)); done\n\
+           [ -e \"$NIX_STUB_DIR/$2\" ] && echo \"$1 overlap\" >> \"$NIX_STUB_DIR/log\"; }\n\
+         case \"$*\" in\n\
+           *--raw*) meet key eval; printf 'c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux' ;;\n\
+           *--json*) meet eval key; echo eval >> \"$NIX_STUB_DIR/evals\"\n\
+              printf '[\"/nix/store/00000000000000000000000000000000-bash-5.2\"]' ;;\n\
+         esac\n",
+    );
+    let work = env::temp_dir().join(format!("nix-path-pkgs-parallel-work-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&work);
+    std::fs::create_dir_all(&work).unwrap();
+    let run = || {
+        Command::new(get_binary_path())
+            .env("PATH", format!("{}:/nix/store/11111111111111111111111111111111-git-2.40.1/bin", nix.display()))
+            .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", work.join("cache"))
+            .env("NIX_STUB_DIR", &work)
+            .arg("--parallel-nix")
+            .output()
+            .unwrap()
+    };
+
+    let output = run();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n", "{}", String::from_utf8_lossy(&output.stderr));
+    let log = std::fs::read_to_string(work.join("log")).unwrap_or_default();
+    assert!(log.contains("key overlap") && log.contains("eval overlap"), "{log:?}");
+    assert!(work.join("cache/nix-path-pkgs/c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json").exists());
+
+    // Warm now: only the key eval runs
+    for marker in ["key", "eval"] {
+        let _ = std::fs::remove_file(work.join(marker));
+    }
+    let output = run();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert_eq!(std::fs::read_to_string(work.join("evals")).unwrap().lines().count(), 1);
+
+    let _ = std::fs::remove_dir_all(&work);
+}
+
+#[test]
+fn test_cache_file_pinned() {
+    let base = env::temp_dir().join(format!("nix-path-pkgs-cache-file-{}", std::process::id()));
+    let cache_home = base.join("xdg");
+    let pinned = base.join("ci/pinned.json");
+    let _ = std::fs::remove_dir_all(&base);
+    std::fs::create_dir_all(pinned.parent().unwrap()).unwrap();
+    let git = "/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+    std::fs::write(&pinned, "nix-path-pkgs cache v1\n[\"/nix/store/11111111111111111111111111111111-git-2.40.1\"]").unwrap();
+
+    // A pre-seeded file is read as is, even with no nix to compute a key
+    let output = Command::new(get_binary_path())
+        .env("PATH", git)
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .args(["--cache-file", pinned.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "Pinned cache should hide git: {output:?}");
+
+    // Nor is nix asked for a key when it's there
+    let log = base.join("nix.log");
+    let nix = stub_nix_dir("cache-file-log", "echo \"$*\" >> \"$NIX_STUB_LOG\"\nexit 1\n");
+    let output = Command::new(get_binary_path())
+        .env("PATH", format!("{}:{git}", nix.display()))
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .env("NIX_STUB_LOG", &log)
+        .args(["--cache-file", pinned.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    assert!(!log.exists(), "A valid pinned cache needs no nix eval: {:?}", std::fs::read_to_string(&log));
+
+    // Past the TTL it's re-evaluated and written back to the same path
+    let two_hours_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(7200);
+    std::fs::File::options().write(true).open(&pinned).unwrap().set_modified(two_hours_ago).unwrap();
+    let output = store_path_command(&[git])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .args(["--cache-file", pinned.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    let rewritten = std::fs::read_to_string(&pinned).unwrap();
+    assert!(rewritten.contains("00000000000000000000000000000000-bash"), "{rewritten}");
+    assert!(!cache_home.exists(), "Nothing should be written under the cache dir");
+
+    let _ = std::fs::remove_dir_all(&base);
+}
+
+#[test]
+fn test_include_result() {
+    use std::os::unix::fs::symlink;
+
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-result-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    symlink("/nix/store/11111111111111111111111111111111-git-2.40.1", dir.join("result")).unwrap();
+    symlink("/nix/store/22222222222222222222222222222222-cargo-1.75.0", dir.join("result-2")).unwrap();
+    symlink("/tmp", dir.join("result-dev")).unwrap(); // not a store path
+    symlink("/nix/store/33333333333333333333333333333333-jq-1.7", dir.join("other")).unwrap();
+
+    let run = |args: &[&str]| store_path_command(&[]).current_dir(&dir).args(args).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&run(&["--include-result"]).stdout), "git, cargo\n");
+    assert_eq!(run(&[]).status.code(), Some(1), "Only with --include-result");
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_toml_format() {
+    let dirs = [
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+        "/nix/store/22222222222222222222222222222222-cargo-watch-8.4.0/bin",
+    ];
+    let output = run_with_store_path(&dirs, &["--format", "toml"]);
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(stdout.matches("[[package]]\n").count(), 2, "{stdout}");
+    assert!(stdout.contains("name = \"cargo-watch\"\nversion = \"8.4.0\"\n"), "{stdout}");
+
+    let output = run_with_store_path(&dirs, &["--format", "toml", "--output-on-empty", "x"]);
+    assert_eq!(output.status.code(), Some(2), "A placeholder would break the TOML");
+}
+
+#[test]
+fn test_copy_to_clipboard() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = env::temp_dir().join(format!("nix-path-pkgs-copy-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let tool = dir.join("wl-copy");
+    std::fs::write(&tool, format!("#!/bin/sh\nwhile IFS= read -r l; do echo \"$l\"; done > {}\n", dir.join("clipboard").display())).unwrap();
+    std::fs::set_permissions(&tool, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let git = "/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+
+    let path = format!("{}:{}:{git}", dir.display(), fake_nix_dir().display());
+    let output = store_path_command(&[])
+        .env("PATH", &path)
+        .env("WAYLAND_DISPLAY", "wayland-0")
+        .arg("--copy")
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert_eq!(std::fs::read_to_string(dir.join("clipboard")).unwrap(), "git\n");
+
+    // No clipboard tool: a warning, but stdout and the exit code are unchanged
+    let output = run_with_store_path(&[git], &["--copy"]);
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no clipboard tool found"));
+
+    let output = run_with_store_path(&[git], &["--copy", "--output", dir.join("out").to_str().unwrap()]);
+    assert_eq!(output.status.code(), Some(2));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_filter_set() {
+    let dirs = [
+        "/nix/store/00000000000000000000000000000000-bash-5.2/bin",
+        "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+    ];
+    // none: nothing hidden and no nix needed
+    let output = Command::new(get_binary_path())
+        .env("PATH", dirs.join(":"))
+        .args(["--filter-set", "none"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "bash, git\n");
+
+    // bootstrap: cached in a file of its own
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-filter-set-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&cache_home);
+    let output = store_path_command(&dirs)
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .arg("--exclude-stdenv-only")
+        .output()
+        .unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "git\n");
+    let cache_dir = cache_home.join("nix-path-pkgs");
+    let key = "c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux";
+    assert!(cache_dir.join(format!("{key}-stdenv-bootstrap.json")).exists());
+    assert!(!cache_dir.join(format!("{key}-stdenv-allowed-requisites.json")).exists());
+
+    let output = run_with_store_path(&dirs, &["--filter-set", "most"]);
+    assert_eq!(output.status.code(), Some(2));
+    let output = Command::new(get_binary_path()).args(["--dump-config", "--filter-set", "bootstrap"]).output().unwrap();
+    assert!(String::from_utf8_lossy(&output.stdout).contains("filter_set = bootstrap  # flag\n"));
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_dry_run_nix() {
+    let output = Command::new(get_binary_path())
+        .env("PATH", "")
+        .args(["--dry-run-nix", "--flake", "github:NixOS/nixpkgs", "--system", "aarch64-darwin", "--store", "/mnt"])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(0), "No nix needed: {output:?}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!(
+        lines[0],
+        r#"nix --store /mnt eval --impure --raw --expr '"${(builtins.getFlake "github:NixOS/nixpkgs").rev}-${"aarch64-darwin"}"'"#
+    );
+    assert!(lines[1].starts_with("nix --store /mnt eval --impure --json --expr '"), "{stdout}");
+    assert!(stdout.contains("with legacyPackages.${\"aarch64-darwin\"};"), "{stdout}");
+    assert!(stdout.contains("stdenv.allowedRequisites"), "{stdout}");
+
+    let output = Command::new(get_binary_path()).args(["--dry-run-nix", "--filter-set", "none"]).output().unwrap();
+    assert_eq!(String::from_utf8_lossy(&output.stdout).lines().count(), 1, "Only the key eval");
+}
+
+#[test]
+fn test_age_sweep_keeps_state_files() {
+    let cache_home = env::temp_dir().join(format!("nix-path-pkgs-sweep-{}", std::process::id()));
+    let dir = cache_home.join("nix-path-pkgs");
+    let _ = std::fs::remove_dir_all(&cache_home);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // Enough entries for the age sweep, all past the default 1-day retention
+    let two_days_ago = std::time::SystemTime::now() - std::time::Duration::from_secs(2 * 86400);
+    let old_cache = dir.join("c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-aarch64-darwin-stdenv-allowed-requisites.json");
+    let state = ["login-path", "last-run", "closure-sizes", "references"].map(|f| dir.join(f));
+    for file in state.iter().chain([&old_cache]) {
+        std::fs::write(file, "x").unwrap();
+        std::fs::File::options().write(true).open(file).unwrap().set_modified(two_days_ago).unwrap();
+    }
+
+    let output = store_path_command(&["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"])
+        .env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+        .env_remove("NIX_PATH_PKGS_CACHE_RETENTION")
+        .env("XDG_CACHE_HOME", &cache_home)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    assert!(!old_cache.exists(), "Old ignore-set cache should be swept");
+    for file in &state {
+        assert!(file.exists(), "{} isn't a cache and must survive", file.display());
+    }
+
+    let _ = std::fs::remove_dir_all(&cache_home);
+}
+
+#[test]
+fn test_cache_fallback_refuses_foreign_dir() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let root = env::temp_dir().join(format!("nix-path-pkgs-foreign-cache-{}", std::process::id()));
+    let tmp = root.join("tmp");
+    let _ = std::fs::remove_dir_all(&root);
+    std::fs::create_dir_all(&tmp).unwrap();
+    let blocked = root.join("blocked");
+    std::fs::write(&blocked, "").unwrap();
+
+    let dirs = ["/nix/store/11111111111111111111111111111111-git-2.40.1/bin"];
+    let run = |user: Option<&str>| {
+        let mut cmd = store_path_command(&dirs);
+        cmd.env("NIX_PATH_PKGS_CACHE_TTL", "3600")
+            .env("XDG_CACHE_HOME", &blocked)
+            .env("TMPDIR", &tmp)
+            .env_remove("USER")
+            .env_remove("LOGNAME");
+        if let Some(user) = user {
+            cmd.env("USER", user);
+        }
+        cmd.output().unwrap()
+    };
+
+    // No user name: no shared "nix-path-pkgs-" dir
+    assert_eq!(String::from_utf8_lossy(&run(None).stdout), "git\n");
+    assert!(!tmp.join("nix-path-pkgs-").exists());
+
+    // Someone else's private-looking dir with a planted cache that hides git
+    // (needs root to hand the dir to another uid)
+    let planted = tmp.join("nix-path-pkgs-tester");
+    std::fs::create_dir_all(&planted).unwrap();
+    std::fs::set_permissions(&planted, std::fs::Permissions::from_mode(0o700)).unwrap();
+    std::fs::write(
+        planted.join("c12c63cd6c5eb34c7b4c3076c6a99e00fcab86ec-x86_64-linux-stdenv-allowed-requisites.json"),
+        "nix-path-pkgs cache v1\n[\"/nix/store/11111111111111111111111111111111-git-2.40.1\"]",
+    )
+    .unwrap();
+    if std::os::unix::fs::chown(&planted, Some(4242), None).is_ok() {
+        assert_eq!(String::from_utf8_lossy(&run(Some("tester")).stdout), "git\n", "Planted cache must be ignored");
+    }
+
+    let _ = std::fs::remove_dir_all(&root);
+}
+
@@ -1,233 +1,132 @@
-// Unit tests for internal functions
-// Since the functions in main.rs are not pub, we'll test them through
-// a test module that includes the source
-
-#[path = "../src/main.rs"]
-mod main_module;
+// Unit tests for the pure parsing/filtering functions in the library
 
 #[cfg(test)]
 mod tests {
+    use nix_path_pkgs::*;
     use std::collections::HashSet;
 
-    // We need to expose internal functions for testing
-    // For now, we'll test what we can through the module
-
     #[test]
     fn test_hash_and_name_valid_bash() {
-        // This tests the hash_and_name function logic
         // Nix store hashes are exactly 32 characters
-        let dir = "/nix/store/abc123def45678901234567890123456-bash-5.2-p15/bin";
-
-        // We can't call the function directly as it's private, but we can test the logic
-        let expected_hash = "abc123def45678901234567890123456";
-        let expected_name = "bash";
-
-        // Check format
-        assert!(dir.starts_with("/nix/store/"));
-        assert!(dir.len() >= 44);
-        assert_eq!(dir.as_bytes()[43], b'-');
-
-        let hash = &dir[11..43];
-        assert_eq!(hash, expected_hash);
-
-        let rest = &dir[44..];
-        let item = rest.split('/').next().unwrap();
-        assert_eq!(item, "bash-5.2-p15");
-
-        // Find where version starts
-        let bytes = item.as_bytes();
-        let mut cut = item.len();
-        for i in 0..bytes.len() {
-            if bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
-                cut = i;
-                break;
-            }
-        }
-        let name = &item[..cut];
-        assert_eq!(name, expected_name);
+        let dir = "/nix/store/abc123dff45678901234567890123456-bash-5.2-p15/bin";
+
+        assert_eq!(hash_and_item(dir), Some(("abc123dff45678901234567890123456", "bash-5.2-p15")));
+        assert_eq!(hash_and_name(dir), Some(("abc123dff45678901234567890123456", "bash")));
     }
 
     #[test]
     fn test_hash_and_name_valid_git() {
         let dir = "/nix/store/xyz789abc12345678901234567890123-git-2.40.1/bin";
 
-        let hash = &dir[11..43];
+        let (hash, name) = hash_and_name(dir).unwrap();
         assert_eq!(hash.len(), 32);
-
-        let rest = &dir[44..];
-        let item = rest.split('/').next().unwrap();
-        assert_eq!(item, "git-2.40.1");
-
-        let bytes = item.as_bytes();
-        let mut cut = item.len();
-        for i in 0..bytes.len() {
-            if bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
-                cut = i;
-                break;
-            }
-        }
-        assert_eq!(&item[..cut], "git");
+        assert_eq!(name, "git");
+        assert_eq!(split_version(hash_and_item(dir).unwrap().1), ("git", "2.40.1"));
     }
 
     #[test]
     fn test_hash_and_name_with_dash_in_name() {
         let dir = "/nix/store/12345678901234567890123456789012-cargo-watch-8.4.0/bin";
 
-        let rest = &dir[44..];
-        let item = rest.split('/').next().unwrap();
-        assert_eq!(item, "cargo-watch-8.4.0");
-
-        let bytes = item.as_bytes();
-        let mut cut = item.len();
-        for i in 0..bytes.len() {
-            if bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
-                cut = i;
-                break;
-            }
-        }
-        assert_eq!(&item[..cut], "cargo-watch");
+        assert_eq!(hash_and_name(dir).unwrap().1, "cargo-watch");
     }
 
     #[test]
     fn test_hash_and_name_no_version() {
         let dir = "/nix/store/12345678901234567890123456789012-rustup/bin";
 
-        let rest = &dir[44..];
-        let item = rest.split('/').next().unwrap();
-        assert_eq!(item, "rustup");
-
-        let bytes = item.as_bytes();
-        let mut cut = item.len();
-        for i in 0..bytes.len() {
-            if bytes[i] == b'-' && bytes.get(i + 1).is_some_and(|c| c.is_ascii_digit()) {
-                cut = i;
-                break;
-            }
-        }
-        assert_eq!(&item[..cut], "rustup");
+        assert_eq!(hash_and_name(dir).unwrap().1, "rustup");
     }
 
     #[test]
     fn test_hash_and_name_invalid_too_short() {
-        let dir = "/nix/store/short-package/bin";
-
-        // Should fail - not long enough for hash
-        assert!(dir.len() < 44);
+        // Not long enough for hash
+        assert_eq!(hash_and_name("/nix/store/short-package/bin"), None);
     }
 
     #[test]
     fn test_hash_and_name_invalid_no_dash() {
-        let dir = "/nix/store/12345678901234567890123456789012package/bin";
-
-        // Should fail - no dash after hash
-        if dir.len() >= 44 {
-            assert_ne!(dir.as_bytes()[43], b'-');
-        }
+        // No dash after hash
+        assert_eq!(hash_and_name("/nix/store/12345678901234567890123456789012package/bin"), None);
     }
 
     #[test]
     fn test_hash_and_name_invalid_not_nix_store() {
-        let dir = "/usr/local/123456789012345678901234567890123456-package/bin";
-
-        assert!(!dir.starts_with("/nix/store/"));
+        assert_eq!(hash_and_name("/usr/local/123456789012345678901234567890123456-package/bin"), None);
     }
 
     #[test]
     fn test_parse_hashes_json_format() {
-        // Test the JSON parsing logic
-        let json = br#"["/nix/store/abc123def45678901234567890123456-bash-5.2/","/nix/store/xyz78901234567890123456789012345-coreutils-9.1/"]"#;
-
-        let text = std::str::from_utf8(json).unwrap();
-        let mut hashes = HashSet::new();
-        let bytes = text.as_bytes();
-
-        let mut i = 0;
-        while i < bytes.len() {
-            if bytes.get(i..i + 11) == Some(b"/nix/store/") {
-                let hash_start = i + 11;
-                let hash_end = hash_start + 32;
-
-                if hash_end < bytes.len()
-                    && bytes.get(hash_end) == Some(&b'-')
-                    && text.is_char_boundary(hash_start)
-                    && text.is_char_boundary(hash_end)
-                {
-                    hashes.insert(&text[hash_start..hash_end]);
-                    i = hash_end;
-                } else {
-                    i += 1;
-                }
-            } else {
-                i += 1;
-            }
-        }
+        let json = br#"["/nix/store/abc123dff45678901234567890123456-bash-5.2/","/nix/store/xyz78901234567890123456789012345-coreutils-9.1/"]"#;
 
+        let hashes = parse_hashes(json);
         assert_eq!(hashes.len(), 2);
-        assert!(hashes.contains("abc123def45678901234567890123456"));
+        assert!(hashes.contains("abc123dff45678901234567890123456"));
         assert!(hashes.contains("xyz78901234567890123456789012345"));
     }
 
     #[test]
     fn test_parse_hashes_empty() {
-        let json = b"[]";
-        let text = std::str::from_utf8(json).unwrap();
-        let mut hashes = HashSet::new();
-        let bytes = text.as_bytes();
-
-        let mut i = 0;
-        while i < bytes.len() {
-            if bytes.get(i..i + 11) == Some(b"/nix/store/") {
-                let hash_start = i + 11;
-                let hash_end = hash_start + 32;
-
-                if hash_end < bytes.len()
-                    && bytes.get(hash_end) == Some(&b'-')
-                    && text.is_char_boundary(hash_start)
-                    && text.is_char_boundary(hash_end)
-                {
-                    hashes.insert(&text[hash_start..hash_end]);
-                    i = hash_end;
-                } else {
-                    i += 1;
-                }
-            } else {
-                i += 1;
+        assert_eq!(parse_hashes(b"[]").len(), 0);
+    }
+
+    #[test]
+    fn test_hash_scanner_any_chunking() {
+        let json = format!(r#"["{BASH}","{GIT}","/nix/store/33333333333333333333333333333333-é-1.0","{CARGO}"]"#);
+        let whole = parse_hashes(json.as_bytes());
+        assert_eq!(whole.len(), 4);
+        for size in 1..=json.len() {
+            let mut scanner = HashScanner::default();
+            for chunk in json.as_bytes().chunks(size) {
+                scanner.feed(chunk);
             }
+            let hashes = scanner.finish();
+            assert_eq!(hashes.len(), 4, "chunks of {size}");
+            assert!(hashes.contains("33333333333333333333333333333333"), "chunks of {size}");
         }
 
-        assert_eq!(hashes.len(), 0);
+        // Invalid or truncated UTF-8 anywhere => nothing, as with parse_hashes
+        let mut scanner = HashScanner::default();
+        scanner.feed(json.as_bytes());
+        scanner.feed(b"\xff");
+        assert!(scanner.finish().is_empty());
+        let mut scanner = HashScanner::default();
+        scanner.feed(json.as_bytes());
+        scanner.feed("é".as_bytes().split_at(1).0);
+        assert!(scanner.finish().is_empty());
     }
 
     #[test]
     fn test_parse_hashes_malformed() {
-        let json = b"invalid json";
-        let text = std::str::from_utf8(json).unwrap();
-        let mut hashes = HashSet::new();
-        let bytes = text.as_bytes();
-
-        let mut i = 0;
-        while i < bytes.len() {
-            if bytes.get(i..i + 11) == Some(b"/nix/store/") {
-                let hash_start = i + 11;
-                let hash_end = hash_start + 32;
-
-                if hash_end < bytes.len()
-                    && bytes.get(hash_end) == Some(&b'-')
-                    && text.is_char_boundary(hash_start)
-                    && text.is_char_boundary(hash_end)
-                {
-                    hashes.insert(&text[hash_start..hash_end]);
-                    i = hash_end;
-                } else {
-                    i += 1;
-                }
-            } else {
-                i += 1;
-            }
-        }
-
         // Should handle gracefully and return empty
-        assert_eq!(hashes.len(), 0);
+        assert_eq!(parse_hashes(b"invalid json").len(), 0);
+        assert_eq!(parse_hashes(b"\xff\xfe").len(), 0);
+    }
+
+    #[test]
+    fn test_json_payload_skips_leading_noise() {
+        let noisy = b"warning: Git tree '/src' is dirty\ntrace: hi\n  [\"/nix/store/x\"]\n";
+        assert_eq!(json_payload(noisy), b"  [\"/nix/store/x\"]\n");
+        assert_eq!(json_payload(b"{\"a\":1}"), b"{\"a\":1}");
+        assert_eq!(json_payload(b"[]"), b"[]");
+        assert_eq!(json_payload(b"no json here\n"), b"no json here\n");
+        assert_eq!(json_payload(b""), b"");
+    }
+
+    #[test]
+    fn test_is_string_array() {
+        assert!(is_string_array(b"[]"));
+        assert!(is_string_array(b" [ ]\n"));
+        assert!(is_string_array(br#"["/nix/store/a-b", "c\"]d"]"#));
+        assert!(is_string_array(b"[\"x\"]\n"));
+
+        assert!(!is_string_array(br#"{"out":"/nix/store/abc123dff45678901234567890123456-bash-5.2"}"#));
+        assert!(!is_string_array(br#"[["/nix/store/a-b"]]"#));
+        assert!(!is_string_array(br#"["a", 1]"#));
+        assert!(!is_string_array(br#"["a",]"#));
+        assert!(!is_string_array(br#"["a"] x"#));
+        assert!(!is_string_array(br#"["a"#));
+        assert!(!is_string_array(b""));
     }
 
     #[test]
@@ -245,13 +144,11 @@ mod tests {
 
     #[test]
     fn test_skip_list() {
-        let skip = &["bash-interactive", "ghostty", "ghostty-bin"];
-
-        assert!(skip.contains(&"bash-interactive"));
-        assert!(skip.contains(&"ghostty"));
-        assert!(skip.contains(&"ghostty-bin"));
-        assert!(!skip.contains(&"bash"));
-        assert!(!skip.contains(&"git"));
+        assert!(SKIP.contains(&"bash-interactive"));
+        assert!(SKIP.contains(&"ghostty"));
+        assert!(SKIP.contains(&"ghostty-bin"));
+        assert!(!SKIP.contains(&"bash"));
+        assert!(!SKIP.contains(&"git"));
     }
 
     #[test]
@@ -278,50 +175,987 @@ lib.filter lib.isDerivation stdenv.allowedRequisites
         assert!(filename.starts_with("abc123"));
     }
 
+    const BASH: &str = "/nix/store/00000000000000000000000000000000-bash-5.2/bin";
+    const GIT: &str = "/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+    const CARGO: &str = "/nix/store/22222222222222222222222222222222-cargo-1.75.0/bin";
+    const GIT_OLD: &str = "/nix/store/33333333333333333333333333333333-git-2.39.0/bin";
+
+    fn select(path: &str) -> Vec<String> {
+        select_packages(path, &IgnoreSet::default(), &HashSet::new())
+    }
+
     #[test]
     fn test_path_splitting() {
-        let path = "/nix/store/abc-bash/bin:/nix/store/def-git/bin:/usr/bin";
-        let entries: Vec<&str> = path.split(':').filter(|s| !s.is_empty()).collect();
+        let path = format!("{BASH}:{GIT}:/usr/bin");
 
-        assert_eq!(entries.len(), 3);
-        assert_eq!(entries[0], "/nix/store/abc-bash/bin");
-        assert_eq!(entries[1], "/nix/store/def-git/bin");
-        assert_eq!(entries[2], "/usr/bin");
+        assert_eq!(select(&path), vec!["bash", "git"]);
     }
 
     #[test]
     fn test_path_empty_entries() {
-        let path = ":/nix/store/abc-bash/bin:::/nix/store/def-git/bin:";
-        let entries: Vec<&str> = path.split(':').filter(|s| !s.is_empty()).collect();
+        let path = format!(":{BASH}:::{GIT}:");
 
         // Should filter out empty strings
-        assert_eq!(entries.len(), 2);
+        assert_eq!(select(&path).len(), 2);
     }
 
     #[test]
     fn test_deduplication_logic() {
-        let mut seen = HashSet::new();
-        let mut ordered = Vec::new();
+        let path = [BASH, GIT, BASH, CARGO, GIT_OLD].join(":");
 
-        let packages = vec!["bash", "git", "bash", "cargo", "git"];
+        // First occurrence wins, PATH order is kept
+        assert_eq!(select(&path), vec!["bash", "git", "cargo"]);
+    }
 
-        for pkg in packages {
-            if seen.insert(pkg) {
-                ordered.push(pkg);
-            }
-        }
+    #[test]
+    fn test_select_packages_ignore_and_skip() {
+        let path = [BASH, GIT, CARGO].join(":");
+        let ignore: IgnoreSet = parse_hashes(format!(r#"["{BASH}"]"#).as_bytes());
+        let skip: HashSet<&str> = ["cargo"].into_iter().collect();
+
+        assert_eq!(select_packages(&path, &ignore, &skip), vec!["git"]);
+    }
+
+    #[test]
+    fn test_select_packages_default_skip_list() {
+        let path = format!("/nix/store/44444444444444444444444444444444-bash-interactive-5.2/bin:{GIT}");
+        let skip: HashSet<&str> = SKIP.iter().copied().collect();
 
-        assert_eq!(ordered, vec!["bash", "git", "cargo"]);
-        assert_eq!(ordered.len(), 3);
+        assert_eq!(select_packages(&path, &IgnoreSet::default(), &skip), vec!["git"]);
     }
 
     #[test]
     fn test_output_format() {
-        let packages = vec!["bash", "git", "cargo"];
+        let packages = ["bash", "git", "cargo"];
         let output = packages.join(", ");
 
         assert_eq!(output, "bash, git, cargo");
         assert!(output.contains(", "));
         assert_eq!(output.matches(", ").count(), 2);
     }
+
+    #[test]
+    fn test_split_version_interpreter_prefix() {
+                assert_eq!(split_version("python3.11-foo"), ("python3.11-foo", ""));
+        assert_eq!(
+            split_version("python3.11-setuptools-68.0.0"),
+            ("python3.11-setuptools", "68.0.0")
+        );
+        assert_eq!(split_version("python3-3.11.4"), ("python3", "3.11.4"));
+    }
+
+    #[test]
+    fn test_split_version_plain_and_symbols() {
+                assert_eq!(split_version("libfoo-1.2"), ("libfoo", "1.2"));
+        assert_eq!(split_version("gtk+-3.0"), ("gtk+", "3.0"));
+        assert_eq!(split_version("bash-5.2-p15"), ("bash", "5.2-p15"));
+        assert_eq!(split_version("zlib-1"), ("zlib", "1"));
+    }
+
+    #[test]
+    fn test_split_version_digit_led_name_segment() {
+        // "2fa" starts with a digit but isn't a version
+        assert_eq!(split_version("foo-2fa-1.0"), ("foo-2fa", "1.0"));
+        assert_eq!(
+            hash_and_name("/nix/store/12345678901234567890123456789012-foo-2fa-1.0/bin"),
+            Some(("12345678901234567890123456789012", "foo-2fa"))
+        );
+
+        // Undotted versions still cut: letters between digits, or dates
+        assert_eq!(split_version("openjdk-8u402"), ("openjdk", "8u402"));
+        assert_eq!(split_version("openjdk-8u402-b06"), ("openjdk", "8u402-b06"));
+        assert_eq!(split_version("foo-unstable-2024-01-01"), ("foo-unstable", "2024-01-01"));
+        assert_eq!(split_version("bar-3d-20240101"), ("bar-3d", "20240101"));
+
+        // One trailing letter is a release suffix, not a name
+        assert_eq!(split_version("tzdata-2024a-bin"), ("tzdata", "2024a-bin"));
+        assert_eq!(split_version("mksh-59c"), ("mksh", "59c"));
+        assert_eq!(
+            hash_and_name("/nix/store/12345678901234567890123456789012-tzdata-2024a-bin/bin"),
+            Some(("12345678901234567890123456789012", "tzdata"))
+        );
+    }
+
+    #[test]
+    fn test_transient_nix_errors() {
+        assert!(is_transient_nix_error("error: SQLite database '/nix/var/nix/db/db.sqlite' is busy"));
+        assert!(is_transient_nix_error("error: database is locked"));
+        assert!(is_transient_nix_error(
+            "error: unable to download 'https://cache.nixos.org/x.narinfo': HTTP error 503"
+        ));
+        assert!(!is_transient_nix_error("error: cannot find flake 'flake:nixpkgs' in the flake registries"));
+        assert!(!is_transient_nix_error("error: undefined variable 'stdenv'"));
+    }
+
+    #[test]
+    fn test_package_from_dir() {
+        let pkg = Package::from_dir(GIT).unwrap();
+
+        assert_eq!(pkg.name, "git");
+        assert_eq!(pkg.version, "2.40.1");
+        assert_eq!(pkg.hash, "11111111111111111111111111111111");
+        assert_eq!(pkg.path, "/nix/store/11111111111111111111111111111111-git-2.40.1");
+    }
+
+    #[test]
+    fn test_json_object() {
+        let pkg = Package::from_dir(GIT).unwrap();
+
+        assert_eq!(
+            format::json_object(&pkg),
+            r#"{"name":"git","version":"2.40.1","hash":"11111111111111111111111111111111","path":"/nix/store/11111111111111111111111111111111-git-2.40.1"}"#
+        );
+        assert_eq!(format::json_str("a\"b\\c\n\u{1}"), r#""a\"b\\c\n\u0001""#);
+    }
+
+    #[test]
+    fn test_template_render() {
+        let pkg = Package::from_dir(GIT).unwrap();
+        let t: format::Template = "{name}@{version} ({hash})".parse().unwrap();
+
+        assert_eq!(t.render(&pkg), "git@2.40.1 (11111111111111111111111111111111)");
+
+        let t: format::Template = "{{{name}}} {path}".parse().unwrap();
+        assert_eq!(t.render(&pkg), "{git} /nix/store/11111111111111111111111111111111-git-2.40.1");
+    }
+
+    #[test]
+    fn test_template_rejects_unknown_placeholders() {
+        assert!("{name} {size}".parse::<format::Template>().is_err());
+        assert!("{name".parse::<format::Template>().is_err());
+        assert!("name}".parse::<format::Template>().is_err());
+    }
+
+    #[test]
+    fn test_flake_not_found() {
+        assert!(is_flake_not_found("error: cannot find flake 'flake:nixpkgs' in the flake registries"));
+        assert!(!is_flake_not_found("error: database is locked"));
+    }
+
+    #[test]
+    fn test_flakes_disabled() {
+        let stderr = "error: experimental Nix feature 'nix-command' is disabled; add '--extra-experimental-features nix-command' to enable it";
+        assert!(is_flakes_disabled(stderr));
+        assert!(is_bad_expr(stderr), "No retry fixes it");
+        assert!(!is_flakes_disabled("error: cannot find flake 'flake:nixpkgs' in the flake registries"));
+    }
+
+    #[test]
+    fn test_parse_path_info_sizes() {
+        let text = "/nix/store/11111111111111111111111111111111-git-2.40.1\t  123456\n\
+                    /nix/store/22222222222222222222222222222222-fd-9.0.0 42\n\
+                    garbage line\n";
+
+        assert_eq!(
+            parse_path_info_sizes(text),
+            vec![("11111111111111111111111111111111", 123456), ("22222222222222222222222222222222", 42)]
+        );
+    }
+
+    #[test]
+    fn test_parse_duration_secs() {
+        assert_eq!(parse_duration_secs("3600"), Some(3600));
+        assert_eq!(parse_duration_secs("45s"), Some(45));
+        assert_eq!(parse_duration_secs("30m"), Some(1800));
+        assert_eq!(parse_duration_secs("2h"), Some(7200));
+        assert_eq!(parse_duration_secs("1d"), Some(86400));
+        assert_eq!(parse_duration_secs("0"), Some(0));
+
+        assert_eq!(parse_duration_secs(""), None);
+        assert_eq!(parse_duration_secs("h"), None);
+        assert_eq!(parse_duration_secs("-1"), None);
+        assert_eq!(parse_duration_secs("5y"), None);
+        assert_eq!(parse_duration_secs("1.5h"), None);
+        assert_eq!(parse_duration_secs("999999999999999999d"), None);
+    }
+
+    #[test]
+    fn test_walk_stats() {
+        let path = format!("/usr/bin:{BASH}:{GIT}:{GIT_OLD}:{CARGO}:");
+        let ignore = parse_hashes(format!(r#"["{BASH}"]"#).as_bytes());
+        let skip: HashSet<&str> = ["cargo"].into_iter().collect();
+
+        let stats = Filter::new(&ignore, &skip).for_each(&path, |_| {});
+        assert_eq!(
+            stats,
+            Stats { scanned: 5, kept: 1, skipped: 1, ignored: 1, duplicates: 1 }
+        );
+    }
+
+    #[test]
+    fn test_decode_base32() {
+        // nix bit order: last char holds the lowest bits of byte 0
+        assert_eq!(decode_base32("00000000000000000000000000000000"), Some([0; 20]));
+        assert_eq!(decode_base32("00000000000000000000000000000001"), Some({
+            let mut b = [0; 20];
+            b[0] = 1;
+            b
+        }));
+        // first char holds the top 5 bits of byte 19
+        assert_eq!(decode_base32("z0000000000000000000000000000000").unwrap()[19], 0xf8);
+        assert_eq!(decode_base32("zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz"), Some([0xff; 20]));
+
+        assert_eq!(decode_base32("0000000000000000000000000000000e"), None, "e isn't base32");
+        assert_eq!(decode_base32("0000"), None);
+    }
+
+    #[test]
+    fn test_ignore_set_lookup() {
+        let ignore: IgnoreSet = [
+            "0c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp", // valid base32
+            "abc123def45678901234567890123456", // 'e' => can't be on PATH, dropped
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(ignore.len(), 1);
+        assert!(ignore.contains("0c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp"));
+        assert!(!ignore.contains("abc123def45678901234567890123456"));
+        assert!(!ignore.contains("1c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp"));
+        assert!(!ignore.contains("abc123def45678901234567890123457"));
+    }
+
+    #[test]
+    fn test_requisites_only() {
+        let path = format!("{BASH}:{GIT}:{CARGO}");
+        let ignore = parse_hashes(format!(r#"["{BASH}", "{CARGO}"]"#).as_bytes());
+        let skip: HashSet<&str> = ["cargo"].into_iter().collect();
+
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.requisites_only = true;
+        // cargo is a requisite but still skipped by name
+        assert_eq!(filter.select(&path), vec!["bash"]);
+    }
+
+    #[test]
+    fn test_drv_and_source_paths() {
+        const DRV: &str = "/nix/store/44444444444444444444444444444444-foo-1.0.drv";
+        const SRC: &str = "/nix/store/55555555555555555555555555555555-foo-1.0.tar.gz";
+
+        assert!(hash_and_name(DRV).is_none());
+        assert!(hash_and_name(SRC).is_none());
+        assert!(Package::from_dir(DRV).is_none());
+        assert!(is_non_package("foo-1.0.tar.xz"));
+        assert!(!is_non_package("foo-1.0"));
+        assert!(!is_non_package("python3.12-drv-tools-1.0"), "only suffixes count");
+
+        assert_eq!(select(&format!("{DRV}:{SRC}:{GIT}")), vec!["git"]);
+    }
+
+    #[test]
+    fn test_only_allowlist() {
+        let path = format!("{BASH}:{GIT}:{CARGO}");
+        let ignore = IgnoreSet::default();
+        let skip: HashSet<&str> = ["cargo"].into_iter().collect();
+        let only: HashSet<String> = ["git", "cargo", "jq"].into_iter().map(String::from).collect();
+
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.only = Some(&only);
+        // skip still wins; jq isn't on PATH
+        assert_eq!(filter.select(&path), vec!["git"]);
+        assert_eq!(filter.for_each(&path, |_| {}).skipped, 2);
+    }
+
+    #[test]
+    fn test_custom_delimiter() {
+        let path = format!("{GIT}\0/usr/bin\0{CARGO}\0");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+
+        let mut filter = Filter::new(&ignore, &skip);
+        assert_eq!(filter.select(&path), vec!["git"], "Colon split sees one long entry");
+        filter.delimiter = '\0';
+        assert_eq!(filter.select(&path), vec!["git", "cargo"]);
+        assert_eq!(path_packages(&path, '\0'), vec![("git", "2.40.1"), ("cargo", "1.75.0")]);
+        assert_eq!(entries_hint(&path, '\0', None), 4);
+    }
+
+    #[test]
+    fn test_table_output() {
+        let pkgs = [Package::from_dir(GIT).unwrap(), Package::from_dir(CARGO).unwrap()];
+
+        let mut out = Vec::new();
+        format::write_table(&mut out, &pkgs, None, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NAME   VERSION  HASH      PATH\n\
+             git    2.40.1   11111111  /nix/store/11111111111111111111111111111111-git-2.40.1\n\
+             cargo  1.75.0   22222222  /nix/store/22222222222222222222222222222222-cargo-1.75.0\n"
+        );
+
+        // 40 columns minus 25 for name/version/hash leave 15 for the path
+        let mut out = Vec::new();
+        format::write_table(&mut out, &pkgs[..1], Some(40), true).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.starts_with("\x1b[1mNAME"));
+        assert!(text.ends_with("  /nix/store/111…\n"), "Got: {text}");
+
+        assert_eq!(format::ellipsize("abcdef", 4), "abc…");
+        assert_eq!(format::ellipsize("abc", 4), "abc");
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        use nix_path_pkgs::error::Error;
+
+        assert!(is_bad_expr("error: cannot find flake 'flake:nixpkgs' in the flake registries"));
+        assert!(is_bad_expr("error: flake 'path:/tmp/x' does not provide attribute 'legacyPackages'"));
+        assert!(is_bad_expr("error: attribute 'stdenv' missing"));
+        assert!(!is_bad_expr("error: database is locked"));
+
+        let e = Error::BadExpr { flake: "nixpkgs".into(), stderr: "error: cannot find flake".into() };
+        assert!(e.to_string().contains("flake not in registry"));
+        let e = Error::Timeout(std::time::Duration::from_secs(5));
+        assert!(e.to_string().contains("longer than 5s"));
+        let e = Error::NixNotFound(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert!(std::error::Error::source(&e).is_some());
+        assert!(e.to_string().contains("not found on PATH"));
+        let e = Error::NixNotFound(std::io::Error::from(std::io::ErrorKind::PermissionDenied));
+        assert!(e.to_string().starts_with("cannot run `nix`"));
+    }
+
+    #[test]
+    fn test_parse_package_list() {
+        let git = format::json_object(&Package::from_dir(GIT).unwrap());
+        let cargo = format::json_object(&Package::from_dir(CARGO).unwrap());
+        let expected = vec![("git".to_string(), "2.40.1".to_string()), ("cargo".to_string(), "1.75.0".to_string())];
+
+        assert_eq!(format::parse_package_list(&format!("[\n{git},\n{cargo}\n]\n")), Some(expected.clone()));
+        assert_eq!(format::parse_package_list(&format!("{git}\n{cargo}\n")), Some(expected));
+        assert_eq!(
+            format::parse_package_list(r#"[{"name":"a\"b","extra":{"name":"x"},"n":1}]"#),
+            Some(vec![("a\"b".to_string(), String::new())])
+        );
+        assert_eq!(format::parse_package_list("[]"), Some(vec![]));
+        assert_eq!(format::parse_package_list(r#"[{"version":"1"}]"#), None, "name is required");
+        assert_eq!(format::parse_package_list(r#"[{"name":"git"#), None);
+    }
+
+    #[test]
+    fn test_compare_versions() {
+        use std::cmp::Ordering::*;
+
+        // Cases from nix's own compareVersions tests
+        assert_eq!(compare_versions("1.0", "2.3"), Less);
+        assert_eq!(compare_versions("2.1", "2.3"), Less);
+        assert_eq!(compare_versions("2.3", "2.3"), Equal);
+        assert_eq!(compare_versions("2.5", "2.3"), Greater);
+        assert_eq!(compare_versions("3.1", "2.3"), Greater);
+        assert_eq!(compare_versions("2.3.1", "2.3"), Greater);
+        assert_eq!(compare_versions("2.3.1", "2.3a"), Greater);
+        assert_eq!(compare_versions("2.3pre1", "2.3"), Less);
+        assert_eq!(compare_versions("2.3pre3", "2.3pre12"), Less);
+        assert_eq!(compare_versions("2.3a", "2.3c"), Less);
+        assert_eq!(compare_versions("2.3pre1", "2.3c"), Less);
+        assert_eq!(compare_versions("2.3pre1", "2.3q"), Less);
+        assert_eq!(compare_versions("2.40.1", "2.40"), Greater);
+    }
+
+    #[test]
+    fn test_min_versions() {
+        let path = format!("{GIT_OLD}:{CARGO}");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mins = [("git".to_string(), "2.40".to_string()), ("jq".to_string(), "1.7".to_string())];
+
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.min_versions = &mins;
+        // git 2.39.0 is too old; cargo has no constraint
+        assert_eq!(filter.select(&path), vec!["cargo"]);
+        assert_eq!(filter.select(&format!("{GIT}:{CARGO}")), vec!["git", "cargo"]);
+    }
+
+    #[test]
+    fn test_shadowed() {
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.first = Some(1);
+
+        let path = format!("{GIT}:{CARGO}:{GIT_OLD}:{GIT}");
+        assert_eq!(filter.shadowed(&path), vec![("git", vec!["2.40.1", "2.39.0"])]);
+        // Same version twice isn't a conflict
+        assert!(filter.shadowed(&format!("{GIT}:{CARGO}:{GIT}")).is_empty());
+    }
+
+    #[test]
+    fn test_packages_iter_is_lazy() {
+        let path = format!("{BASH}:{GIT}:{GIT_OLD}:{CARGO}");
+        let ignore: IgnoreSet = ["00000000000000000000000000000000"].into_iter().collect();
+        let skip = HashSet::new();
+
+        let mut walk = packages_iter(&path, &ignore, &skip);
+        assert_eq!(walk.next().map(|p| p.name), Some("git"));
+        // Only bash and git have been looked at so far
+        assert_eq!((walk.stats().scanned, walk.stats().ignored), (2, 1));
+        let rest: Vec<_> = walk.by_ref().map(|p| p.name).collect();
+        assert_eq!(rest, vec!["cargo"]);
+        assert_eq!((walk.stats().scanned, walk.stats().duplicates), (4, 1));
+
+        // `first` ends the walk without touching later entries
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.first = Some(1);
+        let mut walk = filter.iter(&path);
+        assert_eq!(walk.by_ref().count(), 1);
+        assert_eq!(walk.stats().scanned, 2);
+    }
+
+    #[test]
+    fn test_should_color() {
+        let env = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| pairs.iter().find(|(k, _)| *k == name).map(|(_, v)| v.to_string())
+        };
+
+        assert!(format::should_color(None, env(&[]), true));
+        assert!(!format::should_color(None, env(&[]), false));
+        assert!(!format::should_color(None, env(&[("NO_COLOR", "1")]), true));
+        // Empty NO_COLOR doesn't count
+        assert!(format::should_color(None, env(&[("NO_COLOR", "")]), true));
+        assert!(!format::should_color(None, env(&[("CLICOLOR", "0")]), true));
+        assert!(format::should_color(None, env(&[("CLICOLOR", "1")]), true));
+        assert!(format::should_color(None, env(&[("CLICOLOR_FORCE", "1")]), false));
+        assert!(!format::should_color(None, env(&[("CLICOLOR_FORCE", "0")]), false));
+        assert!(format::should_color(None, env(&[("CLICOLOR", "0"), ("CLICOLOR_FORCE", "1")]), false));
+        assert!(!format::should_color(None, env(&[("NO_COLOR", "1"), ("CLICOLOR_FORCE", "1")]), true));
+        // --color overrides everything
+        assert!(format::should_color(Some(true), env(&[("NO_COLOR", "1"), ("CLICOLOR", "0")]), false));
+        assert!(!format::should_color(Some(false), env(&[("CLICOLOR_FORCE", "1")]), true));
+    }
+
+    #[test]
+    fn test_json_array() {
+        let pkgs = [Package::from_dir(GIT).unwrap(), Package::from_dir(CARGO).unwrap()];
+        let git = format::json_object(&pkgs[0]);
+        let cargo = format::json_object(&pkgs[1]);
+        let render = |pkgs: &[Package], pretty| {
+            let mut out = Vec::new();
+            format::write_json_array(&mut out, pkgs, |_| None, None, pretty).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(render(&pkgs, false), format!("[{git},{cargo}]\n"));
+        assert_eq!(render(&[], false), "[]\n");
+        assert_eq!(render(&[], true), "[]\n");
+
+        let pretty = render(&pkgs[..1], true);
+        assert_eq!(
+            pretty,
+            concat!(
+                "[\n",
+                "  {\n",
+                "    \"name\": \"git\",\n",
+                "    \"version\": \"2.40.1\",\n",
+                "    \"hash\": \"11111111111111111111111111111111\",\n",
+                "    \"path\": \"/nix/store/11111111111111111111111111111111-git-2.40.1\"\n",
+                "  }\n",
+                "]\n",
+            )
+        );
+        // Both forms read back as the same list
+        assert_eq!(format::parse_package_list(&render(&pkgs, true)), format::parse_package_list(&render(&pkgs, false)));
+    }
+
+    #[test]
+    fn test_regex() {
+        let re = |s: &str| s.parse::<pattern::Regex>().unwrap();
+
+        assert!(re("ghostty").is_match("ghostty-bin"));
+        assert!(!re("^ghostty$").is_match("ghostty-bin"));
+        assert!(re("-unwrapped$").is_match("firefox-unwrapped"));
+        assert!(re("^(git|cargo)$").is_match("cargo"));
+        assert!(!re("^(git|cargo)$").is_match("cargo-watch"));
+        assert!(re("^py[0-9]+\\.\\d+-").is_match("py3.11-numpy"));
+        assert!(re("^[^a-z]").is_match("7zip"));
+        assert!(!re("^[^a-z]").is_match("zip"));
+        assert!(re("^a.?c*$").is_match("ab"));
+        assert!(re("^(a?)*$").is_match(""));
+        assert!(re("\\W").is_match("a-b"));
+        assert!(re("").is_match("anything"));
+        assert!(re("^(ab|a)(bc)+$").is_match("abcbc"));
+        assert!(re("^(a|ab)c$").is_match("abc"));
+        assert!(!re("^a+$").is_match("aab"));
+
+        // Nested repeats stay fast instead of backtracking exponentially
+        let long = "a".repeat(2000);
+        assert!(!re("(a|aa)*c").is_match(&long));
+        assert!(!re("^(a*)*b$").is_match(&long));
+        assert!(re("(a|aa)*$").is_match(&long));
+
+        for bad in ["(ghostty", "ghostty)", "*x", "a|+", "[abc", "[z-a]", "x\\"] {
+            assert!(bad.parse::<pattern::Regex>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_skip_patterns() {
+        let path = format!("{GIT}:{CARGO}:/nix/store/44444444444444444444444444444444-firefox-unwrapped-120.0/bin");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let patterns = ["-unwrapped$".parse().unwrap(), "^car".parse().unwrap()];
+
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.skip_patterns = &patterns;
+        assert_eq!(filter.select(&path), vec!["git"]);
+        assert_eq!(filter.for_each(&path, |_| {}).skipped, 2);
+    }
+
+    #[test]
+    fn test_split_output() {
+        let out = |dir| split_output(&Package::from_dir(dir).unwrap());
+
+        assert_eq!(out(GIT), ("git", "out"));
+        assert_eq!(out("/nix/store/11111111111111111111111111111111-git-2.40.1-doc"), ("git", "doc"));
+        assert_eq!(out("/nix/store/44444444444444444444444444444444-foo-dev/bin"), ("foo", "dev"));
+        assert_eq!(out("/nix/store/44444444444444444444444444444444-rustup/bin"), ("rustup", "out"));
+        // Not a known output name
+        assert_eq!(out("/nix/store/44444444444444444444444444444444-foo-utils/bin"), ("foo-utils", "out"));
+    }
+
+    #[test]
+    fn test_outputs() {
+        let path = [
+            "/nix/store/44444444444444444444444444444444-openssl-3.0.12-bin/bin",
+            GIT,
+            "/nix/store/55555555555555555555555555555555-openssl-3.0.12-dev/bin",
+            "/nix/store/66666666666666666666666666666666-openssl-3.0.12-bin/bin",
+        ]
+        .join(":");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+
+        assert_eq!(filter.outputs(&path), vec![("openssl", vec!["bin", "dev"]), ("git", vec!["out"])]);
+        filter.first = Some(1);
+        assert_eq!(filter.outputs(&path), vec![("openssl", vec!["bin", "dev"])]);
+
+        assert_eq!(format::output_group("openssl", &["bin", "dev"]), "openssl [bin, dev]");
+        assert_eq!(format::output_group("git", &["out"]), "git");
+    }
+
+    #[test]
+    fn test_unwrapped_name() {
+        assert_eq!(unwrapped_name("foo-wrapped"), "foo");
+        assert_eq!(unwrapped_name("hi.wrapped"), "hi");
+        assert_eq!(unwrapped_name("foo-unwrapped"), "foo-unwrapped");
+        assert_eq!(unwrapped_name("wrapped"), "wrapped");
+        assert_eq!(unwrapped_name("-wrapped"), "-wrapped");
+    }
+
+    #[test]
+    fn test_dedup_wrappers() {
+        let path = [
+            "/nix/store/44444444444444444444444444444444-foo-wrapped-1.2/bin",
+            GIT,
+            "/nix/store/55555555555555555555555555555555-foo-1.2/bin",
+            "/nix/store/66666666666666666666666666666666-hi-wrapped/bin",
+            "/nix/store/77777777777777777777777777777777-hi/bin",
+        ]
+        .join(":");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+
+        assert_eq!(filter.select(&path), vec!["foo-wrapped", "git", "foo", "hi-wrapped", "hi"]);
+        filter.dedup_wrappers = true;
+        assert_eq!(filter.select(&path), vec!["foo", "git", "hi"]);
+        assert_eq!(filter.for_each(&path, |_| {}).duplicates, 2);
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let path = [
+            "/nix/store/44444444444444444444444444444444-Git-2.41.0/bin",
+            GIT,
+            "/nix/store/55555555555555555555555555555555-GhostTY-1.0/bin",
+            CARGO,
+        ]
+        .join(":");
+        let ignore = IgnoreSet::default();
+        let skip: HashSet<&str> = ["ghostty", "CARGO"].into_iter().collect();
+        let mut filter = Filter::new(&ignore, &skip);
+
+        assert_eq!(filter.select(&path), vec!["Git", "git", "GhostTY", "cargo"]);
+        filter.case_insensitive = true;
+        // First spelling wins; skip entries match in either direction
+        assert_eq!(filter.select(&path), vec!["Git"]);
+        let stats = filter.for_each(&path, |_| {});
+        assert_eq!((stats.duplicates, stats.skipped), (1, 2));
+    }
+
+    #[test]
+    fn test_hash_and_item_rejects_non_base32_hash() {
+        let dots = format!("/nix/store/{}-foo-1.0/bin", ".".repeat(32));
+        assert_eq!(hash_and_item(&dots), None);
+        // e, o, t and u aren't in nix's alphabet
+        for c in ['e', 'o', 't', 'u', 'A', '_'] {
+            let dir = format!("/nix/store/{c}{}-foo-1.0/bin", "0".repeat(31));
+            assert_eq!(hash_and_item(&dir), None, "{c}");
+        }
+        let valid = "/nix/store/0123456789abcdfghijklmnpqrsvwxyz-foo-1.0/bin";
+        assert_eq!(hash_and_item(valid), Some(("0123456789abcdfghijklmnpqrsvwxyz", "foo-1.0")));
+        assert!(is_base32("0123456789abcdfghijklmnpqrsvwxyz"));
+        assert!(!is_base32("0123456789abcdefghijklmnpqrsvwxy"));
+    }
+
+    #[test]
+    fn test_json_schema_matches_json_object() {
+        let p = Package::from_dir(GIT).unwrap();
+        let schema = format::json_schema();
+        let obj = format::json_object(&p);
+        for (key, _) in format::JSON_FIELDS {
+            assert!(obj.contains(&format!("\"{key}\":")), "{obj}");
+            assert!(schema.contains(&format!("\"{key}\": {{ \"type\": \"string\"")), "{schema}");
+        }
+        assert!(schema.contains(r#""required": ["name", "version", "hash", "path"]"#), "{schema}");
+        assert!(schema.contains(r#""nixpkgs_rev": { "type": "string""#), "{schema}");
+        assert!(schema.ends_with("}\n"));
+
+        let tagged = format::json_object_counted(&p, Some(2), Some("abc123"));
+        assert!(tagged.ends_with(r#","occurrences":2,"nixpkgs_rev":"abc123"}"#), "{tagged}");
+    }
+
+    #[test]
+    fn test_is_valid_system() {
+        for ok in ["x86_64-linux", "aarch64-darwin", "armv7l-linux", "riscv64-linux"] {
+            assert!(is_valid_system(ok), "{ok}");
+        }
+        for bad in ["", "linux", "-linux", "x86_64-", "x86_64-Linux", "x86_64-linux\"", "x86_64-${x}"] {
+            assert!(!is_valid_system(bad), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_search() {
+        let watch = "/nix/store/44444444444444444444444444444444-Cargo-watch-8.4.0/bin";
+        let path = format!("{BASH}:{GIT}:{CARGO}:{watch}");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.search = Some("CARGO");
+        assert_eq!(filter.select(&path), vec!["cargo", "Cargo-watch"]);
+        filter.search_exact = true;
+        assert_eq!(filter.select(&path), vec!["cargo"]);
+        filter.search = Some("jq");
+        assert!(filter.select(&path).is_empty());
+
+        assert!(contains_ignore_case("ripgrep", "GREP"));
+        assert!(!contains_ignore_case("rg", "ripgrep"));
+    }
+
+    #[test]
+    fn test_store_hash_round_trip() {
+        for s in [
+            "00000000000000000000000000000000",
+            "zzzzzzzzzzzzzzzzzzzzzzzzzzzzzzzz",
+            "0c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp",
+            "0123456789abcdfghijklmnpqrsvwxyz",
+        ] {
+            let hash: StoreHash = s.parse().unwrap();
+            assert_eq!(hash.to_string(), s);
+        }
+        let one: StoreHash = "00000000000000000000000000000001".parse().unwrap();
+        assert_eq!(one.0[0], 1);
+        assert!("0000000000000000000000000000000e".parse::<StoreHash>().is_err());
+        assert!("0000".parse::<StoreHash>().is_err());
+
+        assert_eq!(store_hash(GIT), Some("11111111111111111111111111111111"));
+        assert_eq!(store_hash("/nix/store/11111111111111111111111111111111"), None, "No dash");
+        assert_eq!(store_hash("/usr/bin"), None);
+
+        let ignore: IgnoreSet = ["0c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp"].into_iter().collect();
+        assert!(ignore.contains_hash(&"0c5bdhb5m4lpj8v2qnm4zgxslqaa1ppp".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_hash_and_item_double_dash() {
+        let dir = "/nix/store/11111111111111111111111111111111--doubledash-foo-1.0/bin";
+        assert_eq!(hash_and_item(dir), Some(("11111111111111111111111111111111", "doubledash-foo-1.0")));
+        let p = Package::from_dir(dir).unwrap();
+        assert_eq!((p.name, p.version), ("doubledash-foo", "1.0"));
+        assert_eq!(p.path, "/nix/store/11111111111111111111111111111111--doubledash-foo-1.0");
+
+        // Nothing but dashes => no name, never kept
+        let dashes = "/nix/store/11111111111111111111111111111111---/bin";
+        assert_eq!(hash_and_name(dashes), Some(("11111111111111111111111111111111", "")));
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        assert!(Filter::new(&ignore, &skip).select(dashes).is_empty());
+    }
+
+    #[test]
+    fn test_write_env() {
+        assert_eq!(format::env_var_name("cargo-watch"), "NIX_PKG_CARGO_WATCH");
+        assert_eq!(format::env_var_name("python3.12"), "NIX_PKG_PYTHON3_12");
+        assert_eq!(format::env_var_name("7zip"), "NIX_PKG_7ZIP");
+
+        let dirs = [
+            "/nix/store/11111111111111111111111111111111-cargo-watch-8.4.0/bin",
+            "/nix/store/22222222222222222222222222222222-cargo.watch-1.0/bin",
+            "/nix/store/33333333333333333333333333333333-cargo_watch_2-2.0/bin",
+            "/nix/store/44444444444444444444444444444444-Cargo-Watch-3.0/bin",
+        ];
+        let pkgs: Vec<Package> = dirs.iter().filter_map(|d| Package::from_dir(d)).collect();
+        let mut out = Vec::new();
+        format::write_env(&mut out, &pkgs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NIX_PKG_CARGO_WATCH=8.4.0\nNIX_PKG_CARGO_WATCH_2=1.0\nNIX_PKG_CARGO_WATCH_2_2=2.0\nNIX_PKG_CARGO_WATCH_3=3.0\n"
+        );
+    }
+
+    #[test]
+    fn test_store_root() {
+        let chroot = "/mnt/nix/store/11111111111111111111111111111111-git-2.40.1/bin";
+        assert_eq!(in_store_root(chroot, "/mnt"), GIT);
+        assert_eq!(in_store_root(GIT, "/mnt"), GIT, "Plain store paths pass through");
+        assert_eq!(in_store_root("/mnt/usr/bin", "/mnt"), "/mnt/usr/bin");
+        assert_eq!(in_store_root(chroot, ""), chroot);
+
+        let path = format!("{chroot}:{CARGO}");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+        assert_eq!(filter.select(&path), vec!["cargo"]);
+        filter.store_root = "/mnt";
+        assert_eq!(filter.select(&path), vec!["git", "cargo"]);
+        assert_eq!(filter.packages(&path)[0].path, "/nix/store/11111111111111111111111111111111-git-2.40.1");
+    }
+
+    #[test]
+    fn test_skip_versioned() {
+        let py311 = "/nix/store/44444444444444444444444444444444-python3-3.11.8/bin";
+        let py312 = "/nix/store/55555555555555555555555555555555-python3-3.12.1/bin";
+        let py3110 = "/nix/store/66666666666666666666666666666666-python3-3.110/bin";
+        let path = format!("{py311}:{py312}:{py3110}:{GIT}");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+
+        let versioned = [("python3".to_string(), "3.11".to_string())];
+        filter.skip_versioned = &versioned;
+        let versions: Vec<&str> = filter.packages(&path).iter().map(|p| p.version).collect();
+        assert_eq!(versions, vec!["3.12.1", "2.40.1"], "3.11.8 hidden, 3.12.1 now first");
+
+        let exact = [("python3".to_string(), "3.110".to_string())];
+        filter.skip_versioned = &exact;
+        let versions: Vec<&str> = filter.packages(&path).iter().map(|p| p.version).collect();
+        assert_eq!(versions, vec!["3.11.8", "2.40.1"]);
+
+        // Unversioned names still go through the plain skip list
+        let skip: HashSet<&str> = ["python3"].into_iter().collect();
+        let filter = Filter::new(&ignore, &skip);
+        assert_eq!(filter.select(&path), vec!["git"]);
+    }
+
+    #[test]
+    fn test_sort_by_name_is_byte_order() {
+        let dirs = [
+            "/nix/store/11111111111111111111111111111111-git-2.40.1/bin",
+            "/nix/store/22222222222222222222222222222222-apple-1.0/bin",
+            "/nix/store/33333333333333333333333333333333-_x-1.0/bin",
+            "/nix/store/44444444444444444444444444444444-Zed-1.0/bin",
+            "/nix/store/55555555555555555555555555555555-git-2.39.0/bin",
+        ];
+        let mut pkgs: Vec<Package> = dirs.iter().filter_map(|d| Package::from_dir(d)).collect();
+        sort_by_name(&mut pkgs);
+        let order: Vec<(&str, &str)> = pkgs.iter().map(|p| (p.name, p.version)).collect();
+        assert_eq!(order, vec![("Zed", "1.0"), ("_x", "1.0"), ("apple", "1.0"), ("git", "2.39.0"), ("git", "2.40.1")]);
+    }
+
+    #[test]
+    fn test_explain_package() {
+        let ignored = "/nix/store/44444444444444444444444444444444-git-2.38.0/bin";
+        let path = format!("{ignored}:{GIT}:{CARGO}:{GIT_OLD}");
+        let mut ignore = IgnoreSet::default();
+        ignore.insert("44444444444444444444444444444444");
+        let skip = HashSet::new();
+        let filter = Filter::new(&ignore, &skip);
+
+        let found: Vec<(&str, Verdict)> = filter.explain(&path, "git").iter().map(|(p, v)| (p.version, *v)).collect();
+        assert_eq!(found, vec![("2.38.0", Verdict::Ignored), ("2.40.1", Verdict::Kept), ("2.39.0", Verdict::Duplicate)]);
+        assert!(filter.explain(&path, "jq").is_empty());
+
+        let skip: HashSet<&str> = ["git"].into_iter().collect();
+        let filter = Filter::new(&ignore, &skip);
+        let verdicts: Vec<Verdict> = filter.explain(&path, "git").iter().map(|(_, v)| *v).collect();
+        assert_eq!(verdicts, vec![Verdict::Ignored, Verdict::Skipped, Verdict::Skipped]);
+    }
+
+    #[test]
+    fn test_occurrences() {
+        let path = format!("{GIT}:{CARGO}:{GIT_OLD}:{BASH}:{GIT}");
+        let ignore: IgnoreSet = parse_hashes(format!(r#"["{BASH}"]"#).as_bytes());
+        let skip = HashSet::new();
+        let filter = Filter::new(&ignore, &skip);
+        let counts = filter.occurrences(&path);
+        assert_eq!(counts.get("git"), Some(&3));
+        assert_eq!(counts.get("cargo"), Some(&1));
+        assert_eq!(counts.get("bash"), None, "ignored entries don't count");
+
+        let p = Package::from_dir(GIT).unwrap();
+        assert_eq!(format::json_object_counted(&p, Some(3), None), format::json_object(&p).replace('}', r#","occurrences":3}"#));
+        assert!(format::json_object_pretty(&p, Some(3), None, "").ends_with("  \"occurrences\": 3\n}"));
+        assert!(format::json_schema().contains(r#""occurrences": { "type": "integer""#));
+    }
+
+    #[test]
+    fn test_write_tree() {
+        let pkgs = [Package::from_dir(GIT).unwrap(), Package::from_dir(CARGO).unwrap()];
+        let git = "/nix/store/11111111111111111111111111111111-git-2.40.1";
+        let bash = "/nix/store/00000000000000000000000000000000-bash-5.2";
+        let curl = "/nix/store/44444444444444444444444444444444-curl-8.4.0";
+        let refs = std::collections::HashMap::from([(
+            "11111111111111111111111111111111".to_string(),
+            vec![bash.to_string(), curl.to_string(), git.to_string()],
+        )]);
+        let ignore: IgnoreSet = parse_hashes(format!(r#"["{BASH}"]"#).as_bytes());
+
+        let mut out = Vec::new();
+        format::write_tree(&mut out, &pkgs, &refs, &ignore).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "git 2.40.1\n├── bash-5.2 (ignored)\n└── curl-8.4.0\ncargo 1.75.0\n└── ?\n"
+        );
+    }
+
+    #[test]
+    fn test_keep_duplicates() {
+        let path = format!("{GIT}:{CARGO}:{GIT_OLD}:{BASH}:{GIT}");
+        let ignore: IgnoreSet = parse_hashes(format!(r#"["{BASH}"]"#).as_bytes());
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+        filter.keep_duplicates = true;
+
+        let versions: Vec<&str> = filter.packages(&path).iter().map(|p| p.version).collect();
+        assert_eq!(versions, vec!["2.40.1", "1.75.0", "2.39.0", "2.40.1"]);
+        let mut dirs = Vec::new();
+        let stats = filter.for_each_dir(&path, |d| dirs.push(d));
+        assert_eq!(dirs, vec![GIT, CARGO, GIT_OLD, GIT]);
+        assert_eq!((stats.kept, stats.ignored, stats.duplicates), (4, 1, 0));
+    }
+
+    #[test]
+    fn test_glob() {
+        let glob = |s: &str| s.parse::<pattern::Glob>().unwrap();
+
+        assert!(glob("cargo-*").matches("cargo-watch"));
+        assert!(!glob("cargo-*").matches("cargo"));
+        assert!(!glob("cargo").matches("cargo-watch"), "whole name, unlike --skip-regex");
+        assert!(glob("*-unwrapped").matches("firefox-unwrapped"));
+        assert!(glob("*").matches(""));
+        assert!(glob("pyth?n3").matches("python3") && !glob("py?on3").matches("python3"));
+        assert!(glob("*a*b*c").matches("xaxbxbc"));
+        assert!(!glob("*a*b*c").matches("xaxbxcb"));
+        assert!(glob("python3.1[0-9]*").matches("python3.12-full"));
+        assert!(glob("[!a-z]*").matches("7zip") && !glob("[!a-z]*").matches("zip"));
+        assert!(glob("[^a-z]*").matches("7zip"));
+        assert!(glob("[]]").matches("]") && glob("a\\*").matches("a*") && !glob("a\\*").matches("ab"));
+
+        for bad in ["[abc", "[z-a]", "x\\", "[!"] {
+            assert!(bad.parse::<pattern::Glob>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn test_include_exclude_globs() {
+        let watch = "/nix/store/44444444444444444444444444444444-cargo-watch-8.4.0/bin";
+        let path = format!("{GIT}:{CARGO}:{watch}");
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let mut filter = Filter::new(&ignore, &skip);
+
+        let include = ["cargo*".parse().unwrap()];
+        filter.include_globs = &include;
+        assert_eq!(filter.select(&path), vec!["cargo", "cargo-watch"]);
+        let exclude = ["*-watch".parse().unwrap()];
+        filter.exclude_globs = &exclude;
+        assert_eq!(filter.select(&path), vec!["cargo"]);
+        filter.include_globs = &[];
+        assert_eq!(filter.select(&path), vec!["git", "cargo"]);
+    }
+
+    #[test]
+    fn test_collapse_prefixes() {
+        let names = ["git", "cargo-watch", "cargo", "ripgrep", "cargo-edit", "git-lfs", "nix-tree"];
+        assert_eq!(
+            format::collapse_prefixes(&names, "-", false),
+            vec!["git", "cargo (+2 subcommands)", "ripgrep", "git-lfs", "nix-tree"]
+        );
+        assert_eq!(
+            format::collapse_prefixes(&names, "-", true),
+            vec!["git", "cargo-* (2)", "cargo", "ripgrep", "git-lfs", "nix-tree"]
+        );
+        // No base on PATH: the group still takes the first member's place
+        assert_eq!(format::collapse_prefixes(&["jq", "cargo-a", "cargo-b"], "-", false), vec!["jq", "cargo (+2 subcommands)"]);
+        assert_eq!(format::collapse_prefixes(&["a.b", "a.c"], ".", false), vec!["a (+2 subcommands)"]);
+        assert!(format::collapse_prefixes(&[], "-", false).is_empty());
+    }
+
+    #[test]
+    fn test_write_toml() {
+        let pkgs = [Package::from_dir(GIT).unwrap(), Package::from_dir(BASH).unwrap()];
+        let mut out = Vec::new();
+        format::write_toml(&mut out, &pkgs, |p| (p.name == "git").then_some(2)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[[package]]\n\
+             name = \"git\"\n\
+             version = \"2.40.1\"\n\
+             hash = \"11111111111111111111111111111111\"\n\
+             path = \"/nix/store/11111111111111111111111111111111-git-2.40.1\"\n\
+             occurrences = 2\n\
+             \n\
+             [[package]]\n\
+             name = \"bash\"\n\
+             version = \"5.2\"\n\
+             hash = \"00000000000000000000000000000000\"\n\
+             path = \"/nix/store/00000000000000000000000000000000-bash-5.2\"\n"
+        );
+
+        let mut out = Vec::new();
+        format::write_toml(&mut out, &[], |_| None).unwrap();
+        assert!(out.is_empty());
+        assert_eq!(format::toml_str("a\"b\\c\n\u{8}\u{1}\u{7f}"), r#""a\"b\\c\n\b\u0001\u007F""#);
+    }
+
+    #[test]
+    fn test_hash_and_item_store_dir_shapes() {
+        const H: &str = "11111111111111111111111111111111";
+        let git = Some((H, "git-2.40.1"));
+        // The item ends at the first `/`, whatever follows
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-git-2.40.1")), git);
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-git-2.40.1/")), git);
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-git-2.40.1//bin/")), git);
+        for dir in [format!("/nix/store/{H}-git-2.40.1/"), format!("/nix/store/{H}-git-2.40.1")] {
+            let p = Package::from_dir(&dir).unwrap();
+            assert_eq!((p.name, p.version, p.path), ("git", "2.40.1", &*format!("/nix/store/{H}-git-2.40.1")));
+        }
+
+        // `?` and `=` are legal in store names, so they're never cut off as a
+        // query string
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-foo?x=1/bin")), Some((H, "foo?x=1")));
+
+        // Nothing after the dash: a hash but no name
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-")), Some((H, "")));
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}-/")), Some((H, "")));
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}")), None);
+        assert_eq!(hash_and_item(&format!("/nix/store/{H}/bin")), None);
+
+        // A trailing slash doesn't make the same package a second entry
+        let ignore = IgnoreSet::default();
+        let skip = HashSet::new();
+        let path = format!("/nix/store/{H}-git-2.40.1/bin:/nix/store/{H}-git-2.40.1/");
+        assert_eq!(Filter::new(&ignore, &skip).select(&path), ["git"]);
+    }
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(format::shell_quote("--impure"), "--impure");
+        assert_eq!(format::shell_quote("/nix/store/x-git_2.40@1"), "/nix/store/x-git_2.40@1");
+        assert_eq!(format::shell_quote(""), "''");
+        assert_eq!(format::shell_quote("a b"), "'a b'");
+        assert_eq!(format::shell_quote("\"${x}\"\n"), "'\"${x}\"\n'");
+        assert_eq!(format::shell_quote("it's"), r"'it'\''s'");
+    }
 }